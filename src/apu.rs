@@ -0,0 +1,96 @@
+#![allow(dead_code)]
+
+use crate::{
+    cpu::memorybus::io::IoDevice,
+    state::{Snapshot, StateError, StateReader, StateWriter},
+};
+
+pub const APU_BEGIN: usize = 0xFF10;
+pub const APU_END: usize = 0xFF26;
+pub const APU_SIZE: usize = APU_END - APU_BEGIN + 1;
+
+pub const WAVE_RAM_BEGIN: usize = 0xFF30;
+pub const WAVE_RAM_END: usize = 0xFF3F;
+pub const WAVE_RAM_SIZE: usize = WAVE_RAM_END - WAVE_RAM_BEGIN + 1;
+
+/// NR52
+const POWER_CONTROL: usize = 0xFF26;
+
+/// The audio registers. No sound is generated yet, but the registers hold their values so games
+/// can read them back
+#[derive(Debug, Clone, Copy)]
+pub struct Apu {
+    registers: [u8; APU_SIZE],
+    pub wave_ram: [u8; WAVE_RAM_SIZE],
+    /// NR52 bit 7
+    powered: bool,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self {
+            registers: [0; APU_SIZE],
+            wave_ram: [0; WAVE_RAM_SIZE],
+            powered: false,
+        }
+    }
+}
+
+impl Apu {
+    /// The state the boot ROM leaves the APU in after playing the chime
+    pub fn after_boot() -> Self {
+        let mut apu = Self::default();
+        apu.write(POWER_CONTROL, 0x80);
+        apu.write(0xFF11, 0x80); // NR11
+        apu.write(0xFF12, 0xF3); // NR12
+        apu.write(0xFF24, 0x77); // NR50
+        apu.write(0xFF25, 0xF3); // NR51
+        apu
+    }
+}
+
+/// The sound registers and wave RAM
+impl IoDevice for Apu {
+    /// Reads a register as it was written. Unused bits are left to the bus
+    fn read(&self, address: usize) -> u8 {
+        match address {
+            POWER_CONTROL => {
+                // FIXME: bits 0-3 should say which channels are playing
+                u8::from(self.powered) << 7
+            }
+            APU_BEGIN..=APU_END => self.registers[address - APU_BEGIN],
+            WAVE_RAM_BEGIN..=WAVE_RAM_END => self.wave_ram[address - WAVE_RAM_BEGIN],
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u8) {
+        if (WAVE_RAM_BEGIN..=WAVE_RAM_END).contains(&address) {
+            self.wave_ram[address - WAVE_RAM_BEGIN] = value;
+        } else if address == POWER_CONTROL {
+            self.powered = value & 0x80 != 0;
+            if !self.powered {
+                // turning the APU off clears every register, and they ignore writes until it's
+                // turned back on
+                self.registers = [0; APU_SIZE];
+            }
+        } else if self.powered && (APU_BEGIN..=APU_END).contains(&address) {
+            self.registers[address - APU_BEGIN] = value;
+        }
+    }
+}
+
+impl Snapshot for Apu {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.registers);
+        state.bytes(&self.wave_ram);
+        state.bool(self.powered);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes(&mut self.registers)?;
+        state.bytes(&mut self.wave_ram)?;
+        self.powered = state.bool()?;
+        Ok(())
+    }
+}
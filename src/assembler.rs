@@ -0,0 +1,132 @@
+#![allow(dead_code)]
+
+use crate::disassembler::{instruction::Instruction, parse_instruction};
+
+/// Assembles a program written in the same syntax [`Instruction`] is displayed with, one
+/// instruction per line. Immediates can be written as decimal, `$FF` or `0xFF`, and `;` starts a
+/// comment.
+///
+/// Rather than keeping a separate opcode table, every opcode is decoded and displayed until one
+/// matches the line, so this always stays in sync with the decoder.
+///
+/// # Panics
+/// Panics if a line doesn't match any instruction
+pub fn assemble(source: &str) -> Vec<u8> {
+    source
+        .lines()
+        .map(|line| line.split(';').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .flat_map(|line| {
+            assemble_line(line).unwrap_or_else(|| panic!("failed to assemble `{line}`"))
+        })
+        .collect()
+}
+
+fn assemble_line(line: &str) -> Option<Vec<u8>> {
+    let (target, immediate) = normalize(line);
+    // negative relative offsets are encoded as two's complement
+    let [low, high, ..] = immediate.unwrap_or_default().to_le_bytes();
+
+    let primary = (0..=0xFF).map(|opcode| [opcode, low, high, 0x00]);
+    let prefixed = (0..=0xFF).map(|opcode| [0xCB, opcode, 0x00, 0x00]);
+    primary.chain(prefixed).find_map(|bytes| {
+        let (_, instruction) = parse_instruction(&bytes).ok()?;
+        if matches!(instruction, Instruction::Illegal(_)) {
+            return None;
+        }
+        (normalize(&instruction.to_string()).0 == target)
+            .then(|| bytes[..usize::from(instruction.length())].to_vec())
+    })
+}
+
+/// Strips whitespace and rewrites every number as decimal so differently formatted lines can be
+/// compared. Also returns the value of the last number, which is the instruction's immediate.
+fn normalize(line: &str) -> (String, Option<i32>) {
+    let line = line.to_uppercase();
+    let mut chars = line.chars().filter(|c| !c.is_whitespace()).peekable();
+    let mut output = String::with_capacity(line.len());
+    let mut immediate = None;
+
+    while let Some(c) = chars.next() {
+        let radix = match c {
+            '$' => 16,
+            '0' if chars.peek() == Some(&'X') => {
+                chars.next();
+                16
+            }
+            '0'..='9' => 10,
+            // a leading plus isn't significant, but HL+ is
+            '+' if chars
+                .peek()
+                .is_some_and(|c| *c == '$' || c.is_ascii_digit()) =>
+            {
+                continue;
+            }
+            _ => {
+                output.push(c);
+                continue;
+            }
+        };
+
+        let mut digits = String::new();
+        if radix == 10 {
+            digits.push(c);
+        }
+        while let Some(digit) = chars.next_if(|c| c.is_digit(radix)) {
+            digits.push(digit);
+        }
+        let Ok(mut value) = i32::from_str_radix(&digits, radix) else {
+            // not actually a number, so leave it as is
+            output.push(c);
+            continue;
+        };
+        if output.ends_with('-') {
+            output.pop();
+            value = -value;
+        }
+        output.push_str(&value.to_string());
+        immediate = Some(value);
+    }
+
+    (output, immediate)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Write as _;
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let program = "
+            LD A, 0x42
+            INC A
+            LD HL, $C000
+            LD (HL+), A ; comment
+            JR NZ, -5
+            BIT 7, (HL)
+            LDH ($44), A
+            CALL $0150
+            RET Z
+            HALT
+        ";
+        let bytes = assemble(program);
+        assert_eq!(
+            bytes,
+            [
+                0x3E, 0x42, 0x3C, 0x21, 0x00, 0xC0, 0x22, 0x20, 0xFB, 0xCB, 0x7E, 0xE0, 0x44, 0xCD,
+                0x50, 0x01, 0xC8, 0x76
+            ]
+        );
+
+        let mut rest = bytes.as_slice();
+        let mut disassembly = String::new();
+        while !rest.is_empty() {
+            let (after, instruction) = parse_instruction(rest).unwrap();
+            let _ = writeln!(disassembly, "{instruction}");
+            rest = after;
+        }
+        assert_eq!(assemble(&disassembly), bytes);
+    }
+}
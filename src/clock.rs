@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+/// 2^22Hz
+pub const CYCLES_PER_SECOND: u32 = 4_194_304;
+pub const BURSTS_PER_SECOND: u32 = 60;
+
+/// Decides how many cycles are run between each wait for the clock. They don't divide evenly
+/// into bursts, so some bursts get a cycle more than others, which keeps the total in line with
+/// [`CYCLES_PER_SECOND`] over time. Bursts which run over because an instruction didn't end right
+/// on the budget are made up for in the next one
+#[derive(Debug, Default)]
+pub struct BurstBudget {
+    bursts: u64,
+    /// Cycles actually run so far
+    cycles: u64,
+}
+
+impl BurstBudget {
+    /// How many cycles to run in the next burst
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn next_burst(&mut self) -> u32 {
+        self.bursts += 1;
+        let target = self.bursts * u64::from(CYCLES_PER_SECOND) / u64::from(BURSTS_PER_SECOND);
+        // a burst is never more than a few frames of cycles
+        target.saturating_sub(self.cycles) as u32
+    }
+
+    /// Records how many cycles the burst really ran
+    pub fn ran(&mut self, cycles: u32) {
+        self.cycles += u64::from(cycles);
+    }
+}
+
+/// Decides when the next burst of cycles runs, so the same stepping code can run in real time
+/// or as fast as possible
+pub trait Clock {
+    /// Called after every burst
+    fn wait_for_next_burst(&mut self);
+}
+
+/// Paces bursts to wall time
+#[derive(Debug)]
+pub struct RealTimeClock {
+    burst_duration: Duration,
+    next_burst: Instant,
+}
+
+impl RealTimeClock {
+    pub fn new() -> Self {
+        let burst_duration = Duration::from_secs_f64(1.0 / f64::from(BURSTS_PER_SECOND));
+        Self {
+            burst_duration,
+            next_burst: Instant::now() + burst_duration,
+        }
+    }
+}
+
+impl Clock for RealTimeClock {
+    fn wait_for_next_burst(&mut self) {
+        debug!(
+            delta = ?(self.burst_duration.saturating_sub(self.next_burst.duration_since(Instant::now()))),
+            target = ?self.burst_duration,
+            "frame took"
+        );
+        if !self.next_burst.elapsed().is_zero() {
+            warn!("lagging by {:?}", self.next_burst.elapsed());
+        }
+
+        std::thread::sleep_until(self.next_burst);
+        self.next_burst = Instant::now() + self.burst_duration;
+    }
+}
+
+/// Runs bursts back to back. Time only moves forward by the cycles emulated, so runs are
+/// reproducible no matter how fast the host is
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    /// How many bursts have finished
+    pub bursts: u64,
+}
+
+impl VirtualClock {
+    /// Emulated time that has passed
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs(self.bursts) / BURSTS_PER_SECOND
+    }
+}
+
+impl Clock for VirtualClock {
+    fn wait_for_next_burst(&mut self) {
+        self.bursts += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    /// Runs 30 bursts of the boot sequence, sleeping between each to mimic a slow host
+    fn run(host_delay: Duration) -> (u64, u64) {
+        let rom = include_bytes!("../test_roms/instr_timing/instr_timing.gb");
+        let mut cpu = Cpu::new_with_boot_sequence(rom, false);
+        let mut clock = VirtualClock::default();
+        let mut budget = BurstBudget::default();
+        while clock.bursts < 30 {
+            let cycles = cpu.step_cycles(budget.next_burst()).unwrap();
+            budget.ran(cycles);
+            std::thread::sleep(host_delay);
+            clock.wait_for_next_burst();
+        }
+        assert_eq!(clock.elapsed(), Duration::from_millis(500));
+        (cpu.total_cycles, cpu.bus.gpu.frame_hash())
+    }
+
+    #[test]
+    fn test_virtual_clock_ignores_host_speed() {
+        assert_eq!(run(Duration::ZERO), run(Duration::from_millis(2)));
+    }
+
+    #[test]
+    fn test_burst_budget_doesnt_drift() {
+        let rom = include_bytes!("../test_roms/instr_timing/instr_timing.gb");
+        let mut cpu = Cpu::new(None, rom, false);
+        let mut budget = BurstBudget::default();
+        for _ in 0..600 {
+            let cycles = cpu.step_cycles(budget.next_burst()).unwrap();
+            budget.ran(cycles);
+        }
+        // the last instruction can run a little past the end of the final burst
+        let ten_seconds = 10 * u64::from(CYCLES_PER_SECOND);
+        assert!((ten_seconds..ten_seconds + 24).contains(&cpu.total_cycles));
+    }
+}
@@ -0,0 +1,189 @@
+//! Which opcodes a set of test ROMs run, and whether the ROMs running them pass
+
+use std::fmt::Write as _;
+
+use parse_display::Display;
+
+use crate::{
+    clock::CYCLES_PER_SECOND,
+    cpu::Cpu,
+    disassembler::{instruction::Instruction, parse_instruction},
+    serial,
+};
+
+/// How far along an opcode is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum OpcodeStatus {
+    /// Doesn't decode to anything the cpu can run
+    Unimplemented,
+    /// Decodes, but no test ROM runs it
+    Untested,
+    /// Only run by ROMs which passed
+    Passed,
+    /// Run by at least one ROM which failed or never finished
+    Failed,
+}
+
+/// The status of every primary and CB-prefixed opcode
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    primary: [OpcodeStatus; 0x100],
+    prefixed: [OpcodeStatus; 0x100],
+}
+
+impl Coverage {
+    /// Starts with every opcode the decoder understands as untested
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn new() -> Self {
+        let status = |bytes: [u8; 4]| match parse_instruction(&bytes) {
+            Ok((_, Instruction::Illegal(_))) | Err(_) => OpcodeStatus::Unimplemented,
+            Ok(_) => OpcodeStatus::Untested,
+        };
+        Self {
+            primary: std::array::from_fn(|opcode| status([opcode as u8, 0, 0, 0])),
+            prefixed: std::array::from_fn(|opcode| status([0xCB, opcode as u8, 0, 0])),
+        }
+    }
+
+    /// Runs `rom` until it reports a result over serial, or for `seconds` of emulated time, then
+    /// marks every opcode it ran with the result. Returns if it passed
+    pub fn run_rom(&mut self, rom: &[u8], seconds: u64) -> bool {
+        let mut cpu = Cpu::new(None, rom, false);
+        let mut primary = [false; 0x100];
+        let mut prefixed = [false; 0x100];
+        let limit = seconds * u64::from(CYCLES_PER_SECOND);
+        let passed = loop {
+            let [opcode, prefixed_opcode, ..] = cpu.bus.slice_from(cpu.pc);
+            let instructions = cpu.total_instructions;
+            if cpu.step().is_err() {
+                break false;
+            }
+            // interrupts, HALT and DMA don't run the instruction at PC
+            if cpu.total_instructions != instructions {
+                primary[usize::from(opcode)] = true;
+                if opcode == 0xCB {
+                    prefixed[usize::from(prefixed_opcode)] = true;
+                }
+            }
+
+            if let Some(passed) = serial::test_verdict(&cpu.bus.serial.output) {
+                break passed;
+            }
+            if cpu.total_cycles >= limit {
+                break false;
+            }
+        };
+
+        for (statuses, ran) in [(&mut self.primary, primary), (&mut self.prefixed, prefixed)] {
+            for (status, _) in statuses.iter_mut().zip(ran).filter(|(_, ran)| *ran) {
+                *status = match (*status, passed) {
+                    (OpcodeStatus::Failed, _) | (_, false) => OpcodeStatus::Failed,
+                    _ => OpcodeStatus::Passed,
+                };
+            }
+        }
+        passed
+    }
+
+    pub const fn primary(&self, opcode: u8) -> OpcodeStatus {
+        self.primary[opcode as usize]
+    }
+
+    pub const fn prefixed(&self, opcode: u8) -> OpcodeStatus {
+        self.prefixed[opcode as usize]
+    }
+
+    /// A markdown summary, followed by every opcode which isn't passing
+    pub fn report(&self) -> String {
+        const STATUSES: [OpcodeStatus; 4] = [
+            OpcodeStatus::Passed,
+            OpcodeStatus::Failed,
+            OpcodeStatus::Untested,
+            OpcodeStatus::Unimplemented,
+        ];
+        let count = |statuses: &[OpcodeStatus], wanted| {
+            statuses.iter().filter(|&&status| status == wanted).count()
+        };
+
+        let mut report = String::from("# Opcode coverage\n\n| |");
+        for status in STATUSES {
+            write!(report, " {status} |").unwrap();
+        }
+        report.push_str("\n|---|---|---|---|---|\n");
+        for (name, statuses) in [("Primary", &self.primary), ("CB", &self.prefixed)] {
+            write!(report, "| {name} |").unwrap();
+            for status in STATUSES {
+                write!(report, " {} |", count(statuses, status)).unwrap();
+            }
+            report.push('\n');
+        }
+
+        for status in &STATUSES[1..] {
+            write!(report, "\n## {status}\n\n").unwrap();
+            for opcode in 0..=0xFF {
+                if self.primary(opcode) == *status {
+                    let instruction = if opcode == 0xCB {
+                        String::from("PREFIX CB")
+                    } else {
+                        describe([opcode, 0, 0, 0])
+                    };
+                    writeln!(report, "- `{opcode:02X}` {instruction}").unwrap();
+                }
+            }
+            for opcode in 0..=0xFF {
+                if self.prefixed(opcode) == *status {
+                    let instruction = describe([0xCB, opcode, 0, 0]);
+                    writeln!(report, "- `CB {opcode:02X}` {instruction}").unwrap();
+                }
+            }
+        }
+        report
+    }
+}
+
+/// The instruction `bytes` decode to, or nothing if they don't
+fn describe(bytes: [u8; 4]) -> String {
+    parse_instruction(&bytes).map_or_else(
+        |_| String::new(),
+        |(_, instruction)| instruction.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut passing = vec![0; 0x8000];
+        let mut program = Vec::new();
+        for byte in b"Passed" {
+            // LD A,byte; LDH (SB),A; LD A,$81; LDH (SC),A
+            program.extend_from_slice(&[0x3E, *byte, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02]);
+        }
+        passing[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let mut failing = vec![0; 0x8000];
+        // LD A,$00; JR -2
+        failing[0x100..0x104].copy_from_slice(&[0x3E, 0x00, 0x18, 0xFE]);
+
+        let mut coverage = Coverage::new();
+        assert!(coverage.run_rom(&passing, 1));
+        assert!(!coverage.run_rom(&failing, 1));
+        assert_eq!(coverage.primary(0xE0), OpcodeStatus::Passed);
+        assert_eq!(coverage.primary(0x3E), OpcodeStatus::Failed);
+        assert_eq!(coverage.primary(0x18), OpcodeStatus::Failed);
+        assert_eq!(coverage.prefixed(0x20), OpcodeStatus::Untested);
+        assert_eq!(coverage.primary(0xD3), OpcodeStatus::Unimplemented);
+
+        let report = coverage.report();
+        assert!(
+            report.contains("| Primary | 1 | 2 | 242 | 11 |"),
+            "{report}"
+        );
+        assert!(report.contains("| CB | 0 | 0 | 256 | 0 |"), "{report}");
+        assert!(report.contains("- `18` JR 0\n"), "{report}");
+        assert!(report.contains("- `CB 20` SLA B\n"), "{report}");
+        assert!(report.contains("- `D3` ILLEGAL $D3\n"), "{report}");
+        assert!(!report.contains("`E0`"), "{report}");
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
-use enumflags2::make_bitflags;
+use boot::BootSequence;
+use enumflags2::{BitFlags, make_bitflags};
 use memorybus::MemoryBus;
 use registers::{Flags, Registers};
 use std::fmt::Write as _;
@@ -14,9 +15,17 @@ use crate::{
             LoadType, Register, Register16, Register16Alt, RegisterOrImmediate, Rot,
         },
         parse_instruction,
+        symbols::Symbols,
     },
+    error::EmuError,
+    gpu::Mode,
+    state::{Snapshot, StateError, StateReader, StateWriter},
 };
 
+/// T-cycles in a single frame, 154 lines of 456 cycles
+pub const CYCLES_PER_FRAME: u32 = 70224;
+
+pub mod boot;
 pub mod memorybus;
 pub mod registers;
 
@@ -30,6 +39,14 @@ pub struct Cpu {
     pub interrupts_enabled: bool,
     interrupts_enabled_next: bool,
     pub halted: bool,
+    /// T-cycles elapsed since power on or the last `reset_counters`
+    pub total_cycles: u64,
+    /// Instructions executed since power on or the last `reset_counters`
+    pub total_instructions: u64,
+    /// Runs in place of the boot ROM until the cartridge takes over
+    boot_sequence: Option<BootSequence>,
+    /// Names jump and call targets in traces
+    pub symbols: Symbols,
 
     debug_bytes_consumed: Vec<u8>,
     // Optionally used
@@ -61,47 +78,124 @@ macro_rules! print_debug {
 
 impl Cpu {
     pub fn new(boot_rom: Option<&[u8; 256]>, game_rom: &[u8], test_mode: bool) -> Self {
-        // FIXME: support running without boot_rom
-        // this will need us to set the registers to a good state
-        match boot_rom {
-            Some(_) => Self {
-                registers: Registers::default(),
-                pc: 0,
-                sp: 0,
-                bus: MemoryBus::new(boot_rom, game_rom, test_mode),
-                interrupts_enabled: false,
-                interrupts_enabled_next: false,
-                halted: false,
-                debug_bytes_consumed: Vec::default(),
-                debug_context: Vec::default(),
-            },
-            None => Self {
-                registers: Registers {
-                    a: 0x01,
-                    b: 0x00,
-                    c: 0x13,
-                    d: 0x00,
-                    e: 0xD8,
-                    h: 0x01,
-                    l: 0x4D,
-                    f: make_bitflags!(Flags::{Carry | HalfCarry | Zero}),
-                },
-                pc: 0x100,
-                sp: 0xFFFE,
-                bus: MemoryBus::new(boot_rom, game_rom, test_mode),
-                interrupts_enabled: false,
-                interrupts_enabled_next: false,
-                halted: false,
-                debug_bytes_consumed: Vec::default(),
-                debug_context: Vec::default(),
-            },
+        Self::power_on(MemoryBus::new(boot_rom, game_rom, test_mode))
+    }
+
+    /// Starts at the beginning of the boot ROM if there is one, or where it would have left off
+    pub fn power_on(bus: MemoryBus) -> Self {
+        let (registers, pc, sp) = if bus.boot_rom_mapped() {
+            (Registers::default(), 0, 0)
+        } else {
+            (Self::post_boot_registers(&bus), 0x100, 0xFFFE)
+        };
+        Self {
+            registers,
+            pc,
+            sp,
+            bus,
+            interrupts_enabled: false,
+            interrupts_enabled_next: false,
+            halted: false,
+            total_cycles: 0,
+            total_instructions: 0,
+            boot_sequence: None,
+            symbols: Symbols::default(),
+            debug_bytes_consumed: Vec::default(),
+            debug_context: Vec::default(),
+        }
+    }
+
+    /// The registers the boot ROM leaves behind when handing over to the cartridge
+    fn post_boot_registers(bus: &MemoryBus) -> Registers {
+        // the boot ROM ends by adding 0x19 and every header byte from the title to the checksum,
+        // and the flags from the last ADD are left behind. With a valid header that's
+        // -checksum + checksum, so C is set unless the checksum is 0 and H unless its low
+        // nibble is
+        let (&checksum, bytes) = bus.cartridge_header()[0x34..=0x4D].split_last().unwrap();
+        let sum = bytes
+            .iter()
+            .fold(0x19_u8, |sum, byte| sum.wrapping_add(*byte));
+        let mut f = BitFlags::empty();
+        f.set(Flags::Zero, sum.wrapping_add(checksum) == 0);
+        f.set(Flags::HalfCarry, (sum & 0xF) + (checksum & 0xF) > 0xF);
+        f.set(Flags::Carry, u16::from(sum) + u16::from(checksum) > 0xFF);
+        Registers {
+            // games check this to tell if they're running on a CGB
+            a: if bus.cgb_mode { 0x11 } else { 0x01 },
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            h: 0x01,
+            l: 0x4D,
+            f,
+        }
+    }
+
+    pub const fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    /// Replaces A-L and F in one go. PC and SP are separate fields
+    pub const fn set_registers(&mut self, registers: Registers) {
+        self.registers = registers;
+    }
+
+    /// A copy in the power on state, with the same cartridge and settings
+    pub fn power_cycled(&self) -> Self {
+        Self {
+            symbols: self.symbols.clone(),
+            ..Self::power_on(self.bus.power_cycled())
+        }
+    }
+
+    /// A copy in the power on state with the same settings, but a different cartridge inserted.
+    /// With `keep_ram` the old cartridge's RAM is copied over, as far as it fits
+    pub fn with_cartridge(&self, game_rom: &[u8], keep_ram: bool) -> Self {
+        let mut bus = self.bus.with_cartridge(game_rom);
+        if keep_ram {
+            bus.load_external_ram(self.bus.external_ram());
+        }
+        Self {
+            symbols: self.symbols.clone(),
+            ..Self::power_on(bus)
+        }
+    }
+
+    /// Goes back to the power on state like the reset button, without reloading the cartridge
+    pub fn reset(&mut self) {
+        let mut cpu = self.power_cycled();
+        cpu.bus.take_io_devices(&mut self.bus);
+        *self = cpu;
+    }
+
+    /// Starts without a boot ROM, but recreates its logo animation before handing over to the
+    /// cartridge
+    pub fn new_with_boot_sequence(game_rom: &[u8], test_mode: bool) -> Self {
+        Self {
+            registers: Registers::default(),
+            pc: 0,
+            sp: 0,
+            boot_sequence: Some(BootSequence::default()),
+            ..Self::new(None, game_rom, test_mode)
         }
     }
 
-    pub fn step(&mut self) -> u8 {
+    pub fn step(&mut self) -> Result<u8, EmuError> {
         self.debug_context.clear();
 
-        let (next_pc, cycles) = if self.interrupts_enabled && self.bus.is_interrupt_pending() {
+        let (next_pc, cycles) = if let Some(boot_sequence) = &mut self.boot_sequence {
+            if boot_sequence.step(&mut self.bus) {
+                self.boot_sequence = None;
+                self.finish_boot_sequence();
+                (0x100, 4)
+            } else {
+                (self.pc, 4)
+            }
+        } else if self.bus.dma_stall > 0 {
+            self.bus.dma_stall = self.bus.dma_stall.saturating_sub(4);
+            (self.pc, 4)
+        } else if self.interrupts_enabled && self.bus.is_interrupt_pending() {
             self.push(self.pc);
             self.interrupts_enabled = false;
             self.halted = false;
@@ -110,12 +204,23 @@ impl Cpu {
         } else if !self.halted || self.bus.is_interrupt_pending() {
             self.halted = false; // disable HALT if an interrupt caused cpu to resume execution
             let slice = self.bus.slice_from(self.pc);
-            let (after, instruction) = parse_instruction(&slice).unwrap();
-            let bytes_consumed_len = slice.len() - after.len();
-            self.debug_bytes_consumed
-                .splice(.., slice[..bytes_consumed_len].iter().copied());
+            let (_, instruction) = parse_instruction(&slice).map_err(|_| EmuError::Decode {
+                pc: self.pc,
+                bytes: slice,
+            })?;
+            if let Instruction::Illegal(opcode) = instruction {
+                return Err(EmuError::IllegalOpcode {
+                    pc: self.pc,
+                    opcode,
+                });
+            }
+            self.debug_bytes_consumed.splice(
+                ..,
+                slice[..usize::from(instruction.length())].iter().copied(),
+            );
 
             let res = self.execute(instruction);
+            self.total_instructions = self.total_instructions.wrapping_add(1);
 
             // FIXME: EI should be handled even if we're dispatching an interrupt, i think...
             if self.interrupts_enabled_next {
@@ -131,18 +236,82 @@ impl Cpu {
             (self.pc, 4)
         };
 
-        self.bus.gpu.step(cycles);
+        // the PPU runs off its own clock, so it doesn't speed up in double speed mode
+        let ppu_cycles = if self.bus.double_speed {
+            cycles / 2
+        } else {
+            cycles
+        };
+        let was_hblank = self.bus.gpu.mode == Mode::HBlank;
+        self.bus.interrupt_flag |= self.bus.gpu.step(ppu_cycles);
+        if !was_hblank && self.bus.gpu.mode == Mode::HBlank {
+            self.bus.hblank_dma();
+        }
+        self.bus.step_oam_dma(cycles);
         if self.bus.timer.step(cycles) {
             self.bus.interrupt_flag.insert(InterruptFlag::Timer);
         }
+        if self.bus.serial.step(cycles) {
+            self.bus.interrupt_flag.insert(InterruptFlag::Serial);
+        }
 
         self.pc = next_pc;
-        cycles
+        self.total_cycles = self.total_cycles.wrapping_add(u64::from(cycles));
+        Ok(cycles)
+    }
+
+    /// Steps until at least `budget` cycles have run, returning how many actually ran
+    pub fn step_cycles(&mut self, budget: u32) -> Result<u32, EmuError> {
+        let mut cycles = 0;
+        while cycles < budget {
+            cycles += u32::from(self.step()?);
+        }
+        Ok(cycles)
+    }
+
+    /// Runs until the PPU enters `VBlank`, or for a frame's worth of cycles while the LCD is off.
+    /// Returns how many cycles were run
+    pub fn run_frame(&mut self) -> Result<u32, EmuError> {
+        let mut cycles = 0;
+        let frame = self.bus.gpu.frames;
+        while self.bus.gpu.lcd_enabled() || cycles < CYCLES_PER_FRAME {
+            cycles += u32::from(self.step()?);
+            if self.bus.gpu.frames != frame {
+                break;
+            }
+        }
+        Ok(cycles)
+    }
+
+    /// Steps, panicking if the emulator can't continue
+    #[cfg(test)]
+    pub fn step_unwrap(&mut self) -> u8 {
+        self.step().unwrap()
+    }
+
+    pub const fn reset_counters(&mut self) {
+        self.total_cycles = 0;
+        self.total_instructions = 0;
     }
 
+    /// Leaves the registers as the boot ROM would when handing over to the cartridge
+    fn finish_boot_sequence(&mut self) {
+        // the same as skipping the boot ROM, flags from the header sum included
+        self.registers = Self::post_boot_registers(&self.bus);
+        self.sp = 0xFFFE;
+        self.bus.write_byte(0xFF50, 0x01);
+    }
+
+    /// Formats the registers like Gameboy Doctor expects. Banked ROMs also get the active ROM
+    /// bank before PC, as 0x4000-0x7FFF is ambiguous otherwise
     pub fn format_state(&self) -> String {
+        let bank = self
+            .bus
+            .rom_bank()
+            .map_or_else(String::new, |bank| format!("Bank:{bank:02X} "));
+        let pcmem = self.bus.slice_from(self.pc);
         format!(
-            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}\n",
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} {bank}PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}\n",
             self.registers.a,
             self.registers.f.bits(),
             self.registers.b,
@@ -153,10 +322,10 @@ impl Cpu {
             self.registers.l,
             self.sp,
             self.pc,
-            self.bus.read_byte(self.pc),
-            self.bus.read_byte(self.pc + 1),
-            self.bus.read_byte(self.pc + 2),
-            self.bus.read_byte(self.pc + 3),
+            pcmem[0],
+            pcmem[1],
+            pcmem[2],
+            pcmem[3],
         )
     }
 
@@ -181,6 +350,12 @@ impl Cpu {
                 });
         trace!("{:04X} {bytes:12} {opcode:32} ; {context}", self.pc);
     }
+    /// The label for `address` in the current bank, or the address in hex
+    #[cfg(debug_assertions)]
+    fn target_name(&self, address: u16) -> String {
+        self.symbols.name(address, self.bus.rom_bank().unwrap_or(1))
+    }
+
     #[cfg(debug_assertions)]
     fn push_debug_context(&mut self, ctx: String) {
         self.debug_context.push(ctx);
@@ -188,7 +363,6 @@ impl Cpu {
 
     #[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
     fn execute(&mut self, instruction: Instruction) -> (u16, u8) {
-        #![allow(unreachable_patterns)]
         match instruction {
             Instruction::Ld(load_type) => match load_type {
                 LoadType::Indirect(indirect_type, direction) => {
@@ -287,7 +461,7 @@ impl Cpu {
                             let value = self.bus.read_byte(address);
                             debug_context!(self, "({address:04X}) = {value:02X}");
                             print_debug!(self, "LDH A, ({source})");
-                            self.registers.a = self.bus.read_byte(address);
+                            self.registers.a = value;
                         }
                     }
 
@@ -515,7 +689,7 @@ impl Cpu {
                     .pc
                     .wrapping_add(2)
                     .wrapping_add_signed(i16::from(relative));
-                print_debug!(self, "JR {condition} {target_address:04X}");
+                print_debug!(self, "JR {condition} {}", self.target_name(target_address));
 
                 self.relative_jump(should_jump, relative)
             }
@@ -529,7 +703,14 @@ impl Cpu {
                     }
                     HLOrImmediate::Immediate(address) => address,
                 };
-                print_debug!(self, "JP {condition} {target}");
+                print_debug!(
+                    self,
+                    "JP {condition} {}",
+                    match target {
+                        HLOrImmediate::HL => String::from("HL"),
+                        HLOrImmediate::Immediate(address) => self.target_name(address),
+                    }
+                );
                 match target {
                     // there is no conditional HL jump, only conditional immediate
                     HLOrImmediate::HL => (address, 4),
@@ -552,6 +733,7 @@ impl Cpu {
             Instruction::Inc16(register) => {
                 let value = self.match_register16(register);
                 let new_value = value.wrapping_add(1);
+                self.bus.inc_dec_16(value);
                 self.write_register16(register, new_value);
                 print_debug!(
                     self,
@@ -577,6 +759,7 @@ impl Cpu {
             Instruction::Dec16(register) => {
                 let value = self.match_register16(register);
                 let new_value = value.wrapping_sub(1);
+                self.bus.inc_dec_16(value);
                 self.write_register16(register, new_value);
                 print_debug!(
                     self,
@@ -589,7 +772,7 @@ impl Cpu {
                 let should_jump = self.match_jump_condition(condition);
                 let pc = self.call(should_jump, address);
 
-                print_debug!(self, "CALL {condition} {address:04X}");
+                print_debug!(self, "CALL {condition} {}", self.target_name(address));
                 pc
             }
             Instruction::Ret(condition) => {
@@ -769,6 +952,8 @@ impl Cpu {
             Instruction::Di => {
                 print_debug!(self, "DI");
                 self.interrupts_enabled = false;
+                // cancels an EI which hasn't taken effect yet
+                self.interrupts_enabled_next = false;
                 (self.pc.wrapping_add(1), 4)
             }
             Instruction::Ei => {
@@ -819,7 +1004,21 @@ impl Cpu {
 
                 (self.pc.wrapping_add(1), 4)
             }
-            _ => todo!("unimplemented instruction: {:?}", instruction),
+            Instruction::Stop => {
+                // FIXME: low power mode isn't emulated, STOP only has the side effects of
+                // resetting DIV and applying an armed speed switch
+                self.bus.write_byte(0xFF04, 0);
+                if self.bus.try_speed_switch() {
+                    debug_context!(self, "double speed = {}", self.bus.double_speed);
+                }
+                print_debug!(self, "STOP");
+                (self.pc.wrapping_add(2), 4)
+            }
+            Instruction::Illegal(opcode) => {
+                // the cpu hard locks when executing an illegal opcode, so never advance pc
+                print_debug!(self, "ILLEGAL {opcode:02X}");
+                (self.pc, 4)
+            }
         }
     }
 
@@ -894,8 +1093,6 @@ impl Cpu {
     }
 
     fn pop(&mut self) -> u16 {
-        // BUG: If the stack pointer would wrap in the middle of this read, i think this will have
-        // incorrect behaviour
         let word = self.bus.read_word(self.sp);
         debug_context!(self, "SP = {:04X}", self.sp);
         self.sp = self.sp.wrapping_add(2);
@@ -926,11 +1123,12 @@ impl Cpu {
         if add_carry {
             debug_context!(self, "C = {carry}");
         }
-        let (new_value, overflow) = self.registers.a.overflowing_add(value);
-        let (new_value, overflow2) = new_value.overflowing_add(carry);
+        // add in 16 bits so the carry-in is accounted for in one step
+        let [new_value, overflow] =
+            (u16::from(self.registers.a) + u16::from(value) + u16::from(carry)).to_le_bytes();
         self.set_flag(Flags::Zero, new_value == 0);
         self.registers.f.remove(Flags::Subtraction);
-        self.set_flag(Flags::Carry, overflow || overflow2);
+        self.set_flag(Flags::Carry, overflow != 0);
         // HalfCarry is set if the lower 4 bits added together don't fit in the lower 4 bits
         self.set_flag(
             Flags::HalfCarry,
@@ -952,21 +1150,16 @@ impl Cpu {
     }
 
     fn add_signed(&mut self, value: u16, offset: i8) -> u16 {
-        let offset = i16::from(offset);
-        let new_value = value.wrapping_add_signed(offset);
-        let mask = 0b1111;
-        let mask_signed = 0b1111;
+        let new_value = value.wrapping_add_signed(offset.into());
+        // annoyingly, carry and half carry are set as if it was an 8-bit add of the low byte and
+        // the offset as unsigned, not 16-bit, so negative offsets still carry
+        let low = value & 0b1111_1111;
+        let offset = u16::from(offset.cast_unsigned());
         self.set_flag(
             Flags::HalfCarry,
-            (value & mask).wrapping_add_signed(offset & mask_signed) > mask,
-        );
-        // annoyingly, carry and half carry are set as if it was 8-bit, not 16-bit
-        let mask = 0b1111_1111;
-        let mask_signed = 0b1111_1111;
-        self.set_flag(
-            Flags::Carry,
-            (value & mask).wrapping_add_signed(offset & mask_signed) > mask,
+            (low & 0b1111) + (offset & 0b1111) > 0b1111,
         );
+        self.set_flag(Flags::Carry, low + offset > 0b1111_1111);
         self.registers.f.remove(Flags::Subtraction | Flags::Zero);
 
         new_value
@@ -1014,10 +1207,11 @@ impl Cpu {
         self.set_flag(Flags::Zero, new_value == 0);
         self.registers.f.insert(Flags::Subtraction);
         self.set_flag(Flags::Carry, overflow || overflow2);
-        self.set_flag(
-            Flags::HalfCarry,
-            (self.registers.a & 0b1111) < (value & 0b1111) + carry,
-        );
+        // borrowing out of the low nibble leaves bit 4 set
+        let low_nibble = (self.registers.a & 0b1111)
+            .wrapping_sub(value & 0b1111)
+            .wrapping_sub(carry);
+        self.set_flag(Flags::HalfCarry, low_nibble & 0b1_0000 != 0);
         new_value
     }
 
@@ -1068,7 +1262,8 @@ impl Cpu {
         let new_value = value.wrapping_sub(1);
         self.set_flag(Flags::Zero, new_value == 0);
         self.registers.f.insert(Flags::Subtraction);
-        // HalfCarry is set if the lower 4 bits are 0, meaning we needed a bit from the upper 4 bits
+        // HalfCarry is set if the lower 4 bits are 0, meaning we needed a bit from the upper 4 bits.
+        // `trailing_zeros() >= 4` is the same as `value & 0b1111 == 0`, including for 0
         self.set_flag(Flags::HalfCarry, value.trailing_zeros() >= 4);
         new_value
     }
@@ -1208,9 +1403,48 @@ impl Cpu {
     }
 }
 
+/// A state saved during the boot animation picks up with it already finished
+impl Snapshot for Cpu {
+    fn save(&self, state: &mut StateWriter) {
+        state.u16(self.registers.af());
+        state.u16(self.registers.bc());
+        state.u16(self.registers.de());
+        state.u16(self.registers.hl());
+        state.u16(self.pc);
+        state.u16(self.sp);
+        state.bool(self.interrupts_enabled);
+        state.bool(self.interrupts_enabled_next);
+        state.bool(self.halted);
+        state.u64(self.total_cycles);
+        state.u64(self.total_instructions);
+        self.bus.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers.set_af(state.u16()?);
+        self.registers.set_bc(state.u16()?);
+        self.registers.set_de(state.u16()?);
+        self.registers.set_hl(state.u16()?);
+        self.pc = state.u16()?;
+        self.sp = state.u16()?;
+        self.interrupts_enabled = state.bool()?;
+        self.interrupts_enabled_next = state.bool()?;
+        self.halted = state.bool()?;
+        self.total_cycles = state.u64()?;
+        self.total_instructions = state.u64()?;
+        self.boot_sequence = None;
+        self.bus.load(state)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{
+        cpu::memorybus::io::IoDevice,
+        gpu::{LCDControl, Mode, OAM_SIZE, VRAM_SIZE},
+    };
+    use num_traits::FromPrimitive as _;
 
     #[test]
     fn test_boot_rom() {
@@ -1218,7 +1452,967 @@ mod test {
         let test_rom = include_bytes!("../test_roms/cpu_instrs/individual/01-special.gb");
         let mut cpu = Cpu::new(Some(boot_rom), test_rom, false);
         while cpu.pc < 0x100 {
-            cpu.step();
+            cpu.step_unwrap();
+        }
+    }
+
+    #[test]
+    fn test_boot_sequence_matches_boot_rom() {
+        let boot_rom = include_bytes!("../dmg_boot.bin");
+        // a DMG only ROM, as the DMG boot ROM always leaves A=01 even when a CGB game runs in
+        // CGB mode
+        let test_rom = include_bytes!("../test_roms/mbcs/mbc1/bits_bank1.gb");
+        let mut expected = Cpu::new(Some(boot_rom), test_rom, false);
+        while expected.pc != 0x100 {
+            expected.step_unwrap();
+        }
+        let mut cpu = Cpu::new_with_boot_sequence(test_rom, false);
+        while cpu.pc != 0x100 {
+            cpu.step_unwrap();
+        }
+
+        assert_eq!(cpu.format_state(), expected.format_state());
+        assert_eq!(cpu.registers.f, expected.registers.f);
+        assert!((0..VRAM_SIZE).all(|i| cpu.bus.gpu.read_vram(i) == expected.bus.gpu.read_vram(i)));
+        assert_eq!(cpu.bus.gpu.lcd_control, expected.bus.gpu.lcd_control);
+        assert_eq!(cpu.bus.gpu.scroll_y, expected.bus.gpu.scroll_y);
+        assert_eq!(
+            cpu.bus.gpu.palettes.background,
+            expected.bus.gpu.palettes.background
+        );
+    }
+
+    #[test]
+    fn test_boot_sequence_flags_match_boot_rom() {
+        // a checksum of 0x10, where the boot ROM's last ADD carries without half carrying
+        let boot_rom = include_bytes!("../dmg_boot.bin");
+        let mut rom = vec![0; 0x8000];
+        crate::header::patch_logo(&mut rom);
+        rom[0x134] = 0xD7;
+        rom[0x14D] = 0x10;
+        let mut expected = Cpu::new(Some(boot_rom), &rom, false);
+        while expected.pc != 0x100 {
+            expected.step_unwrap();
+        }
+        let mut cpu = Cpu::new_with_boot_sequence(&rom, false);
+        while cpu.pc != 0x100 {
+            cpu.step_unwrap();
+        }
+        assert_eq!(expected.registers.f, make_bitflags!(Flags::{Zero | Carry}));
+        assert_eq!(cpu.format_state(), expected.format_state());
+    }
+
+    #[test]
+    fn test_adc_carry_in() {
+        let mut cpu = Cpu::new(None, &[], false);
+        cpu.registers.a = 0xFF;
+        cpu.registers.f = Flags::Carry.into();
+        cpu.execute(Instruction::Arithmetic(
+            Alu::Adc,
+            RegisterOrImmediate::Immediate(0x00),
+        ));
+        assert_eq!(cpu.registers.a, 0x00);
+        assert_eq!(
+            cpu.registers.f,
+            make_bitflags!(Flags::{Zero | HalfCarry | Carry})
+        );
+    }
+
+    #[test]
+    fn test_sbc_carry_in() {
+        let mut cpu = Cpu::new(None, &[], false);
+        cpu.registers.a = 0x00;
+        cpu.registers.f = Flags::Carry.into();
+        cpu.execute(Instruction::Arithmetic(
+            Alu::Sbc,
+            RegisterOrImmediate::Immediate(0x0F),
+        ));
+        assert_eq!(cpu.registers.a, 0xF0);
+        assert_eq!(
+            cpu.registers.f,
+            make_bitflags!(Flags::{Subtraction | HalfCarry | Carry})
+        );
+
+        // SBC A,A
+        cpu.registers.a = 0x1F;
+        cpu.registers.f = Flags::Carry.into();
+        cpu.execute(Instruction::Arithmetic(
+            Alu::Sbc,
+            RegisterOrImmediate::Register(Register::A),
+        ));
+        assert_eq!(cpu.registers.a, 0xFF);
+        assert_eq!(
+            cpu.registers.f,
+            make_bitflags!(Flags::{Subtraction | HalfCarry | Carry})
+        );
+    }
+
+    #[test]
+    fn test_bit_preserves_carry() {
+        // CB 40-7F, every bit of every register, each tested against a value with only that bit
+        // set and one with only that bit clear
+        for opcode in 0x40..=0x7F_u8 {
+            let (bit, register) = (opcode >> 3 & 7, Register::from_u8(opcode & 7).unwrap());
+            for (value, zero) in [(1 << bit, false), (!(1 << bit), true)] {
+                for carry in [false, true] {
+                    let mut rom = vec![0; 0x8000];
+                    rom[0x100..0x102].copy_from_slice(&[0xCB, opcode]);
+                    let mut cpu = Cpu::new(None, &rom, false);
+                    cpu.registers.set_hl(0xC000);
+                    cpu.write_register(register, value);
+                    cpu.registers.f = make_bitflags!(Flags::{Subtraction});
+                    cpu.set_flag(Flags::Carry, carry);
+
+                    let cycles = if register == Register::HLIndirect {
+                        12
+                    } else {
+                        8
+                    };
+                    assert_eq!(cpu.step_unwrap(), cycles, "CB {opcode:02X}");
+                    let mut expected = BitFlags::from(Flags::HalfCarry);
+                    expected.set(Flags::Zero, zero);
+                    expected.set(Flags::Carry, carry);
+                    assert_eq!(cpu.registers.f, expected, "CB {opcode:02X} {value:02X}");
+                    assert_eq!(cpu.match_register(register), value, "CB {opcode:02X}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_shift_right() {
+        // CB 28-2F is SRA, which keeps bit 7, and CB 38-3F is SRL, which clears it
+        for opcode in (0x28..=0x2F_u8).chain(0x38..=0x3F) {
+            let register = Register::from_u8(opcode & 7).unwrap();
+            let arithmetic = opcode < 0x30;
+            let cases = [
+                (0x01, 0x00, make_bitflags!(Flags::{Zero | Carry})),
+                (
+                    0x80,
+                    if arithmetic { 0xC0 } else { 0x40 },
+                    BitFlags::empty(),
+                ),
+                (
+                    0x83,
+                    if arithmetic { 0xC1 } else { 0x41 },
+                    Flags::Carry.into(),
+                ),
+                (0x00, 0x00, Flags::Zero.into()),
+            ];
+            for (value, result, flags) in cases {
+                let mut rom = vec![0; 0x8000];
+                rom[0x100..0x102].copy_from_slice(&[0xCB, opcode]);
+                let mut cpu = Cpu::new(None, &rom, false);
+                cpu.registers.set_hl(0xC000);
+                cpu.write_register(register, value);
+                cpu.registers.f = BitFlags::all();
+
+                let cycles = if register == Register::HLIndirect {
+                    16
+                } else {
+                    8
+                };
+                assert_eq!(cpu.step_unwrap(), cycles, "CB {opcode:02X}");
+                assert_eq!(cpu.registers.f, flags, "CB {opcode:02X} {value:02X}");
+                assert_eq!(cpu.match_register(register), result, "CB {opcode:02X}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_hl() {
+        // opcode, HL, operand, result, flags. HalfCarry is from bit 11 and Zero is left alone
+        let cases = [
+            (
+                0x09,
+                0x0FFF,
+                0x0001,
+                0x1000,
+                BitFlags::from(Flags::HalfCarry),
+            ), // ADD HL, BC
+            (0x09, 0x00FF, 0x0001, 0x0100, BitFlags::empty()),
+            (0x19, 0x8000, 0x8000, 0x0000, Flags::Carry.into()), // ADD HL, DE
+            (
+                0x29,
+                0x8800,
+                0x8800,
+                0x1000,
+                make_bitflags!(Flags::{HalfCarry | Carry}),
+            ), // ADD HL, HL
+            (
+                0x39,
+                0x0001,
+                0xFFFF,
+                0x0000,
+                make_bitflags!(Flags::{HalfCarry | Carry}),
+            ), // ADD HL, SP
+        ];
+        for (opcode, hl, operand, result, flags) in cases {
+            for zero in [false, true] {
+                let mut rom = vec![0; 0x8000];
+                rom[0x100] = opcode;
+                let mut cpu = Cpu::new(None, &rom, false);
+                cpu.registers.set_bc(operand);
+                cpu.registers.set_de(operand);
+                cpu.sp = operand;
+                cpu.registers.set_hl(hl);
+                cpu.registers.f = make_bitflags!(Flags::{Subtraction});
+                cpu.set_flag(Flags::Zero, zero);
+
+                assert_eq!(cpu.step_unwrap(), 8, "{opcode:02X}");
+                assert_eq!(cpu.registers.hl(), result, "{opcode:02X} {hl:04X}");
+                let mut expected = flags;
+                expected.set(Flags::Zero, zero);
+                assert_eq!(cpu.registers.f, expected, "{opcode:02X} {hl:04X}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_sp_offset() {
+        // SP, offset, result, flags. Zero and Subtraction are always cleared
+        let cases = [
+            (0x0000, 0x01, 0x0001, BitFlags::empty()),
+            (0x000F, 0x01, 0x0010, BitFlags::from(Flags::HalfCarry)),
+            (
+                0x00FF,
+                0x01,
+                0x0100,
+                make_bitflags!(Flags::{HalfCarry | Carry}),
+            ),
+            (
+                0xFFF8,
+                0x08,
+                0x0000,
+                make_bitflags!(Flags::{HalfCarry | Carry}),
+            ),
+            (0x0000, 0xFF, 0xFFFF, BitFlags::empty()), // -1
+            (
+                0x0001,
+                0xFF,
+                0x0000,
+                make_bitflags!(Flags::{HalfCarry | Carry}),
+            ),
+            (0x1000, 0x80, 0x0F80, BitFlags::empty()), // -128
+            (
+                0xD00F,
+                0xF1,
+                0xD000,
+                make_bitflags!(Flags::{HalfCarry | Carry}),
+            ), // -15
+            (0xD0F0, 0xF0, 0xD0E0, BitFlags::from(Flags::Carry)), // -16
+        ];
+        // opcode, cycles
+        for (opcode, cycles) in [(0xE8, 16), (0xF8, 12)] {
+            for (sp, offset, result, flags) in cases {
+                let mut rom = vec![0; 0x8000];
+                rom[0x100..0x102].copy_from_slice(&[opcode, offset]);
+                let mut cpu = Cpu::new(None, &rom, false);
+                cpu.sp = sp;
+                cpu.registers.set_hl(0x1234);
+                cpu.registers.f = make_bitflags!(Flags::{Zero | Subtraction});
+
+                assert_eq!(cpu.step_unwrap(), cycles, "{opcode:02X}");
+                assert_eq!(cpu.pc, 0x102);
+                let context = format!("{opcode:02X} {sp:04X} {offset:02X}");
+                if opcode == 0xE8 {
+                    assert_eq!(cpu.sp, result, "{context}");
+                    assert_eq!(cpu.registers.hl(), 0x1234, "{context}");
+                } else {
+                    assert_eq!(cpu.registers.hl(), result, "{context}");
+                    assert_eq!(cpu.sp, sp, "{context}");
+                }
+                assert_eq!(cpu.registers.f, flags, "{context}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_ldh() {
+        // opcode, immediate, cycles, length
+        let cases = [
+            (0xE0, 0x42, 12, 2), // LDH (n), A
+            (0xF0, 0x42, 12, 2), // LDH A, (n)
+            (0xE2, 0x00, 8, 1),  // LD (C), A
+            (0xF2, 0x00, 8, 1),  // LD A, (C)
+        ];
+        for (opcode, immediate, cycles, length) in cases {
+            let mut rom = vec![0; 0x8000];
+            rom[0x100..0x102].copy_from_slice(&[opcode, immediate]);
+            let mut cpu = Cpu::new(None, &rom, false);
+            cpu.registers.c = 0x80;
+            cpu.registers.a = 0x12;
+            cpu.bus.write_byte(0xFF42, 0x34);
+            cpu.bus.write_byte(0xFF80, 0x56);
+
+            assert_eq!(cpu.step_unwrap(), cycles, "{opcode:02X}");
+            assert_eq!(cpu.pc, 0x100 + length, "{opcode:02X}");
+            let (scroll_y, hram, a) = match opcode {
+                0xE0 => (0x12, 0x56, 0x12),
+                0xF0 => (0x34, 0x56, 0x34),
+                0xE2 => (0x34, 0x12, 0x12),
+                _ => (0x34, 0x56, 0x56),
+            };
+            assert_eq!(cpu.bus.gpu.scroll_y, scroll_y, "{opcode:02X}");
+            assert_eq!(cpu.bus.read_byte(0xFF80), hram, "{opcode:02X}");
+            assert_eq!(cpu.registers.a, a, "{opcode:02X}");
+        }
+    }
+
+    #[test]
+    fn test_ldh_reads_once() {
+        /// Counts how many times it's read
+        #[derive(Debug, Default)]
+        struct Counter(std::cell::Cell<u8>);
+        impl IoDevice for Counter {
+            fn read(&self, _address: usize) -> u8 {
+                self.0.set(self.0.get() + 1);
+                self.0.get()
+            }
+            fn write(&mut self, _address: usize, _value: u8) {}
+        }
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0xF0, 0x7F]);
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus
+            .register_io_device(0xFF7F..=0xFF7F, Counter::default())
+            .unwrap();
+        cpu.step_unwrap();
+        assert_eq!(cpu.registers.a, 1);
+    }
+
+    #[test]
+    fn test_arithmetic_cycles() {
+        // opcode, cycles, length. (HL) and immediates both spend a machine cycle reading memory
+        let cases = [
+            (0x80, 4, 1), // ADD A, B
+            (0x86, 8, 1), // ADD A, (HL)
+            (0x8E, 8, 1), // ADC A, (HL)
+            (0x96, 8, 1), // SUB (HL)
+            (0x9E, 8, 1), // SBC A, (HL)
+            (0xA6, 8, 1), // AND (HL)
+            (0xAE, 8, 1), // XOR (HL)
+            (0xB6, 8, 1), // OR (HL)
+            (0xBE, 8, 1), // CP (HL)
+            (0xC6, 8, 2), // ADD A, n
+            (0xCE, 8, 2), // ADC A, n
+            (0xD6, 8, 2), // SUB n
+            (0xDE, 8, 2), // SBC A, n
+            (0xE6, 8, 2), // AND n
+            (0xEE, 8, 2), // XOR n
+            (0xF6, 8, 2), // OR n
+            (0xFE, 8, 2), // CP n
+        ];
+        for (opcode, cycles, length) in cases {
+            let mut rom = vec![0; 0x8000];
+            rom[0x100] = opcode;
+            let mut cpu = Cpu::new(None, &rom, false);
+            cpu.registers.set_hl(0xC000);
+            assert_eq!(cpu.step_unwrap(), cycles, "{opcode:02X}");
+            assert_eq!(cpu.pc, 0x100 + length, "{opcode:02X}");
+        }
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        // opcode, cycles when taken, cycles when not taken. NZ and NC are taken with F = 0, and Z
+        // and C are taken with F = Z | C
+        let conditional = [
+            (0x20, 12, 8),  // JR NZ
+            (0x28, 12, 8),  // JR Z
+            (0x30, 12, 8),  // JR NC
+            (0x38, 12, 8),  // JR C
+            (0xC2, 16, 12), // JP NZ
+            (0xCA, 16, 12), // JP Z
+            (0xD2, 16, 12), // JP NC
+            (0xDA, 16, 12), // JP C
+            (0xC4, 24, 12), // CALL NZ
+            (0xCC, 24, 12), // CALL Z
+            (0xD4, 24, 12), // CALL NC
+            (0xDC, 24, 12), // CALL C
+            (0xC0, 20, 8),  // RET NZ
+            (0xC8, 20, 8),  // RET Z
+            (0xD0, 20, 8),  // RET NC
+            (0xD8, 20, 8),  // RET C
+        ];
+        let unconditional = [
+            (0x18, 12), // JR
+            (0xC3, 16), // JP
+            (0xE9, 4),  // JP HL
+            (0xCD, 24), // CALL
+            (0xC9, 16), // RET
+            (0xD9, 16), // RETI
+        ];
+
+        let run = |opcode, flags| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x100] = opcode;
+            let mut cpu = Cpu::new(None, &rom, false);
+            cpu.registers.f = flags;
+            cpu.step_unwrap()
+        };
+        let set = make_bitflags!(Flags::{Zero | Carry});
+        for (opcode, taken, not_taken) in conditional {
+            let (when_clear, when_set) = if opcode & 0b1000 == 0 {
+                (taken, not_taken)
+            } else {
+                (not_taken, taken)
+            };
+            assert_eq!(run(opcode, BitFlags::EMPTY), when_clear, "{opcode:02X}");
+            assert_eq!(run(opcode, set), when_set, "{opcode:02X}");
+        }
+        for (opcode, cycles) in unconditional {
+            assert_eq!(run(opcode, BitFlags::EMPTY), cycles, "{opcode:02X}");
+            assert_eq!(run(opcode, set), cycles, "{opcode:02X}");
+        }
+    }
+
+    #[test]
+    fn test_conditional_call() {
+        let set = make_bitflags!(Flags::{Zero | Carry});
+        for opcode in [0xC4, 0xCC, 0xD4, 0xDC] {
+            // NZ and NC are taken with F = 0, and Z and C with F = Z | C
+            let (taken, not_taken) = if opcode & 0b1000 == 0 {
+                (BitFlags::EMPTY, set)
+            } else {
+                (set, BitFlags::EMPTY)
+            };
+            let mut rom = vec![0; 0x8000];
+            rom[0x100..0x103].copy_from_slice(&[opcode, 0x34, 0x12]);
+
+            let mut cpu = Cpu::new(None, &rom, false);
+            cpu.sp = 0xD000;
+            cpu.registers.f = not_taken;
+            assert_eq!(cpu.step_unwrap(), 12, "{opcode:02X}");
+            assert_eq!((cpu.pc, cpu.sp), (0x103, 0xD000), "{opcode:02X}");
+
+            let mut cpu = Cpu::new(None, &rom, false);
+            cpu.sp = 0xD000;
+            cpu.registers.f = taken;
+            assert_eq!(cpu.step_unwrap(), 24, "{opcode:02X}");
+            assert_eq!((cpu.pc, cpu.sp), (0x1234, 0xCFFE), "{opcode:02X}");
+            assert_eq!(cpu.bus.read_word(0xCFFE), 0x103, "{opcode:02X}");
+        }
+    }
+
+    /// Totals from the sm83 cycle arrays. Where each access lands within them only matters once
+    /// there's M-cycle stepping
+    #[test]
+    fn test_stack_cycles() {
+        let table = [
+            (0xC5, 16), // PUSH BC
+            (0xD5, 16), // PUSH DE
+            (0xE5, 16), // PUSH HL
+            (0xF5, 16), // PUSH AF
+            (0xC1, 12), // POP BC
+            (0xD1, 12), // POP DE
+            (0xE1, 12), // POP HL
+            (0xF1, 12), // POP AF
+            (0xCD, 24), // CALL
+            (0xC9, 16), // RET
+        ];
+        for (opcode, cycles) in table {
+            let mut rom = vec![0; 0x8000];
+            rom[0x100] = opcode;
+            let mut cpu = Cpu::new(None, &rom, false);
+            assert_eq!(cpu.step_unwrap(), cycles, "{opcode:02X}");
+        }
+
+        let mut rom = vec![0; 0x8000];
+        // PUSH BC
+        rom[0x100] = 0xC5;
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.registers.set_bc(0x1234);
+        cpu.sp = 0xD000;
+        cpu.step_unwrap();
+        assert_eq!(cpu.sp, 0xCFFE);
+        assert_eq!(cpu.bus.read_byte(0xCFFF), 0x12);
+        assert_eq!(cpu.bus.read_byte(0xCFFE), 0x34);
+    }
+
+    #[test]
+    fn test_inc_dec_hl_indirect() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x34; // INC (HL)
+        rom[0x101] = 0x35; // DEC (HL)
+
+        // (HL) before, opcode, (HL) after, flags after. Carry is set beforehand and must survive
+        let cases = [
+            (0x0F, 0x34, 0x10, make_bitflags!(Flags::{HalfCarry | Carry})),
+            (
+                0xFF,
+                0x34,
+                0x00,
+                make_bitflags!(Flags::{Zero | HalfCarry | Carry}),
+            ),
+            (
+                0x10,
+                0x35,
+                0x0F,
+                make_bitflags!(Flags::{Subtraction | HalfCarry | Carry}),
+            ),
+            (
+                0x00,
+                0x35,
+                0xFF,
+                make_bitflags!(Flags::{Subtraction | HalfCarry | Carry}),
+            ),
+            (
+                0x01,
+                0x35,
+                0x00,
+                make_bitflags!(Flags::{Zero | Subtraction | Carry}),
+            ),
+        ];
+        for (before, opcode, after, flags) in cases {
+            let mut cpu = Cpu::new(None, &rom, false);
+            cpu.pc = if opcode == 0x34 { 0x100 } else { 0x101 };
+            cpu.registers.f = Flags::Carry.into();
+            // H and L are left alone, so half-carry can only come from the memory value
+            cpu.registers.h = 0xC0;
+            cpu.registers.l = 0x00;
+            cpu.bus.write_byte(0xC000, before);
+
+            assert_eq!(cpu.step_unwrap(), 12);
+            assert_eq!(cpu.bus.read_byte(0xC000), after);
+            assert_eq!(cpu.registers.f, flags, "{opcode:02X} on {before:02X}");
+            assert_eq!((cpu.registers.h, cpu.registers.l), (0xC0, 0x00));
+        }
+    }
+
+    #[test]
+    fn test_speed_switch() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x10; // STOP
+        rom[0x102] = 0x10; // STOP
+
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus.cgb_mode = true;
+        assert_eq!(cpu.bus.read_byte(0xFF4D), 0x7E);
+        cpu.bus.write_byte(0xFF4D, 1);
+        assert_eq!(cpu.bus.read_byte(0xFF4D), 0x7F);
+
+        cpu.step_unwrap();
+        assert!(cpu.bus.double_speed);
+        assert_eq!(cpu.bus.read_byte(0xFF4D), 0xFE);
+
+        // without arming it again STOP leaves the speed alone
+        cpu.step_unwrap();
+        assert!(cpu.bus.double_speed);
+    }
+
+    #[test]
+    fn test_speed_switch_ignored_on_dmg() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x10; // STOP
+
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus.write_byte(0xFF4D, 1);
+        assert_eq!(cpu.bus.read_byte(0xFF4D), 0xFF);
+        cpu.step_unwrap();
+        assert!(!cpu.bus.double_speed);
+    }
+
+    #[test]
+    fn test_hblank_dma() {
+        let rom = vec![0; 0x8000]; // all NOPs
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus.cgb_mode = true;
+        cpu.bus.restrict_ppu_access = false;
+        cpu.bus.write_byte(0xFF40, 0x80);
+        cpu.bus.gpu.mode = Mode::OamScan;
+        for offset in 0..0x30 {
+            cpu.bus.write_byte(0xC000 + offset, 0xAA);
+        }
+        cpu.bus.write_byte(0xFF51, 0xC0);
+        cpu.bus.write_byte(0xFF52, 0x00);
+        cpu.bus.write_byte(0xFF53, 0x00);
+        cpu.bus.write_byte(0xFF54, 0x00);
+        // 3 blocks, one per HBlank
+        cpu.bus.write_byte(0xFF55, 0x82);
+        assert_eq!(cpu.bus.read_byte(0xFF55), 0x02);
+
+        let copied = |cpu: &Cpu| {
+            (0..0x40)
+                .filter(|&i| cpu.bus.gpu.read_vram(i) == 0xAA)
+                .count()
+        };
+        for (line, remaining) in [(1, 0x01), (2, 0x00), (3, 0xFF)] {
+            while cpu.bus.gpu.line < line {
+                cpu.step_unwrap();
+            }
+            assert_eq!(copied(&cpu), usize::from(line) * 0x10);
+            assert_eq!(cpu.bus.read_byte(0xFF55), remaining);
+        }
+    }
+
+    #[test]
+    fn test_dma_stalls_cpu() {
+        let rom = vec![0; 0x8000]; // all NOPs
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus.cgb_mode = true;
+        cpu.bus.write_byte(0xFF55, 0x00);
+        // a single block takes 32 cycles
+        for _ in 0..8 {
+            assert_eq!(cpu.step_unwrap(), 4);
+            assert_eq!(cpu.pc, 0x100);
+        }
+        cpu.step_unwrap();
+        assert_eq!(cpu.pc, 0x101);
+    }
+
+    #[test]
+    fn test_ly_reaches_vblank() {
+        let mut rom = vec![0; 0x8000];
+        // LDH A,(LY); CP $90; JR NZ,-6
+        rom[0x100..0x106].copy_from_slice(&[0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA]);
+        for test_mode in [false, true] {
+            let mut cpu = Cpu::new(None, &rom, test_mode);
+            cpu.bus.write_byte(0xFF40, 0x80);
+            while cpu.pc != 0x106 {
+                cpu.step_unwrap();
+                assert!(cpu.total_cycles < u64::from(CYCLES_PER_FRAME));
+            }
+            if !test_mode {
+                assert_eq!(cpu.bus.gpu.line, 0x90);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let mut cpu = Cpu::new(None, rom, false);
+        cpu.bus.restrict_ppu_access = false;
+        cpu.step_cycles(CYCLES_PER_FRAME * 3).unwrap();
+        cpu.reset();
+
+        let mut fresh = Cpu::new(None, rom, false);
+        fresh.bus.restrict_ppu_access = false;
+        assert_eq!(cpu.format_state(), fresh.format_state());
+        assert_eq!((cpu.total_cycles, cpu.interrupts_enabled), (0, false));
+        assert!(!cpu.bus.restrict_ppu_access);
+        assert!((0xC000..=0xDFFF).all(|address| cpu.bus.read_byte(address) == 0));
+        assert!((0..VRAM_SIZE).all(|index| cpu.bus.gpu.read_vram(index) == 0));
+
+        // both run the same from there
+        for _ in 0..1000 {
+            cpu.step_unwrap();
+            fresh.step_unwrap();
+        }
+        assert_eq!(cpu.format_state(), fresh.format_state());
+    }
+
+    #[test]
+    fn test_reset_with_boot_rom() {
+        let boot_rom = include_bytes!("../dmg_boot.bin");
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let mut cpu = Cpu::new(Some(boot_rom), rom, false);
+        while cpu.pc != 0x100 {
+            cpu.step_unwrap();
         }
+        cpu.reset();
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(cpu.bus.read_byte(0), boot_rom[0]);
+    }
+
+    #[test]
+    fn test_pop_af_masks_flags() {
+        let mut rom = vec![0; 0x8000];
+        // LD BC,$1234; PUSH BC; POP AF; PUSH AF; POP DE
+        rom[0x100..0x107].copy_from_slice(&[0x01, 0x34, 0x12, 0xC5, 0xF1, 0xF5, 0xD1]);
+        let mut cpu = Cpu::new(None, &rom, false);
+        for _ in 0..3 {
+            cpu.step_unwrap();
+        }
+        assert_eq!(cpu.registers.af(), 0x1230);
+        assert_eq!(cpu.registers.f.bits(), 0x30);
+        for _ in 0..2 {
+            cpu.step_unwrap();
+        }
+        // the low nibble stays zero going back out onto the stack
+        assert_eq!(cpu.registers.de(), 0x1230);
+    }
+
+    #[test]
+    fn test_hl_increment_loads() {
+        // opcode, HL before, HL after
+        let table = [
+            (0x22, 0xC000, 0xC001), // LD (HL+),A
+            (0x2A, 0xC000, 0xC001), // LD A,(HL+)
+            (0x32, 0xC001, 0xC000), // LD (HL-),A
+            (0x3A, 0xC001, 0xC000), // LD A,(HL-)
+            (0x22, 0xFFFF, 0x0000),
+            (0x2A, 0xFFFF, 0x0000),
+            (0x32, 0x0000, 0xFFFF),
+            (0x3A, 0x0000, 0xFFFF),
+        ];
+        for (opcode, hl, new_hl) in table {
+            let mut rom = vec![0; 0x8000];
+            rom[0] = 0x15;
+            rom[0x100] = opcode;
+            let mut cpu = Cpu::new(None, &rom, false);
+            cpu.bus.write_byte(0xFFFF, 0x0A);
+            cpu.bus.write_byte(0xC000, 0x33);
+            cpu.bus.write_byte(0xC001, 0x55);
+            cpu.registers.set_hl(hl);
+            cpu.registers.a = 0x1F;
+            let before = cpu.bus.read_byte(hl);
+            cpu.step_unwrap();
+
+            assert_eq!(cpu.registers.hl(), new_hl, "{opcode:02X} {hl:04X}");
+            // the access is always to HL from before it changed
+            if opcode & 0b1000 == 0 {
+                if hl != 0x0000 {
+                    assert_eq!(cpu.bus.read_byte(hl), 0x1F, "{opcode:02X} {hl:04X}");
+                }
+            } else {
+                assert_eq!(cpu.registers.a, before, "{opcode:02X} {hl:04X}");
+            }
+            #[cfg(debug_assertions)]
+            assert_eq!(cpu.debug_context[0], format!("HL = {hl:04X}"));
+        }
+    }
+
+    #[test]
+    fn test_ei_delays_interrupt() {
+        let mut rom = vec![0; 0x8000];
+        // EI; NOP; NOP
+        rom[0x100] = 0xFB;
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus.write_byte(0xFFFF, 0x04);
+        cpu.bus.interrupt_flag.insert(InterruptFlag::Timer);
+        cpu.step_unwrap();
+        // the instruction after EI always runs before the interrupt
+        cpu.step_unwrap();
+        assert_eq!(cpu.pc, 0x102);
+        cpu.step_unwrap();
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.pop(), 0x102);
+    }
+
+    #[test]
+    fn test_di_cancels_ei() {
+        let mut rom = vec![0; 0x8000];
+        // EI; DI; NOP; NOP
+        rom[0x100..0x102].copy_from_slice(&[0xFB, 0xF3]);
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus.write_byte(0xFFFF, 0x04);
+        cpu.bus.interrupt_flag.insert(InterruptFlag::Timer);
+        for _ in 0..4 {
+            cpu.step_unwrap();
+        }
+        assert_eq!(cpu.pc, 0x104);
+        assert!(!cpu.interrupts_enabled);
+    }
+
+    #[test]
+    fn test_inc_hl_in_oam_trips_oam_bug() {
+        let mut rom = vec![0; 0x8000];
+        // INC HL; INC HL
+        rom[0x100..0x102].copy_from_slice(&[0x23, 0x23]);
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus.gpu.lcd_control = LCDControl::DisplayEnabled.into();
+        cpu.bus.gpu.mode = Mode::OamScan;
+        for address in 0..OAM_SIZE {
+            cpu.bus.gpu.write_oam(address, 0xFF);
+        }
+        // the PPU is at the start of OAM scan, so push it along to row 4
+        cpu.bus.gpu.step(16);
+        cpu.registers.set_hl(0xFE00);
+        cpu.bus.gpu.write_oam(0x20, 0x00);
+        cpu.step_unwrap();
+        // row 4 was mixed with row 3
+        assert_eq!(cpu.bus.gpu.read_oam(0x20), 0xFF);
+
+        cpu.bus.oam_bug = false;
+        cpu.bus.gpu.write_oam(0x30, 0x00);
+        cpu.step_unwrap();
+        assert_eq!(cpu.bus.gpu.read_oam(0x30), 0x00);
+    }
+
+    #[test]
+    fn test_store_sp() {
+        let mut rom = vec![0; 0x8000];
+        // LD ($C000),SP; LD ($FFFF),SP
+        rom[0x100..0x106].copy_from_slice(&[0x08, 0x00, 0xC0, 0x08, 0xFF, 0xFF]);
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.sp = 0xAB1F;
+        assert_eq!(cpu.step_unwrap(), 20);
+        assert_eq!(cpu.pc, 0x103);
+        assert_eq!(cpu.bus.read_byte(0xC000), 0x1F);
+        assert_eq!(cpu.bus.read_byte(0xC001), 0xAB);
+
+        // the high byte wraps around to ROM, where the write is ignored
+        assert_eq!(cpu.step_unwrap(), 20);
+        assert_eq!(cpu.bus.read_byte(0xFFFF), 0x1F);
+        assert_eq!(cpu.bus.read_byte(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_fetch_from_vram_during_drawing() {
+        for restrict_ppu_access in [true, false] {
+            let mut cpu = Cpu::new(None, &vec![0; 0x8000], false);
+            cpu.bus.restrict_ppu_access = restrict_ppu_access;
+            cpu.bus.gpu.write_vram(0, 0x3C); // INC A
+            cpu.bus.gpu.write_oam(0, 0x3C);
+            cpu.bus.gpu.lcd_control = LCDControl::DisplayEnabled.into();
+            cpu.registers.a = 0;
+
+            for (pc, mode) in [(0x8000, Mode::Drawing), (0xFE00, Mode::OamScan)] {
+                cpu.pc = pc;
+                cpu.sp = 0xFFFE;
+                cpu.bus.gpu.mode = mode;
+                cpu.step_unwrap();
+                if restrict_ppu_access {
+                    // the PPU has the bus, so the fetch sees 0xFF, which is RST 38
+                    assert_eq!(cpu.pc, 0x0038, "{mode:?}");
+                    assert_eq!(cpu.sp, 0xFFFC);
+                } else {
+                    assert_eq!(cpu.pc, pc + 1, "{mode:?}");
+                }
+            }
+            let a = if restrict_ppu_access { 0 } else { 2 };
+            assert_eq!(cpu.registers.a, a);
+        }
+    }
+
+    #[test]
+    fn test_post_boot_registers() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x14D] = 0xE7;
+        let cpu = Cpu::new(None, &rom, false);
+        assert_eq!(
+            cpu.format_state(),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,00,00,00\n"
+        );
+
+        // the header bytes have to add up to 0xE7 for a checksum of 0
+        rom[0x134] = 0xE7;
+        rom[0x14D] = 0x00;
+        let cpu = Cpu::new(None, &rom, false);
+        assert_eq!(cpu.registers.f, BitFlags::from(Flags::Zero));
+
+        // the low nibble of the checksum is 0, so the last ADD doesn't half carry
+        rom[0x134] = 0xD7;
+        rom[0x14D] = 0x10;
+        let cpu = Cpu::new(None, &rom, false);
+        assert_eq!(cpu.registers.f, make_bitflags!(Flags::{Zero | Carry}));
+
+        // the boot animation ends up in the same place
+        let mut booted = Cpu::new_with_boot_sequence(&rom, false);
+        while booted.pc != 0x100 {
+            booted.step_unwrap();
+        }
+        assert_eq!(booted.format_state(), cpu.format_state());
+    }
+
+    #[test]
+    fn test_set_registers() {
+        let mut cpu = Cpu::power_on(MemoryBus::new(None, &[], false));
+        let registers = Registers {
+            a: 1,
+            b: 2,
+            c: 3,
+            d: 4,
+            e: 5,
+            h: 6,
+            l: 7,
+            f: make_bitflags!(Flags::{Zero | Carry}),
+        };
+        cpu.set_registers(registers.clone());
+        assert_eq!(cpu.registers(), &registers);
+        assert_eq!(
+            cpu.format_state(),
+            "A:01 F:90 B:02 C:03 D:04 E:05 H:06 L:07 SP:FFFE PC:0100 PCMEM:00,00,00,00\n"
+        );
+    }
+
+    #[test]
+    fn test_operands_wrap_around_memory() {
+        let mut rom = vec![0; 0x8000];
+        rom[0] = 0x56;
+        let mut cpu = Cpu::new(None, &rom, false);
+        // LD BC,d16 in the last byte of HRAM, its operand in IE then ROM
+        cpu.bus.write_byte(0xFFFE, 0x01);
+        cpu.bus.write_byte(0xFFFF, 0x12);
+        cpu.pc = 0xFFFE;
+        assert_eq!(cpu.bus.slice_from(cpu.pc), [0x01, 0x12, 0x56, 0x00]);
+        cpu.step_unwrap();
+        assert_eq!(cpu.registers.bc(), 0x5612);
+        assert_eq!(cpu.pc, 0x0001);
+
+        cpu.sp = 0xFFFF;
+        assert_eq!(cpu.pop(), 0x5612);
+        assert_eq!(cpu.sp, 0x0001);
+    }
+
+    #[test]
+    fn test_serial_interrupt() {
+        let rom = vec![0; 0x8000]; // all NOPs
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus.write_byte(0xFF02, 0x81);
+        // 8 bits at 512 cycles each
+        for _ in 0..1023 {
+            cpu.step_unwrap();
+        }
+        assert!(!cpu.bus.interrupt_flag.contains(InterruptFlag::Serial));
+        cpu.step_unwrap();
+        assert!(cpu.bus.interrupt_flag.contains(InterruptFlag::Serial));
+        assert_eq!(cpu.bus.read_byte(0xFF02) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_step_cycles() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let mut cpu = Cpu::new(None, rom, false);
+        for budget in [1, 4, 100, 1000, 70224] {
+            let before = cpu.total_cycles;
+            let cycles = cpu.step_cycles(budget).unwrap();
+            assert!(cycles >= budget);
+            // the longest instruction or interrupt dispatch is 24 cycles
+            assert!(cycles < budget + 24);
+            assert_eq!(cpu.total_cycles - before, u64::from(cycles));
+        }
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let rom = vec![0; 0x8000]; // all NOPs
+        let mut cpu = Cpu::new(None, &rom, false);
+        assert_eq!(cpu.run_frame(), Ok(CYCLES_PER_FRAME));
+
+        cpu.bus.write_byte(0xFF40, 0x91);
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.bus.gpu.line, 144);
+        assert_eq!(cpu.run_frame(), Ok(CYCLES_PER_FRAME));
+    }
+
+    #[test]
+    fn test_format_state_bank() {
+        let cpu = Cpu::new(None, &[], false);
+        assert!(!cpu.format_state().contains("Bank"));
+
+        let rom = vec![0; 4 * 0x4000];
+        let mut cpu = Cpu::new(None, &rom, false);
+        assert!(cpu.format_state().contains(" Bank:01 PC:0100 "));
+        cpu.bus.set_rom_bank(3);
+        assert!(cpu.format_state().contains(" Bank:03 PC:0100 "));
+    }
+
+    #[test]
+    fn test_counters() {
+        let rom = vec![0; 0x8000]; // all NOPs
+        let mut cpu = Cpu::new(None, &rom, false);
+        for _ in 0..10 {
+            cpu.step_unwrap();
+        }
+        assert_eq!(cpu.total_cycles, 40);
+        assert_eq!(cpu.total_instructions, 10);
+
+        cpu.reset_counters();
+        assert_eq!(cpu.total_cycles, 0);
+        assert_eq!(cpu.total_instructions, 0);
     }
 }
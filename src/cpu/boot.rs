@@ -0,0 +1,122 @@
+use crate::{cpu::memorybus::MemoryBus, header::LOGO};
+
+/// The ® tile, which lives in the boot ROM rather than the cartridge
+const REGISTERED_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+/// Number of steps which scroll the logo down by a line
+const SCROLL_STEPS: u8 = 0x64;
+/// Number of steps the logo is held still for after it's finished scrolling
+const PAUSE_STEPS: u8 = 0x20;
+
+/// Recreates the visible effects of the DMG boot ROM, without needing a copy of it. The logo is
+/// copied out of the cartridge header and scrolled down the screen, with the same frame timing
+/// and chimes as the real thing.
+#[derive(Debug, Default)]
+pub struct BootSequence {
+    started: bool,
+    /// How many iterations of the boot ROM's scroll loop have finished
+    step: u8,
+    frames_left: u8,
+    in_vblank: bool,
+}
+
+/// Stretches each bit of a nibble to two bits, since the logo is stored at half size
+const fn double_bits(nibble: u8) -> u8 {
+    let mut doubled = 0;
+    let mut bit = 4;
+    while bit > 0 {
+        bit -= 1;
+        doubled <<= 2;
+        if nibble & (1 << bit) != 0 {
+            doubled |= 0b11;
+        }
+    }
+    doubled
+}
+
+impl BootSequence {
+    /// Advances the sequence, returning true once it has finished and the cartridge should take
+    /// over
+    pub fn step(&mut self, bus: &mut MemoryBus) -> bool {
+        if !self.started {
+            Self::setup(bus);
+            self.started = true;
+            self.frames_left = 3;
+            return false;
+        }
+
+        let in_vblank = bus.gpu.line == 144;
+        let vblank_started = in_vblank && !self.in_vblank;
+        self.in_vblank = in_vblank;
+        if !vblank_started {
+            return false;
+        }
+        self.frames_left -= 1;
+        if self.frames_left > 0 {
+            return false;
+        }
+
+        self.step += 1;
+        let chime = match self.step {
+            0x62 => Some(0x83),
+            0x64 => Some(0xC1),
+            _ => None,
+        };
+        if let Some(period) = chime {
+            bus.write_byte(0xFF13, period);
+            bus.write_byte(0xFF14, 0x87);
+        }
+        if self.step <= SCROLL_STEPS {
+            bus.write_byte(0xFF42, bus.read_byte(0xFF42).wrapping_sub(1));
+        }
+        if self.step == SCROLL_STEPS + PAUSE_STEPS {
+            return true;
+        }
+
+        // the boot ROM busy waits on LY, which works out to alternating between 3 and 2 frames
+        self.frames_left = if self.step.is_multiple_of(2) { 3 } else { 2 };
+        false
+    }
+
+    fn setup(bus: &mut MemoryBus) {
+        for address in 0x8000..=0x9FFF {
+            bus.write_byte(address, 0);
+        }
+
+        // audio
+        bus.write_byte(0xFF26, 0x80);
+        bus.write_byte(0xFF11, 0x80);
+        bus.write_byte(0xFF12, 0xF3);
+        bus.write_byte(0xFF25, 0xF3);
+        bus.write_byte(0xFF24, 0x77);
+
+        bus.write_byte(0xFF47, 0xFC);
+
+        // each nibble of the logo is one row of a tile
+        let mut address = 0x8010;
+        for logo_address in LOGO {
+            let byte = bus.read_byte(u16::try_from(logo_address).unwrap());
+            for nibble in [byte >> 4, byte & 0xF] {
+                let row = double_bits(nibble);
+                bus.write_byte(address, row);
+                bus.write_byte(address + 2, row);
+                address += 4;
+            }
+        }
+        for row in REGISTERED_TILE {
+            bus.write_byte(address, row);
+            address += 2;
+        }
+
+        bus.write_byte(0x9910, 0x19);
+        for (tile, address) in (0x0D..=0x18).rev().zip((0x9924..=0x992F).rev()) {
+            bus.write_byte(address, tile);
+        }
+        for (tile, address) in (0x01..=0x0C).rev().zip((0x9904..=0x990F).rev()) {
+            bus.write_byte(address, tile);
+        }
+
+        bus.write_byte(0xFF42, SCROLL_STEPS);
+        bus.write_byte(0xFF40, 0x91);
+    }
+}
@@ -1,13 +1,25 @@
-use bitvec::array::BitArray;
-use enumflags2::{BitFlag, BitFlags, bitflags};
-use tracing::warn;
+use std::ops::RangeInclusive;
+
+use enumflags2::{BitFlags, bitflags};
+
+use hdma::Hdma;
+use io::{AddressUnavailable, BuiltIn, Device, IoDevice, IoDevices};
+use mbc::{CartridgeFeatures, Mbc};
 
 use crate::{
-    gpu::{Gpu, LCDControl, OAM_BEGIN, OAM_END, VRAM_BEGIN, VRAM_END},
-    joypad::Joypad,
+    apu::Apu,
+    diagnostics::warning,
+    gpu::{Gpu, OAM_BEGIN, OAM_END, OAM_SIZE, VRAM_BEGIN, VRAM_END},
+    joypad::{InputLatency, InputState, Joypad},
+    serial::Serial,
+    state::{Snapshot, StateError, StateReader, StateWriter},
     timer::Timer,
 };
 
+pub mod hdma;
+pub mod io;
+pub mod mbc;
+
 pub const BOOT_ROM_BEGIN: usize = 0x00;
 pub const BOOT_ROM_END: usize = 0xFF;
 pub const BOOT_ROM_SIZE: usize = BOOT_ROM_END - BOOT_ROM_BEGIN + 1;
@@ -34,28 +46,102 @@ pub const IO_BEGIN: usize = 0xFF00;
 pub const IO_END: usize = 0xFF7F;
 pub const IO_SIZE: usize = IO_END - IO_BEGIN + 1;
 
+/// 160 M-cycles
+const OAM_DMA_CYCLES: u16 = 640;
+
 pub const HRAM_BEGIN: usize = 0xFF80;
 pub const HRAM_END: usize = 0xFFFE;
 pub const HRAM_SIZE: usize = HRAM_END - HRAM_BEGIN + 1;
 
 #[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct MemoryBus {
     boot_rom: Option<Box<[u8; BOOT_ROM_SIZE]>>,
-    rom_bank_0: Box<[u8; ROM_BANK_0_SIZE]>,
-    rom_bank_n: Box<[u8; ROM_BANK_N_SIZE]>,
-    external_ram: Box<[u8; EXTERNAL_RAM_SIZE]>,
+    /// Cleared by writing to 0xFF50, the boot ROM is kept around for resets
+    boot_rom_mapped: bool,
+    /// The entire cartridge ROM, at least 2 banks long
+    rom: Vec<u8>,
+    /// Which bank is mapped into 0x0000-0x3FFF
+    low_rom_bank: usize,
+    /// Which bank is mapped into 0x4000-0x7FFF
+    rom_bank: usize,
+    pub mbc: Mbc,
+    pub cartridge: CartridgeFeatures,
+    /// As big as the cartridge header says, which can be nothing
+    external_ram: Box<[u8]>,
+    /// Which bank of external RAM is mapped into 0xA000-0xBFFF
+    ram_bank: usize,
+    /// Set when external RAM is written to, so it can be saved
+    pub external_ram_dirty: bool,
     wram: Box<[u8; WRAM_SIZE]>,
     pub gpu: Gpu,
     pub timer: Timer,
     pub joypad: Joypad,
+    pub serial: Serial,
+    pub apu: Apu,
     hram: Box<[u8; HRAM_SIZE]>,
+    /// Which device handles each IO register, built in or registered later
+    io_devices: IoDevices,
 
     /// Controls whether the interrupt handler is being requested
     pub interrupt_flag: BitFlags<InterruptFlag>,
     /// Controls whether the interrupt handler may be called
     pub interrupt_enabled: BitFlags<InterruptFlag>,
-    /// If set, stub out 0xFF44 to return 90 always
+    /// If set, LY always reads 0x90. The PPU advances LY properly, so games don't need this, but
+    /// Gameboy Doctor logs are taken with it stubbed out so it's needed to match them
     pub test_mode: bool,
+    /// If set, VRAM and OAM can't be accessed while the PPU is using them
+    pub restrict_ppu_access: bool,
+    /// If set, writing to STAT can fire a spurious interrupt like on a DMG
+    pub stat_write_bug: bool,
+    /// If set, 16 bit increments and decrements of pointers into OAM corrupt it like on a DMG
+    pub oam_bug: bool,
+    /// CGB only registers like KEY1 are ignored unless this is set
+    pub cgb_mode: bool,
+    /// KEY1 bit 0, the next STOP switches speed
+    speed_switch_armed: bool,
+    /// KEY1 bit 7, the CPU, timer and serial run twice as fast relative to the PPU
+    pub double_speed: bool,
+    pub hdma: Hdma,
+    /// Cycles the CPU has to wait for VRAM DMA to finish
+    pub dma_stall: u32,
+    /// DMA, the high byte of the address the last OAM DMA copied from
+    oam_dma_source: u8,
+    /// Cycles left until the current OAM DMA finishes
+    oam_dma_cycles: u16,
+    /// If set, the CPU can only reach HRAM and the IO registers while OAM DMA is running
+    pub oam_dma_conflicts: bool,
+    /// Listens for SGB command packets, if the cartridge says it uses SGB functions
+    #[cfg(feature = "sgb")]
+    pub sgb: Option<crate::sgb::CommandReceiver>,
+    /// Logs how long games take to notice button presses, if set
+    pub input_latency: Option<InputLatency>,
+}
+
+/// Bits of an IO register which aren't connected to anything and always read as 1. Registers
+/// which are entirely write-only, or don't exist at all, read as 0xFF.
+#[allow(clippy::match_same_arms)]
+const fn unused_io_bits(address: usize) -> u8 {
+    match address {
+        0xFF00 => 0b1100_0000,                              // P1
+        0xFF02 => 0b0111_1110,                              // SC
+        0xFF07 => 0b1111_1000,                              // TAC
+        0xFF0F => 0b1110_0000,                              // IF
+        0xFF10 => 0b1000_0000,                              // NR10
+        0xFF11 | 0xFF16 => 0b0011_1111, // NR11, NR21: only the duty can be read back
+        0xFF13 | 0xFF18 | 0xFF1B | 0xFF1D | 0xFF20 => 0xFF, // lengths and period lows
+        0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => 0b1011_1111, // only the length enable can be read
+        0xFF1A => 0b0111_1111,          // NR30
+        0xFF1C => 0b1001_1111,          // NR32
+        0xFF26 => 0b0111_0000,          // NR52
+        0xFF41 => 0b1000_0000,          // STAT
+        0xFF01 | 0xFF04..=0xFF06 | 0xFF12 | 0xFF17 | 0xFF21 | 0xFF22 | 0xFF24 | 0xFF25 => 0,
+        0xFF30..=0xFF3F => 0, // wave RAM
+        0xFF40..=0xFF4B => 0,
+        // CGB registers are handled by the bus itself
+        0xFF4D | 0xFF4F | 0xFF50 | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B | 0xFFFF => 0,
+        _ => 0xFF,
+    }
 }
 
 #[bitflags]
@@ -69,45 +155,140 @@ pub enum InterruptFlag {
     Joypad = 1 << 4,
 }
 
-fn copy_rom(buffer: &mut [u8; ROM_BANK_0_SIZE], slice: &[u8]) {
-    let n = std::cmp::min(buffer.len(), slice.len());
-    buffer[0..n].copy_from_slice(&slice[0..n]);
+/// Whether the cartridge header's CGB flag says the game can use colour. Only checked with the
+/// `cgb` feature, otherwise every game runs as on a DMG.
+fn supports_cgb(game_rom: &[u8]) -> bool {
+    cfg!(feature = "cgb") && game_rom.get(0x143).is_some_and(|flag| flag & 0x80 != 0)
 }
 
 impl MemoryBus {
     pub fn new(boot_rom: Option<&[u8; 256]>, game_rom: &[u8], test_mode: bool) -> Self {
         let boot_rom = boot_rom.map(|rom| Box::new(rom.to_owned()));
-        let mut rom_bank_0: Box<[u8; ROM_BANK_0_SIZE]> = vec![0; ROM_BANK_0_SIZE]
-            .into_boxed_slice()
-            .try_into()
-            .unwrap();
-        let mut rom_bank_n: Box<[u8; ROM_BANK_N_SIZE]> = vec![0; ROM_BANK_N_SIZE]
-            .into_boxed_slice()
-            .try_into()
-            .unwrap();
-        copy_rom(&mut rom_bank_0, game_rom);
-        if game_rom.len() > ROM_BANK_N_BEGIN {
-            copy_rom(&mut rom_bank_n, &game_rom[ROM_BANK_N_BEGIN..]);
+        let mbc = Mbc::from_header(game_rom);
+        let mut rom = game_rom.to_vec();
+        // pad out small ROMs so both the fixed and switchable bank are always mapped
+        if rom.len() < ROM_BANK_0_SIZE + ROM_BANK_N_SIZE {
+            rom.resize(ROM_BANK_0_SIZE + ROM_BANK_N_SIZE, 0);
         }
 
+        let cgb_mode = supports_cgb(game_rom);
         Self {
-            gpu: Gpu::default(),
+            gpu: Gpu::new(cgb_mode),
             timer: Timer::default(),
             joypad: Joypad::default(),
+            serial: Serial::default(),
+            apu: if boot_rom.is_some() {
+                Apu::default()
+            } else {
+                Apu::after_boot()
+            },
+            boot_rom_mapped: boot_rom.is_some(),
             boot_rom,
-            rom_bank_0,
-            rom_bank_n,
-            external_ram: vec![0; EXTERNAL_RAM_SIZE]
-                .into_boxed_slice()
-                .try_into()
-                .unwrap(),
+            rom,
+            low_rom_bank: 0,
+            rom_bank: 1,
+            mbc,
+            cartridge: CartridgeFeatures::from_header(game_rom),
+            external_ram: vec![0; mbc.ram_size(game_rom)].into_boxed_slice(),
+            ram_bank: 0,
+            external_ram_dirty: false,
             wram: vec![0; WRAM_SIZE].into_boxed_slice().try_into().unwrap(),
             hram: vec![0; HRAM_SIZE].into_boxed_slice().try_into().unwrap(),
+            io_devices: IoDevices::built_in(),
 
             interrupt_flag: BitFlags::EMPTY,
             interrupt_enabled: BitFlags::EMPTY,
             test_mode,
+            restrict_ppu_access: true,
+            stat_write_bug: true,
+            oam_bug: !cgb_mode,
+            cgb_mode,
+            speed_switch_armed: false,
+            double_speed: false,
+            hdma: Hdma::default(),
+            dma_stall: 0,
+            oam_dma_source: 0xFF,
+            oam_dma_cycles: 0,
+            oam_dma_conflicts: true,
+            #[cfg(feature = "sgb")]
+            sgb: crate::sgb::supported(game_rom).then(Default::default),
+            input_latency: None,
+        }
+    }
+
+    /// Called with the value of a register before a 16 bit INC or DEC, which trips the OAM bug
+    /// if it points into OAM
+    pub fn inc_dec_16(&mut self, value: u16) {
+        if self.oam_bug && (0xFE00..=0xFEFF).contains(&value) {
+            self.gpu.corrupt_oam_row();
+        }
+    }
+
+    /// A bus in its power on state, with the same cartridge and settings. Cartridge RAM survives
+    /// too, as it isn't cleared by the reset button
+    pub fn power_cycled(&self) -> Self {
+        let mut bus = self.with_cartridge(&self.rom);
+        bus.external_ram.clone_from(&self.external_ram);
+        bus.cgb_mode = self.cgb_mode;
+        bus.gpu.cgb_mode = self.gpu.cgb_mode;
+        bus
+    }
+
+    /// A bus in its power on state with the same settings, but `game_rom` inserted instead
+    pub fn with_cartridge(&self, game_rom: &[u8]) -> Self {
+        let mut bus = Self::new(self.boot_rom.as_deref(), game_rom, self.test_mode);
+        bus.restrict_ppu_access = self.restrict_ppu_access;
+        bus.stat_write_bug = self.stat_write_bug;
+        bus.oam_bug = self.oam_bug;
+        bus.oam_dma_conflicts = self.oam_dma_conflicts;
+        bus.gpu.per_pixel_rendering = self.gpu.per_pixel_rendering;
+        bus.gpu.sprite_limit = self.gpu.sprite_limit;
+        bus.gpu.palettes.shades = self.gpu.palettes.shades;
+        bus.joypad.block_opposing_directions = self.joypad.block_opposing_directions;
+        if self.input_latency.is_some() {
+            bus.input_latency = Some(InputLatency::default());
         }
+        bus
+    }
+
+    /// Passes the front end's input on to the joypad
+    pub fn set_input(&mut self, input: InputState) {
+        if let Some(latency) = &self.input_latency {
+            latency.input_changed(self.joypad.input, input, self.gpu.frames);
+        }
+        self.joypad.set_input(input);
+    }
+
+    /// Maps `device` over `addresses` in the IO register range, which can only be addresses
+    /// nothing else is mapped to
+    pub fn register_io_device(
+        &mut self,
+        addresses: RangeInclusive<u16>,
+        device: impl IoDevice + 'static,
+    ) -> Result<(), AddressUnavailable> {
+        if let Some(&address) = [addresses.start(), addresses.end()]
+            .into_iter()
+            .find(|&&address| !(IO_BEGIN..=IO_END).contains(&usize::from(address)))
+        {
+            return Err(AddressUnavailable { address });
+        }
+        let addresses = usize::from(*addresses.start())..=usize::from(*addresses.end());
+        self.io_devices.register(addresses, Box::new(device))
+    }
+
+    /// Moves devices registered on `other` over to this bus, so they survive a reset
+    pub fn take_io_devices(&mut self, other: &mut Self) {
+        self.io_devices.take_custom(&mut other.io_devices);
+    }
+
+    /// 0x0100-0x014F of the cartridge, which identifies it
+    pub fn cartridge_header(&self) -> &[u8] {
+        &self.rom[0x100..0x150]
+    }
+
+    /// Whether the boot ROM is still mapped over the start of the cartridge
+    pub const fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
     }
 
     pub fn read_byte(&self, address: u16) -> u8 {
@@ -116,125 +297,309 @@ impl MemoryBus {
 
         let address = address as usize;
         match address {
+            _ if self.blocked_by_oam_dma(address) => 0xFF,
             BOOT_ROM_BEGIN..=BOOT_ROM_END => self
                 .boot_rom
                 .as_ref()
-                .map_or_else(|| self.rom_bank_0[address], |boot_rom| boot_rom[address]),
-            ROM_BANK_0_BEGIN..=ROM_BANK_0_END => self.rom_bank_0[address],
-            ROM_BANK_N_BEGIN..=ROM_BANK_N_END => self.rom_bank_n[address - ROM_BANK_N_BEGIN],
+                .filter(|_| self.boot_rom_mapped)
+                .map_or_else(
+                    || self.rom[self.low_rom_bank_offset(address)],
+                    |boot_rom| boot_rom[address],
+                ),
+            ROM_BANK_0_BEGIN..=ROM_BANK_0_END => self.rom[self.low_rom_bank_offset(address)],
+            ROM_BANK_N_BEGIN..=ROM_BANK_N_END => self.rom[self.rom_bank_offset(address)],
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END if !self.external_ram_accessible() => 0xFF,
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END if matches!(self.mbc, Mbc::Mbc2(_)) => {
+                0xF0 | self.external_ram[self.external_ram_offset(address)]
+            }
             EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END => {
-                self.external_ram[address - EXTERNAL_RAM_BEGIN]
+                self.external_ram[self.external_ram_offset(address)]
             }
             WRAM_BEGIN..=WRAM_END => self.wram[address - WRAM_BEGIN],
             ECHO_RAM_BEGIN..=ECHO_RAM_END => self.wram[address - ECHO_RAM_BEGIN],
+            OAM_BEGIN..=OAM_END if self.restrict_ppu_access && !self.gpu.oam_accessible() => 0xFF,
             OAM_BEGIN..=OAM_END => self.gpu.read_oam(address - OAM_BEGIN),
+            VRAM_BEGIN..=VRAM_END if self.restrict_ppu_access && !self.gpu.vram_accessible() => {
+                0xFF
+            }
             VRAM_BEGIN..=VRAM_END => self.gpu.read_vram(address - VRAM_BEGIN),
             IO_BEGIN..=IO_END | 0xFFFF => self.read_io_register(address),
             HRAM_BEGIN..=HRAM_END => self.hram[address - HRAM_BEGIN],
-            _ => todo!("memory region not readable yet: {:#4x}", address),
+            _ => {
+                warning!("read from unusable memory: {address:04X}");
+                0xFF
+            }
         }
     }
     pub fn write_byte(&mut self, address: u16, value: u8) {
         let address = address as usize;
         match address {
-            ROM_BANK_0_BEGIN..=ROM_BANK_0_END => {
-                warn!("attempted to write to ROM");
-                self.rom_bank_0[address] = value;
-            }
-            ROM_BANK_N_BEGIN..=ROM_BANK_N_END => {
-                warn!("attempted to write to ROM");
-                self.rom_bank_n[address - ROM_BANK_N_BEGIN] = value;
+            _ if self.blocked_by_oam_dma(address) => {}
+            ROM_BANK_0_BEGIN..=ROM_BANK_N_END => match &mut self.mbc {
+                Mbc::None => warning!("attempted to write to ROM"),
+                Mbc::Mbc1(mbc) => {
+                    mbc.write(address, value);
+                    self.low_rom_bank = mbc.low_rom_bank();
+                    self.rom_bank = mbc.high_rom_bank();
+                    self.ram_bank = mbc.ram_bank();
+                }
+                Mbc::Mbc2(mbc) => {
+                    mbc.write(address, value);
+                    self.rom_bank = mbc.rom_bank();
+                }
+            },
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END if !self.external_ram_accessible() => {}
+            // only the low nibble of MBC2's RAM exists
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END if matches!(self.mbc, Mbc::Mbc2(_)) => {
+                self.external_ram[self.external_ram_offset(address)] = value & 0x0F;
+                self.external_ram_dirty = true;
             }
             EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END => {
-                self.external_ram[address - EXTERNAL_RAM_BEGIN] = value;
+                self.external_ram[self.external_ram_offset(address)] = value;
+                self.external_ram_dirty = true;
             }
             WRAM_BEGIN..=WRAM_END => self.wram[address - WRAM_BEGIN] = value,
             ECHO_RAM_BEGIN..=ECHO_RAM_END => self.wram[address - ECHO_RAM_BEGIN] = value,
+            OAM_BEGIN..=OAM_END if self.restrict_ppu_access && !self.gpu.oam_accessible() => {}
             OAM_BEGIN..=OAM_END => self.gpu.write_oam(address - OAM_BEGIN, value),
+            VRAM_BEGIN..=VRAM_END if self.restrict_ppu_access && !self.gpu.vram_accessible() => {}
             VRAM_BEGIN..=VRAM_END => self.gpu.write_vram(address - VRAM_BEGIN, value),
             IO_BEGIN..=IO_END | 0xFFFF => self.write_io_register(address, value),
             HRAM_BEGIN..=HRAM_END => self.hram[address - HRAM_BEGIN] = value,
-            _ => todo!("memory region not writable yet: {:#4x}", address),
+            _ => warning!("write to unusable memory: {address:04X}"),
+        }
+    }
+
+    /// Called on STOP, switching speed if KEY1 armed it. Returns if the speed changed
+    pub const fn try_speed_switch(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    /// Copies 160 bytes from `source` * 0x100 into OAM. The copy happens all at once, but the
+    /// CPU is still locked out of the bus for as long as the real transfer takes
+    fn start_oam_dma(&mut self, source: u8) {
+        self.oam_dma_source = source;
+        // the copy itself isn't held up by a transfer that's already running
+        self.oam_dma_cycles = 0;
+        for (offset, address) in (u16::from(source) << 8..).take(OAM_SIZE).enumerate() {
+            let value = self.read_byte(address);
+            self.gpu.write_oam(offset, value);
+        }
+        self.oam_dma_cycles = OAM_DMA_CYCLES;
+    }
+
+    /// Moves a running OAM DMA along by `cycles`
+    pub const fn step_oam_dma(&mut self, cycles: u8) {
+        self.oam_dma_cycles = self.oam_dma_cycles.saturating_sub(cycles as u16);
+    }
+
+    /// Whether a running OAM DMA keeps the CPU from reaching `address`
+    const fn blocked_by_oam_dma(&self, address: usize) -> bool {
+        self.oam_dma_conflicts && self.oam_dma_cycles > 0 && address < IO_BEGIN
+    }
+
+    /// Called when the PPU enters `HBlank`, copying a block if an `HBlank` transfer is running
+    pub fn hblank_dma(&mut self) {
+        if self.hdma.hblank_active() {
+            self.copy_dma_block();
+        }
+    }
+
+    /// Copies the next 16 bytes of a VRAM DMA, stalling the CPU while it happens
+    fn copy_dma_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+        for offset in 0..0x10 {
+            let value = self.read_byte(source.wrapping_add(offset));
+            self.gpu
+                .write_vram(usize::from(destination + offset), value);
+        }
+        // 8 M-cycles per block at either speed, which is twice as many CPU cycles in double speed
+        self.dma_stall += if self.double_speed { 64 } else { 32 };
+    }
+
+    pub fn external_ram(&self) -> &[u8] {
+        &self.external_ram
+    }
+
+    /// Fills external RAM from a save, ignoring anything past its end
+    pub fn load_external_ram(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(self.external_ram.len());
+        self.external_ram[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    /// The bank mapped into 0x4000-0x7FFF, or `None` if the ROM is too small to be banked
+    pub const fn rom_bank(&self) -> Option<usize> {
+        if self.rom.len() > ROM_BANK_0_SIZE + ROM_BANK_N_SIZE {
+            Some(self.rom_bank)
+        } else {
+            None
         }
     }
 
+    pub const fn set_rom_bank(&mut self, bank: usize) {
+        self.rom_bank = bank;
+    }
+
+    /// Offset into the ROM of an address in 0x0000-0x3FFF
+    const fn low_rom_bank_offset(&self, address: usize) -> usize {
+        (self.low_rom_bank * ROM_BANK_0_SIZE + address) % self.rom.len()
+    }
+
+    /// Offset into the ROM of an address in the switchable bank. Banks past the end of the ROM
+    /// wrap around, like the unconnected upper address lines on a real cartridge.
+    const fn rom_bank_offset(&self, address: usize) -> usize {
+        (self.rom_bank * ROM_BANK_N_SIZE + address - ROM_BANK_N_BEGIN) % self.rom.len()
+    }
+
+    /// Whether there's external RAM and the controller has it enabled
+    fn external_ram_accessible(&self) -> bool {
+        !self.external_ram.is_empty() && self.mbc.ram_enabled()
+    }
+
+    /// Offset into external RAM of an address in 0xA000-0xBFFF. RAM smaller than a bank, like
+    /// MBC2's, repeats through the whole range
+    const fn external_ram_offset(&self, address: usize) -> usize {
+        (self.ram_bank * EXTERNAL_RAM_SIZE + address - EXTERNAL_RAM_BEGIN) % self.external_ram.len()
+    }
+
     pub fn read_word(&self, address: u16) -> u16 {
-        let bytes = [self.read_byte(address), self.read_byte(address + 1)];
+        let bytes = [
+            self.read_byte(address),
+            self.read_byte(address.wrapping_add(1)),
+        ];
         u16::from_le_bytes(bytes)
     }
     pub fn write_word(&mut self, address: u16, value: u16) {
         let bytes = u16::to_le_bytes(value);
         self.write_byte(address, bytes[0]);
-        self.write_byte(address + 1, bytes[1]);
+        self.write_byte(address.wrapping_add(1), bytes[1]);
     }
 
     fn read_io_register(&self, address: usize) -> u8 {
-        match address {
-            0xFF00 => self.joypad.read_joypad(),
-            0xFF04 => self.timer.divider,
-            0xFF05 => self.timer.counter,
-            0xFF06 => self.timer.modulo,
-            0xFF07 => self.timer.control,
-            0xFF0F => self.interrupt_flag.bits(),
-            0xFF26 => 0,
-            0xFF40 => self.gpu.lcd_control.bits(),
-            0xFF42 => self.gpu.scroll_y,
-            0xFF43 => self.gpu.scroll_x,
-            0xFF44 => {
-                if self.test_mode {
-                    0x90
-                } else {
-                    self.gpu.line
-                }
+        match self.io_devices.get(address) {
+            Some(Device::Custom(device)) => device.read(address),
+            Some(&Device::BuiltIn(device)) => {
+                self.read_built_in(device, address) | unused_io_bits(address)
             }
-            0xFF4D => {
-                warn!("read from CGB only register: KEY1");
-                0
+            // nothing is mapped here, so reads float high
+            None => 0xFF,
+        }
+    }
+
+    fn read_built_in(&self, device: BuiltIn, address: usize) -> u8 {
+        match device {
+            BuiltIn::Joypad => {
+                let value = self.joypad.read(address);
+                if let Some(latency) = &self.input_latency {
+                    latency.read(value, self.gpu.frames);
+                }
+                value
             }
-            0xFFFF => self.interrupt_enabled.bits(),
-            _ => todo!("implement io register read {address:04X}"),
+            BuiltIn::Serial if address == 0xFF01 => self.serial.data,
+            BuiltIn::Serial => self.serial.read_control(),
+            BuiltIn::Timer => self.timer.read(address),
+            BuiltIn::Interrupts if address == 0xFFFF => self.interrupt_enabled.bits(),
+            BuiltIn::Interrupts => self.interrupt_flag.bits(),
+            BuiltIn::Apu => self.apu.read(address),
+            BuiltIn::Gpu if address == 0xFF44 && self.test_mode => 0x90,
+            BuiltIn::Gpu => self.gpu.read(address),
+            BuiltIn::OamDma => self.oam_dma_source,
+            // write only
+            BuiltIn::BootRom => 0xFF,
+            // probed by some DMG games, and don't exist on DMG
+            BuiltIn::Cgb if !self.cgb_mode => 0xFF,
+            BuiltIn::Cgb => match address {
+                0xFF4D => {
+                    u8::from(self.double_speed) << 7
+                        | 0b0111_1110
+                        | u8::from(self.speed_switch_armed)
+                }
+                0xFF4F => self.gpu.read_vram_bank(),
+                0xFF55 => self.hdma.read_control(),
+                0xFF68 => self.gpu.cgb_palettes.background.read_index(),
+                0xFF69 => self.gpu.cgb_palettes.background.read_data(),
+                0xFF6A => self.gpu.cgb_palettes.sprites.read_index(),
+                0xFF6B => self.gpu.cgb_palettes.sprites.read_data(),
+                // HDMA1-4 are write only
+                _ => 0xFF,
+            },
         }
     }
 
-    #[allow(clippy::match_same_arms)]
     fn write_io_register(&mut self, address: usize, value: u8) {
-        match address {
-            0xFF00 => self.joypad.write_joypad(value),
-            0xFF01 => { /* Serial transfer data */ }
-            0xFF02 => { /* Serial transfer control */ }
-            0xFF04 => self.timer.divider = 0,
-            0xFF05 => self.timer.counter = value,
-            0xFF06 => self.timer.modulo = value,
-            0xFF07 => self.timer.control = value,
-            0xFF0F => self.interrupt_flag = BitFlags::from_bits(value).unwrap(),
-            0xFF11 => { /* Sound Ch1 Length Timer and Duty Cycle */ }
-            0xFF12 => { /* Sound Ch1 Volume and Envelope */ }
-            0xFF13 => { /* Sound Ch1 Period Low */ }
-            0xFF14 => { /* Sound Ch1 Period High and Control */ }
-            0xFF24 => { /* Master Volume and VIN panning */ }
-            0xFF25 => { /* Sound Panning */ }
-            0xFF26 => { /* Sound Enabled */ }
-            0xFF40 => self.gpu.lcd_control = LCDControl::from_bits(value).unwrap(),
-            0xFF42 => self.gpu.scroll_y = value,
-            0xFF43 => self.gpu.scroll_x = value,
-            0xFF47 => self.gpu.background_colours = BitArray::new([value]),
-            0xFF4D => {
-                warn!("write to CGB only register: KEY1");
+        match self.io_devices.get_mut(address) {
+            Some(Device::Custom(device)) => device.write(address, value),
+            Some(&mut Device::BuiltIn(device)) => self.write_built_in(device, address, value),
+            None => warning!("write to unmapped io register {address:04X}"),
+        }
+    }
+
+    fn write_built_in(&mut self, device: BuiltIn, address: usize, value: u8) {
+        match device {
+            BuiltIn::Joypad => {
+                self.joypad.write(address, value);
+                #[cfg(feature = "sgb")]
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_p1(value);
+                }
+            }
+            BuiltIn::Serial if address == 0xFF01 => self.serial.data = value,
+            BuiltIn::Serial => self.serial.write_control(value),
+            BuiltIn::Timer => {
+                let overflowed = match address {
+                    0xFF04 => self.timer.reset_divider(),
+                    0xFF07 => self.timer.write_control(value),
+                    _ => {
+                        self.timer.write(address, value);
+                        false
+                    }
+                };
+                if overflowed {
+                    self.interrupt_flag.insert(InterruptFlag::Timer);
+                }
+            }
+            BuiltIn::Interrupts if address == 0xFFFF => {
+                self.interrupt_enabled = BitFlags::from_bits_truncate(value);
             }
-            0xFF50 => self.boot_rom = None,
-            0xFFFF => self.interrupt_enabled = BitFlags::from_bits(value).unwrap(),
-            _ => todo!("implement io register write {address:04X}"),
+            BuiltIn::Interrupts => self.interrupt_flag = BitFlags::from_bits_truncate(value),
+            BuiltIn::Apu => self.apu.write(address, value),
+            BuiltIn::Gpu => match address {
+                0xFF41 => self.interrupt_flag |= self.gpu.write_stat(value, self.stat_write_bug),
+                0xFF45 => self.interrupt_flag |= self.gpu.write_line_compare(value),
+                _ => self.gpu.write(address, value),
+            },
+            BuiltIn::OamDma => self.start_oam_dma(value),
+            BuiltIn::BootRom => self.boot_rom_mapped = false,
+            // KEY1 can't be armed on DMG, and the rest don't exist
+            BuiltIn::Cgb if !self.cgb_mode => {}
+            BuiltIn::Cgb => match address {
+                0xFF4D => self.speed_switch_armed = value & 1 == 1,
+                0xFF4F => self.gpu.write_vram_bank(value),
+                0xFF51 => self.hdma.write_source_high(value),
+                0xFF52 => self.hdma.write_source_low(value),
+                0xFF53 => self.hdma.write_destination_high(value),
+                0xFF54 => self.hdma.write_destination_low(value),
+                0xFF55 => {
+                    for _ in 0..self.hdma.write_control(value) {
+                        self.copy_dma_block();
+                    }
+                }
+                0xFF68 => self.gpu.cgb_palettes.background.write_index(value),
+                0xFF69 => self.gpu.cgb_palettes.background.write_data(value),
+                0xFF6A => self.gpu.cgb_palettes.sprites.write_index(value),
+                _ => self.gpu.cgb_palettes.sprites.write_data(value),
+            },
         }
     }
 
+    /// The 4 bytes from `pc`, wrapping around the end of the address space
     pub fn slice_from(&self, pc: u16) -> [u8; 4] {
-        // TODO: iterator?
-        [
-            self.read_byte(pc),
-            self.read_byte(pc + 1),
-            self.read_byte(pc + 2),
-            self.read_byte(pc + 3),
-        ]
+        [0, 1, 2, 3].map(|offset| self.read_byte(pc.wrapping_add(offset)))
     }
 
     pub fn is_interrupt_pending(&self) -> bool {
@@ -262,3 +627,492 @@ impl MemoryBus {
         }
     }
 }
+
+/// The cartridge and settings like `restrict_ppu_access` aren't part of the state
+impl Snapshot for MemoryBus {
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.boot_rom_mapped);
+        state.usize(self.low_rom_bank);
+        state.usize(self.rom_bank);
+        state.usize(self.ram_bank);
+        self.mbc.save(state);
+        state.bytes(&self.external_ram);
+        state.bytes(&*self.wram);
+        state.bytes(&*self.hram);
+        self.gpu.save(state);
+        self.timer.save(state);
+        state.u8(self.joypad.read_joypad());
+        self.serial.save(state);
+        self.apu.save(state);
+        state.u8(self.interrupt_flag.bits());
+        state.u8(self.interrupt_enabled.bits());
+        state.bool(self.speed_switch_armed);
+        state.bool(self.double_speed);
+        self.hdma.save(state);
+        state.u32(self.dma_stall);
+        state.u8(self.oam_dma_source);
+        state.u16(self.oam_dma_cycles);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.boot_rom_mapped = state.bool()? && self.boot_rom.is_some();
+        self.low_rom_bank = state.usize()?;
+        self.rom_bank = state.usize()?;
+        self.ram_bank = state.usize()?;
+        self.mbc.load(state)?;
+        state.bytes(&mut self.external_ram)?;
+        state.bytes(&mut *self.wram)?;
+        state.bytes(&mut *self.hram)?;
+        self.gpu.load(state)?;
+        self.timer.load(state)?;
+        self.joypad.write_joypad(state.u8()?);
+        self.serial.load(state)?;
+        self.apu.load(state)?;
+        self.interrupt_flag = BitFlags::from_bits_truncate(state.u8()?);
+        self.interrupt_enabled = BitFlags::from_bits_truncate(state.u8()?);
+        self.speed_switch_armed = state.bool()?;
+        self.double_speed = state.bool()?;
+        self.hdma.load(state)?;
+        self.dma_stall = state.u32()?;
+        self.oam_dma_source = state.u8()?;
+        self.oam_dma_cycles = state.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use enumflags2::make_bitflags;
+
+    use super::*;
+    use crate::{
+        gpu::{LCDControl, Mode},
+        joypad::Button,
+    };
+
+    #[test]
+    fn test_interrupt_priority() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        bus.interrupt_flag = BitFlags::all();
+        bus.interrupt_enabled = BitFlags::all();
+
+        // VBlank > LcdStat > Timer > Serial > Joypad
+        for (flag, address) in [
+            (InterruptFlag::VBlank, 0x40),
+            (InterruptFlag::LcdStat, 0x48),
+            (InterruptFlag::Timer, 0x50),
+            (InterruptFlag::Serial, 0x58),
+            (InterruptFlag::Joypad, 0x60),
+        ] {
+            assert_eq!(bus.get_first_interrupt(), flag);
+            assert_eq!(bus.pop_interrupt_handler_address(), address);
+            assert!(!bus.interrupt_flag.contains(flag));
+        }
+        assert!(!bus.is_interrupt_pending());
+        assert_eq!(bus.try_get_first_interrupt(), None);
+    }
+
+    #[test]
+    fn test_interrupt_masking() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        bus.interrupt_flag = make_bitflags!(InterruptFlag::{VBlank | Timer | Joypad});
+        bus.interrupt_enabled = make_bitflags!(InterruptFlag::{Timer | Serial | Joypad});
+
+        // VBlank is requested but masked off, so Timer wins
+        assert!(bus.is_interrupt_pending());
+        assert_eq!(bus.pop_interrupt_handler_address(), 0x50);
+        assert_eq!(
+            bus.interrupt_flag,
+            make_bitflags!(InterruptFlag::{VBlank | Joypad})
+        );
+
+        assert_eq!(bus.pop_interrupt_handler_address(), 0x60);
+        assert_eq!(bus.interrupt_flag, InterruptFlag::VBlank);
+
+        // only a masked interrupt remains requested
+        assert!(!bus.is_interrupt_pending());
+        assert_eq!(bus.try_get_first_interrupt(), None);
+    }
+
+    #[test]
+    fn test_io_read_masks() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        bus.write_byte(0xFF07, 0b101);
+        assert_eq!(bus.read_byte(0xFF07), 0b1111_1101);
+        bus.write_byte(0xFF0F, 0);
+        assert_eq!(bus.read_byte(0xFF0F), 0b1110_0000);
+        // write-only and unmapped registers
+        assert_eq!(bus.read_byte(0xFF13), 0xFF);
+        assert_eq!(bus.read_byte(0xFF03), 0xFF);
+    }
+
+    #[test]
+    fn test_cgb_registers() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        // DMG doesn't have palette RAM
+        bus.write_byte(0xFF68, 0x80);
+        assert_eq!(bus.read_byte(0xFF68), 0xFF);
+
+        bus.cgb_mode = true;
+        bus.gpu.cgb_mode = true;
+        bus.write_byte(0xFF4F, 1);
+        bus.write_byte(0x8000, 0x42);
+        bus.write_byte(0xFF4F, 0);
+        assert_eq!(bus.read_byte(0x8000), 0);
+        assert_eq!(bus.read_byte(0xFF4F), 0xFE);
+        bus.write_byte(0xFF4F, 1);
+        assert_eq!(bus.read_byte(0x8000), 0x42);
+
+        bus.write_byte(0xFF68, 0x80 | 0x10);
+        bus.write_byte(0xFF69, 0x1F);
+        bus.write_byte(0xFF69, 0x00);
+        assert_eq!(bus.read_byte(0xFF68), 0xC0 | 0x12);
+        bus.write_byte(0xFF68, 0x10);
+        assert_eq!(bus.read_byte(0xFF69), 0x1F);
+        assert_eq!(bus.gpu.cgb_palettes.background.data[0x10], 0x1F);
+    }
+
+    #[test]
+    fn test_general_purpose_dma() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        bus.cgb_mode = true;
+        for offset in 0..0x20 {
+            bus.write_byte(0xC000 + u16::from(offset), offset + 1);
+        }
+        bus.write_byte(0xFF51, 0xC0);
+        bus.write_byte(0xFF52, 0x00);
+        bus.write_byte(0xFF53, 0x80);
+        bus.write_byte(0xFF54, 0x10);
+        // 2 blocks
+        bus.write_byte(0xFF55, 0x01);
+
+        assert_eq!(bus.read_byte(0x8000), 0);
+        for offset in 0..0x20 {
+            assert_eq!(bus.read_byte(0x8010 + u16::from(offset)), offset + 1);
+        }
+        assert_eq!(bus.read_byte(0x8030), 0);
+        assert_eq!(bus.read_byte(0xFF55), 0xFF);
+        assert_eq!(bus.dma_stall, 64);
+    }
+
+    #[test]
+    fn test_interrupt_flag_upper_bits() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        bus.write_byte(0xFF0F, 0xFF);
+        assert_eq!(bus.interrupt_flag, BitFlags::all());
+        assert_eq!(bus.read_byte(0xFF0F), 0xFF);
+        bus.write_byte(0xFF0F, 0x0F);
+        assert_eq!(bus.read_byte(0xFF0F), 0xEF);
+    }
+
+    #[test]
+    fn test_apu_readback() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        assert_eq!(bus.read_byte(0xFF26), 0xF0);
+        // only NR11's duty can be read back
+        bus.write_byte(0xFF11, 0b1001_0101);
+        assert_eq!(bus.read_byte(0xFF11), 0b1011_1111);
+        bus.write_byte(0xFF12, 0x42);
+        assert_eq!(bus.read_byte(0xFF12), 0x42);
+
+        bus.write_byte(0xFF26, 0);
+        assert_eq!(bus.read_byte(0xFF26), 0x70);
+        assert_eq!(bus.read_byte(0xFF12), 0);
+        // registers can't be written while powered off
+        bus.write_byte(0xFF12, 0x42);
+        assert_eq!(bus.read_byte(0xFF12), 0);
+    }
+
+    #[test]
+    fn test_lcd_control_round_trips() {
+        // every bit of LCDC does something, so none are masked off
+        assert_eq!(BitFlags::<LCDControl>::all().bits(), 0xFF);
+        let mut bus = MemoryBus::new(None, &[], false);
+        for value in 0..=0xFF {
+            bus.write_byte(0xFF40, value);
+            assert_eq!(bus.read_byte(0xFF40), value);
+        }
+    }
+
+    #[test]
+    fn test_stat_write_bug() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        bus.write_byte(0xFF40, 0x91);
+        bus.write_byte(0xFF45, 1); // keep LY=LYC out of the way
+        bus.gpu.mode = Mode::HBlank;
+        bus.write_byte(0xFF41, 0);
+        assert!(bus.interrupt_flag.contains(InterruptFlag::LcdStat));
+
+        // the bug can't fire during OAM scan
+        bus.interrupt_flag = BitFlags::EMPTY;
+        bus.gpu.mode = Mode::OamScan;
+        bus.write_byte(0xFF41, 0);
+        assert_eq!(bus.interrupt_flag, BitFlags::EMPTY);
+
+        bus.stat_write_bug = false;
+        bus.gpu.mode = Mode::HBlank;
+        bus.write_byte(0xFF41, 0);
+        assert_eq!(bus.interrupt_flag, BitFlags::EMPTY);
+        assert_eq!(bus.read_byte(0xFF41), 0b1000_0000);
+    }
+
+    #[test]
+    fn test_rom_banks() {
+        // every byte is the number of the bank it's in
+        let rom: Vec<u8> = (0..4).flat_map(|bank| [bank; ROM_BANK_N_SIZE]).collect();
+        let bus = MemoryBus::new(None, &rom, false);
+        assert_eq!(bus.read_byte(0x0000), 0);
+        assert_eq!(bus.read_byte(0x3FFF), 0);
+        assert_eq!(bus.read_byte(0x4000), 1);
+        assert_eq!(bus.read_byte(0x7FFF), 1);
+    }
+
+    #[test]
+    fn test_mbc1_advanced_banking() {
+        // a 2MB ROM where every byte is the number of the bank it's in
+        let mut rom: Vec<u8> = (0..128).flat_map(|bank| [bank; ROM_BANK_N_SIZE]).collect();
+        rom[0x147] = 0x01; // MBC1
+        let mut bus = MemoryBus::new(None, &rom, false);
+        assert_eq!(bus.mbc, Mbc::Mbc1(mbc::Mbc1::default()));
+
+        bus.write_byte(0x4000, 1); // upper bits
+        assert_eq!(bus.read_byte(0x0000), 0);
+        // BANK1 is 0, which reads as 1
+        assert_eq!(bus.read_byte(0x4000), 0x21);
+
+        bus.write_byte(0x6000, 1); // advanced banking
+        assert_eq!(bus.read_byte(0x0000), 0x20);
+        assert_eq!(bus.read_byte(0x3FFF), 0x20);
+        bus.write_byte(0x4000, 2);
+        assert_eq!(bus.read_byte(0x0000), 0x40);
+
+        bus.write_byte(0x2000, 5);
+        assert_eq!(bus.read_byte(0x4000), 0x45);
+        bus.write_byte(0x6000, 0);
+        assert_eq!(bus.read_byte(0x0000), 0);
+        assert_eq!(bus.read_byte(0x4000), 0x45);
+    }
+
+    #[test]
+    fn test_cartridge_type() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        let bus = MemoryBus::new(None, &rom, false);
+        assert_eq!(bus.mbc, Mbc::Mbc1(mbc::Mbc1::default()));
+        assert_eq!(
+            bus.cartridge,
+            CartridgeFeatures {
+                ram: true,
+                battery: true,
+                timer: false
+            }
+        );
+
+        rom[0x147] = 0x08; // ROM+RAM
+        let bus = MemoryBus::new(None, &rom, false);
+        assert_eq!(bus.mbc, Mbc::None);
+        assert!(bus.cartridge.ram && !bus.cartridge.battery);
+    }
+
+    #[test]
+    fn test_external_ram_size() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x149] = 0x03; // 32KB
+        let mut bus = MemoryBus::new(None, &rom, false);
+        assert_eq!(bus.external_ram().len(), 4 * EXTERNAL_RAM_SIZE);
+
+        bus.write_byte(0x0000, 0x0A);
+        bus.write_byte(0x6000, 1); // advanced banking, so BANK2 picks the RAM bank
+        bus.write_byte(0x4000, 2);
+        bus.write_byte(0xA000, 0x42);
+        assert_eq!(bus.external_ram()[2 * EXTERNAL_RAM_SIZE], 0x42);
+        bus.write_byte(0x4000, 0);
+        assert_eq!(bus.read_byte(0xA000), 0);
+        bus.write_byte(0x4000, 2);
+        assert_eq!(bus.read_byte(0xA000), 0x42);
+
+        rom[0x149] = 0x00;
+        let mut bus = MemoryBus::new(None, &rom, false);
+        assert!(bus.external_ram().is_empty());
+        bus.write_byte(0x0000, 0x0A);
+        bus.write_byte(0xA000, 0x42);
+        assert_eq!(bus.read_byte(0xA000), 0xFF);
+        assert!(!bus.external_ram_dirty);
+    }
+
+    #[test]
+    fn test_external_ram_enable() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x149] = 0x02; // 8KB
+        let mut bus = MemoryBus::new(None, &rom, false);
+
+        // disabled at power on
+        bus.write_byte(0xA000, 0x42);
+        assert_eq!(bus.read_byte(0xA000), 0xFF);
+        assert!(!bus.external_ram_dirty);
+        assert_eq!(bus.external_ram()[0], 0);
+
+        bus.write_byte(0x1FFF, 0x3A); // only the low nibble counts
+        bus.write_byte(0xA000, 0x42);
+        assert_eq!(bus.read_byte(0xA000), 0x42);
+        assert!(bus.external_ram_dirty);
+
+        bus.external_ram_dirty = false;
+        bus.write_byte(0x0000, 0x00);
+        assert_eq!(bus.read_byte(0xA000), 0xFF);
+        bus.write_byte(0xA000, 0x24);
+        assert!(!bus.external_ram_dirty);
+        assert_eq!(bus.external_ram()[0], 0x42);
+    }
+
+    #[test]
+    fn test_mbc2() {
+        // a 256KB ROM where every byte is the number of the bank it's in
+        let mut rom: Vec<u8> = (0..16).flat_map(|bank| [bank; ROM_BANK_N_SIZE]).collect();
+        rom[0x147] = 0x06; // MBC2 with a battery
+        let mut bus = MemoryBus::new(None, &rom, false);
+
+        // address bit 8 clear is RAM enable, set is the ROM bank
+        bus.write_byte(0x2000, 0x0A);
+        assert_eq!(bus.read_byte(0x4000), 1);
+        assert!(matches!(bus.mbc, Mbc::Mbc2(mbc) if mbc.ram_enabled));
+        bus.write_byte(0x2100, 0x05);
+        assert_eq!(bus.read_byte(0x4000), 5);
+        bus.write_byte(0x0100, 0x1F);
+        assert_eq!(bus.read_byte(0x4000), 0x0F);
+        bus.write_byte(0x3EFF, 0x00);
+        assert!(matches!(bus.mbc, Mbc::Mbc2(mbc) if !mbc.ram_enabled));
+        assert_eq!(bus.read_byte(0x4000), 0x0F);
+
+        // disabled RAM reads 0xFF and drops writes
+        bus.write_byte(0xA001, 0xAB);
+        assert_eq!(bus.read_byte(0xA001), 0xFF);
+        assert_eq!(bus.external_ram()[1], 0);
+        assert!(!bus.external_ram_dirty);
+
+        // only the low nibble is stored, and the 512 bytes repeat
+        bus.write_byte(0x0000, 0x0A);
+        bus.write_byte(0xA001, 0xAB);
+        assert_eq!(bus.read_byte(0xA001), 0xFB);
+        assert_eq!(bus.read_byte(0xA201), 0xFB);
+        assert_eq!(bus.read_byte(0xBE01), 0xFB);
+        assert_eq!(bus.external_ram()[1], 0x0B);
+    }
+
+    #[test]
+    fn test_oam_restricted_during_oam_scan() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        bus.gpu.lcd_control = LCDControl::DisplayEnabled.into();
+        bus.gpu.mode = Mode::HBlank;
+        bus.write_byte(0xFE00, 0x42);
+        assert_eq!(bus.read_byte(0xFE00), 0x42);
+
+        bus.gpu.mode = Mode::OamScan;
+        assert_eq!(bus.read_byte(0xFE00), 0xFF);
+
+        bus.restrict_ppu_access = false;
+        assert_eq!(bus.read_byte(0xFE00), 0x42);
+    }
+
+    #[test]
+    fn test_oam_dma_locks_out_cpu() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        for address in 0xC000..0xC0A0 {
+            bus.write_byte(address, 0x42);
+        }
+        bus.write_byte(0xFF80, 0x12);
+        bus.write_byte(0xFF46, 0xC0);
+        assert_eq!(bus.gpu.read_oam(OAM_SIZE - 1), 0x42);
+        assert_eq!(bus.read_byte(0xFF46), 0xC0);
+
+        assert_eq!(bus.read_byte(0xC000), 0xFF);
+        bus.write_byte(0xC000, 0x00);
+        assert_eq!(bus.read_byte(0xFF80), 0x12);
+        for _ in 0..OAM_DMA_CYCLES / 4 {
+            bus.step_oam_dma(4);
+        }
+        assert_eq!(bus.read_byte(0xC000), 0x42);
+
+        bus.oam_dma_conflicts = false;
+        bus.write_byte(0xFF46, 0xC0);
+        assert_eq!(bus.read_byte(0xC000), 0x42);
+    }
+
+    #[test]
+    fn test_custom_io_device() {
+        #[derive(Debug, Default)]
+        struct Latch(u8);
+        impl IoDevice for Latch {
+            fn read(&self, _address: usize) -> u8 {
+                self.0
+            }
+            fn write(&mut self, _address: usize, value: u8) {
+                self.0 = value;
+            }
+        }
+
+        let mut bus = MemoryBus::new(None, &[], false);
+        assert_eq!(bus.read_byte(0xFF7F), 0xFF);
+        bus.register_io_device(0xFF7F..=0xFF7F, Latch::default())
+            .unwrap();
+        bus.write_byte(0xFF7F, 0x42);
+        assert_eq!(bus.read_byte(0xFF7F), 0x42);
+        // the built in devices are still there, and can't be shadowed
+        bus.write_byte(0xFF42, 0x12);
+        assert_eq!(bus.read_byte(0xFF42), 0x12);
+        assert_eq!(
+            bus.register_io_device(0xFF3F..=0xFF42, Latch::default()),
+            Err(AddressUnavailable { address: 0xFF3F })
+        );
+        assert_eq!(
+            bus.register_io_device(0xFF7E..=0xFF7F, Latch::default()),
+            Err(AddressUnavailable { address: 0xFF7F })
+        );
+        assert_eq!(
+            bus.register_io_device(0xC000..=0xC000, Latch::default()),
+            Err(AddressUnavailable { address: 0xC000 })
+        );
+        assert_eq!(bus.read_byte(0xFF42), 0x12);
+
+        let mut reset = bus.power_cycled();
+        reset.take_io_devices(&mut bus);
+        assert_eq!(reset.read_byte(0xFF7F), 0x42);
+    }
+
+    #[test]
+    fn test_input_latency() {
+        let mut bus = MemoryBus::new(None, &[], false);
+        bus.input_latency = Some(InputLatency::default());
+        bus.gpu.frames = 10;
+        let mut input = InputState::default();
+        input.set_button(Button::A, true);
+        bus.set_input(input);
+
+        bus.gpu.frames = 11;
+        // A doesn't show up with only the dpad selected
+        bus.write_byte(0xFF00, 0x20);
+        bus.read_byte(0xFF00);
+        assert_eq!(bus.input_latency.as_ref().unwrap().last.get(), None);
+        bus.write_byte(0xFF00, 0x10);
+        bus.read_byte(0xFF00);
+        assert_eq!(bus.input_latency.as_ref().unwrap().last.get(), Some(1));
+
+        // holding it doesn't count again
+        bus.gpu.frames = 20;
+        bus.set_input(input);
+        bus.read_byte(0xFF00);
+        assert_eq!(bus.input_latency.as_ref().unwrap().last.get(), Some(1));
+
+        // a press released before the game reads it doesn't start the next measurement
+        bus.set_input(InputState::default());
+        bus.set_input(input);
+        bus.set_input(InputState::default());
+        bus.gpu.frames = 30;
+        bus.set_input(input);
+        bus.gpu.frames = 32;
+        bus.read_byte(0xFF00);
+        assert_eq!(bus.input_latency.as_ref().unwrap().last.get(), Some(2));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// HDMA1-HDMA5, which copy 16 byte blocks from ROM or RAM into VRAM. General purpose transfers
+/// copy everything at once, `HBlank` transfers copy a block at the start of every `HBlank`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hdma {
+    /// HDMA1 and HDMA2, the low 4 bits are ignored
+    source: u16,
+    /// HDMA3 and HDMA4 as an offset into VRAM, the low 4 bits are ignored
+    destination: u16,
+    /// HDMA5 bits 0-6, how many blocks are left minus one
+    remaining: u8,
+    /// An `HBlank` transfer is in progress
+    hblank_active: bool,
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Self {
+            source: 0,
+            destination: 0,
+            remaining: 0x7F,
+            hblank_active: false,
+        }
+    }
+}
+
+impl Hdma {
+    pub const fn write_source_high(&mut self, value: u8) {
+        self.source = (value as u16) << 8 | self.source & 0x00F0;
+    }
+
+    pub const fn write_source_low(&mut self, value: u8) {
+        self.source = self.source & 0xFF00 | (value & 0xF0) as u16;
+    }
+
+    pub const fn write_destination_high(&mut self, value: u8) {
+        self.destination = ((value & 0x1F) as u16) << 8 | self.destination & 0x00F0;
+    }
+
+    pub const fn write_destination_low(&mut self, value: u8) {
+        self.destination = self.destination & 0x1F00 | (value & 0xF0) as u16;
+    }
+
+    /// HDMA5 reads 0xFF once a transfer is done
+    pub fn read_control(self) -> u8 {
+        u8::from(!self.hblank_active) << 7 | self.remaining
+    }
+
+    /// Starts a transfer, returning how many blocks a general purpose transfer copies right away.
+    /// Starting a general purpose transfer during an `HBlank` transfer cancels it instead.
+    pub const fn write_control(&mut self, value: u8) -> u8 {
+        if self.hblank_active && value & 0x80 == 0 {
+            self.hblank_active = false;
+            return 0;
+        }
+        self.remaining = value & 0x7F;
+        if value & 0x80 == 0 {
+            self.remaining + 1
+        } else {
+            self.hblank_active = true;
+            0
+        }
+    }
+
+    pub const fn hblank_active(self) -> bool {
+        self.hblank_active
+    }
+
+    /// Advances past the next block, returning its source address and VRAM offset
+    pub const fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(0x10);
+        self.destination = (self.destination + 0x10) & 0x1FF0;
+        if self.remaining == 0 {
+            self.remaining = 0x7F;
+            self.hblank_active = false;
+        } else {
+            self.remaining -= 1;
+        }
+        block
+    }
+}
+
+impl Snapshot for Hdma {
+    fn save(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.remaining);
+        state.bool(self.hblank_active);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.u16()?;
+        self.destination = state.u16()?;
+        self.remaining = state.u8()?;
+        self.hblank_active = state.bool()?;
+        Ok(())
+    }
+}
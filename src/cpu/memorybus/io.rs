@@ -0,0 +1,121 @@
+//! Peripherals which sit in the IO register range
+
+use std::{fmt, ops::RangeInclusive};
+
+use parse_display::Display;
+
+use crate::apu::{APU_BEGIN, APU_END, WAVE_RAM_BEGIN, WAVE_RAM_END};
+
+/// Something with registers in 0xFF00-0xFF7F. Addresses are absolute, not offsets into the
+/// device's range
+pub trait IoDevice: fmt::Debug + Send {
+    fn read(&self, address: usize) -> u8;
+    fn write(&mut self, address: usize, value: u8);
+}
+
+/// Why a device couldn't be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[display("{address:04X} is already mapped or isn't an IO register")]
+pub struct AddressUnavailable {
+    pub address: u16,
+}
+
+impl std::error::Error for AddressUnavailable {}
+
+/// The devices built into the bus. They stay fields of the bus so the rest of the emulator can
+/// reach them, so the table only records which registers they own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltIn {
+    Joypad,
+    Serial,
+    Timer,
+    /// IF and IE
+    Interrupts,
+    Apu,
+    Gpu,
+    OamDma,
+    /// BANK, which unmaps the boot ROM
+    BootRom,
+    /// KEY1, VBK, HDMA and the colour palettes, which read 0xFF and ignore writes on DMG
+    Cgb,
+}
+
+#[derive(Debug)]
+pub enum Device {
+    BuiltIn(BuiltIn),
+    Custom(Box<dyn IoDevice>),
+}
+
+/// Every device in the IO register range and the addresses it covers, which never overlap
+#[derive(Debug, Default)]
+pub struct IoDevices {
+    devices: Vec<(RangeInclusive<usize>, Device)>,
+}
+
+impl IoDevices {
+    /// The map every bus starts with
+    pub fn built_in() -> Self {
+        let mut devices = Self::default();
+        for (addresses, device) in [
+            (0xFF00..=0xFF00, BuiltIn::Joypad),
+            (0xFF01..=0xFF02, BuiltIn::Serial),
+            (0xFF04..=0xFF07, BuiltIn::Timer),
+            (0xFF0F..=0xFF0F, BuiltIn::Interrupts),
+            (APU_BEGIN..=APU_END, BuiltIn::Apu),
+            (WAVE_RAM_BEGIN..=WAVE_RAM_END, BuiltIn::Apu),
+            (0xFF40..=0xFF45, BuiltIn::Gpu),
+            (0xFF46..=0xFF46, BuiltIn::OamDma),
+            (0xFF47..=0xFF4B, BuiltIn::Gpu),
+            (0xFF4D..=0xFF4D, BuiltIn::Cgb),
+            (0xFF4F..=0xFF4F, BuiltIn::Cgb),
+            (0xFF50..=0xFF50, BuiltIn::BootRom),
+            (0xFF51..=0xFF55, BuiltIn::Cgb),
+            (0xFF68..=0xFF6B, BuiltIn::Cgb),
+            (0xFFFF..=0xFFFF, BuiltIn::Interrupts),
+        ] {
+            devices.devices.push((addresses, Device::BuiltIn(device)));
+        }
+        devices
+    }
+
+    /// Maps `device` over `addresses`, as long as nothing else is mapped to any of them
+    pub fn register(
+        &mut self,
+        addresses: RangeInclusive<usize>,
+        device: Box<dyn IoDevice>,
+    ) -> Result<(), AddressUnavailable> {
+        if let Some(address) = addresses
+            .clone()
+            .find(|&address| self.get(address).is_some())
+        {
+            return Err(AddressUnavailable {
+                address: u16::try_from(address).unwrap(),
+            });
+        }
+        self.devices.push((addresses, Device::Custom(device)));
+        Ok(())
+    }
+
+    /// Moves the devices registered on top of the built in ones over from `other`
+    pub fn take_custom(&mut self, other: &mut Self) {
+        let (custom, built_in) = std::mem::take(&mut other.devices)
+            .into_iter()
+            .partition(|(_, device)| matches!(device, Device::Custom(_)));
+        other.devices = built_in;
+        self.devices.extend::<Vec<_>>(custom);
+    }
+
+    pub fn get(&self, address: usize) -> Option<&Device> {
+        self.devices
+            .iter()
+            .find(|(addresses, _)| addresses.contains(&address))
+            .map(|(_, device)| device)
+    }
+
+    pub fn get_mut(&mut self, address: usize) -> Option<&mut Device> {
+        self.devices
+            .iter_mut()
+            .find(|(addresses, _)| addresses.contains(&address))
+            .map(|(_, device)| device)
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    diagnostics::warning,
+    state::{Snapshot, StateError, StateReader, StateWriter},
+};
+
+/// Where the cartridge type lives in the header
+const CARTRIDGE_TYPE: usize = 0x147;
+/// Where the size of external RAM lives in the header
+const RAM_SIZE: usize = 0x149;
+
+/// The memory bank controller on the cartridge, which decides which ROM banks are mapped in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mbc {
+    /// No banking, ROM writes do nothing
+    None,
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+}
+
+impl Mbc {
+    /// Picks the controller named in the cartridge header
+    pub fn from_header(rom: &[u8]) -> Self {
+        match rom.get(CARTRIDGE_TYPE).copied().unwrap_or_default() {
+            0x00 | 0x08 | 0x09 => Self::None,
+            0x01..=0x03 => Self::Mbc1(Mbc1::default()),
+            0x05 | 0x06 => Self::Mbc2(Mbc2::default()),
+            other => {
+                warning!("unsupported cartridge type {other:02X}, treating it as ROM only");
+                Self::None
+            }
+        }
+    }
+
+    /// Bytes of external RAM the cartridge has, going by the header
+    pub fn ram_size(self, rom: &[u8]) -> usize {
+        if let Self::Mbc2(_) = self {
+            return Mbc2::RAM_SIZE;
+        }
+        match rom.get(RAM_SIZE).copied().unwrap_or_default() {
+            0x00 => 0,
+            // not in any licensed cartridge, but listed in some docs as 2KB
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x2_0000,
+            0x05 => 0x1_0000,
+            other => {
+                warning!("unknown RAM size {other:02X}, leaving RAM out");
+                0
+            }
+        }
+    }
+
+    /// Whether external RAM can be read and written. Without a controller there's nothing to
+    /// disable it
+    pub const fn ram_enabled(self) -> bool {
+        match self {
+            Self::None => true,
+            Self::Mbc1(mbc) => mbc.ram_enabled,
+            Self::Mbc2(mbc) => mbc.ram_enabled,
+        }
+    }
+}
+
+/// What else the cartridge type says is on the cartridge, besides ROM and the controller
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CartridgeFeatures {
+    /// External RAM, or MBC2's built in RAM
+    pub ram: bool,
+    /// A battery keeping RAM, and the clock if there is one, alive when switched off
+    pub battery: bool,
+    /// MBC3's real time clock
+    pub timer: bool,
+}
+
+impl CartridgeFeatures {
+    pub fn from_header(rom: &[u8]) -> Self {
+        let (ram, battery, timer) = match rom.get(CARTRIDGE_TYPE).copied().unwrap_or_default() {
+            0x02 | 0x05 | 0x08 | 0x0C | 0x12 | 0x1A | 0x1D => (true, false, false),
+            0x03 | 0x06 | 0x09 | 0x0D | 0x13 | 0x1B | 0x1E => (true, true, false),
+            0x0F => (false, true, true),
+            0x10 => (true, true, true),
+            _ => (false, false, false),
+        };
+        Self {
+            ram,
+            battery,
+            timer,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Mbc1 {
+    /// RAMG, external RAM reads 0xFF and ignores writes until 0xA is written here
+    pub ram_enabled: bool,
+    /// BANK1, the lower 5 bits of the ROM bank
+    bank_low: u8,
+    /// BANK2, either the upper 2 bits of the ROM bank or the RAM bank
+    bank_high: u8,
+    /// MODE, when set BANK2 also applies to 0x0000-0x3FFF and external RAM
+    advanced_banking: bool,
+}
+
+impl Mbc1 {
+    pub const fn write(&mut self, address: usize, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0xF == 0xA,
+            0x2000..=0x3FFF => self.bank_low = value & 0b1_1111,
+            0x4000..=0x5FFF => self.bank_high = value & 0b11,
+            0x6000..=0x7FFF => self.advanced_banking = value & 1 == 1,
+            _ => {}
+        }
+    }
+
+    /// The bank mapped into 0x0000-0x3FFF, which is only ever not 0 on large ROMs in advanced
+    /// banking mode
+    pub const fn low_rom_bank(self) -> usize {
+        if self.advanced_banking {
+            (self.bank_high as usize) << 5
+        } else {
+            0
+        }
+    }
+
+    /// The RAM bank mapped into 0xA000-0xBFFF, which is only ever not 0 in advanced banking mode
+    pub const fn ram_bank(self) -> usize {
+        if self.advanced_banking {
+            self.bank_high as usize
+        } else {
+            0
+        }
+    }
+
+    /// The bank mapped into 0x4000-0x7FFF. BANK1 can't be 0, so banks 0x20, 0x40 and 0x60 can't
+    /// be mapped here
+    pub const fn high_rom_bank(self) -> usize {
+        let low = if self.bank_low == 0 { 1 } else { self.bank_low };
+        (self.bank_high as usize) << 5 | low as usize
+    }
+}
+
+/// MBC2 has 512 4 bit values of RAM built in, instead of RAM on the cartridge
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Mbc2 {
+    /// Like MBC1, RAM reads 0xFF and ignores writes until 0xA is written here
+    pub ram_enabled: bool,
+    /// The ROM bank, 4 bits
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    /// Bytes of built in RAM, which repeat through all of 0xA000-0xBFFF
+    pub const RAM_SIZE: usize = 0x200;
+
+    pub const fn write(&mut self, address: usize, value: u8) {
+        match address {
+            // both registers share the whole range, and bit 8 of the address picks between them
+            0x0000..=0x3FFF if address & 0x100 == 0 => self.ram_enabled = value & 0xF == 0xA,
+            0x0000..=0x3FFF => self.rom_bank = value & 0xF,
+            _ => {}
+        }
+    }
+
+    /// The bank mapped into 0x4000-0x7FFF, where 0 maps bank 1 like on MBC1
+    pub const fn rom_bank(self) -> usize {
+        if self.rom_bank == 0 {
+            1
+        } else {
+            self.rom_bank as usize
+        }
+    }
+}
+
+/// The controller itself comes from the cartridge header, so only its registers are saved
+impl Snapshot for Mbc {
+    fn save(&self, state: &mut StateWriter) {
+        match self {
+            Self::None => {}
+            Self::Mbc1(mbc) => {
+                state.bool(mbc.ram_enabled);
+                state.u8(mbc.bank_low);
+                state.u8(mbc.bank_high);
+                state.bool(mbc.advanced_banking);
+            }
+            Self::Mbc2(mbc) => {
+                state.bool(mbc.ram_enabled);
+                state.u8(mbc.rom_bank);
+            }
+        }
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        match self {
+            Self::None => {}
+            Self::Mbc1(mbc) => {
+                mbc.ram_enabled = state.bool()?;
+                mbc.bank_low = state.u8()?;
+                mbc.bank_high = state.u8()?;
+                mbc.advanced_banking = state.bool()?;
+            }
+            Self::Mbc2(mbc) => {
+                mbc.ram_enabled = state.bool()?;
+                mbc.rom_bank = state.u8()?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@ use enumflags2::{BitFlag as _, BitFlags, bitflags};
 use parse_display::Display;
 
 /// Base registers
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -0,0 +1,88 @@
+#![allow(dead_code)]
+//! Where the emulator core sends its warnings and notices. They go to `tracing` unless a sink is
+//! installed, so an embedder can collect them without setting up a global subscriber
+
+use std::{fmt, sync::RwLock};
+
+/// Receives every warning and notice the core emits
+pub trait Sink: Send + Sync {
+    fn warn(&self, message: fmt::Arguments<'_>);
+    /// Measurements the user turned on, like input latency. Dropped unless overridden
+    fn notice(&self, _message: fmt::Arguments<'_>) {}
+}
+
+impl<F: Fn(fmt::Arguments<'_>) + Send + Sync> Sink for F {
+    fn warn(&self, message: fmt::Arguments<'_>) {
+        self(message);
+    }
+}
+
+static SINK: RwLock<Option<Box<dyn Sink>>> = RwLock::new(None);
+
+/// Sends all future warnings to `sink` instead of `tracing`
+pub fn set_sink(sink: impl Sink + 'static) {
+    *SINK.write().unwrap() = Some(Box::new(sink));
+}
+
+/// Goes back to sending warnings to `tracing`
+pub fn reset_sink() {
+    *SINK.write().unwrap() = None;
+}
+
+pub fn emit_warning(message: fmt::Arguments<'_>) {
+    if let Some(sink) = SINK.read().unwrap().as_deref() {
+        sink.warn(message);
+    } else {
+        tracing::warn!("{message}");
+    }
+}
+
+pub fn emit_notice(message: fmt::Arguments<'_>) {
+    if let Some(sink) = SINK.read().unwrap().as_deref() {
+        sink.notice(message);
+    } else {
+        tracing::info!("{message}");
+    }
+}
+
+/// Like `tracing::warn!`, but goes through the installed sink
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::diagnostics::emit_warning(format_args!($($arg)*))
+    };
+}
+pub(crate) use warning;
+
+/// Like `tracing::info!`, but goes through the installed sink
+macro_rules! notice {
+    ($($arg:tt)*) => {
+        $crate::diagnostics::emit_notice(format_args!($($arg)*))
+    };
+}
+pub(crate) use notice;
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::cpu::memorybus::mbc::Mbc;
+
+    #[test]
+    fn test_custom_sink() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink_warnings = Arc::clone(&warnings);
+        set_sink(move |message: fmt::Arguments<'_>| {
+            sink_warnings.lock().unwrap().push(message.to_string());
+        });
+
+        let mut rom = vec![0; 0x150];
+        rom[0x147] = 0xFC;
+        Mbc::from_header(&rom);
+        reset_sink();
+
+        assert!(warnings.lock().unwrap().contains(&String::from(
+            "unsupported cartridge type FC, treating it as ROM only"
+        )));
+    }
+}
@@ -12,6 +12,7 @@ use num_traits::FromPrimitive as _;
 use crate::disassembler::instruction::HLOrImmediate;
 
 pub mod instruction;
+pub mod symbols;
 
 #[allow(
     clippy::many_single_char_names,
@@ -31,7 +32,6 @@ pub fn parse_instruction(i: &[u8]) -> IResult<&[u8], Instruction> {
     let p = y >> 1;
     let q = y % 2;
 
-    let nyi = || todo!("instruction parse for X:{x} Z:{z} Y:{y} P:{p} Q:{q}");
     let unreachable = || {
         format!(
             "impossible state! X:{x} Z:{z} Y:{y} P:{p} Q:{q}\ndid you increment pc incorrectly?"
@@ -49,6 +49,11 @@ pub fn parse_instruction(i: &[u8]) -> IResult<&[u8], Instruction> {
                         Instruction::Ld(LoadType::FromSp(HLOrImmediate::Immediate(address), 0)),
                     )
                 }
+                2 => {
+                    // STOP is followed by a padding byte which is skipped over
+                    let (i, _) = le_u8().parse(i)?;
+                    (i, Instruction::Stop)
+                }
                 3 => {
                     let (i, relative) = le_i8().parse(i)?;
                     (i, Instruction::JR(JumpTest::Always, relative))
@@ -58,7 +63,7 @@ pub fn parse_instruction(i: &[u8]) -> IResult<&[u8], Instruction> {
                     let (i, relative) = le_i8().parse(i)?;
                     (i, Instruction::JR(condition, relative))
                 }
-                _ => nyi(),
+                _ => unreachable!("{}", unreachable()),
             },
             1 => match q {
                 0 => {
@@ -187,7 +192,7 @@ pub fn parse_instruction(i: &[u8]) -> IResult<&[u8], Instruction> {
                         Instruction::Ld(LoadType::FromSp(HLOrImmediate::HL, offset)),
                     )
                 }
-                _ => nyi(),
+                _ => unreachable!("{}", unreachable()),
             },
             1 => match q {
                 0 => {
@@ -243,7 +248,7 @@ pub fn parse_instruction(i: &[u8]) -> IResult<&[u8], Instruction> {
                         )),
                     )
                 }
-                _ => nyi(),
+                _ => unreachable!("{}", unreachable()),
             },
             3 => match y {
                 0 => {
@@ -256,7 +261,7 @@ pub fn parse_instruction(i: &[u8]) -> IResult<&[u8], Instruction> {
                 1 => prefixed_instruction(i)?,
                 6 => (i, Instruction::Di),
                 7 => (i, Instruction::Ei),
-                _ => panic!("non-existent instruction: {}", unreachable()),
+                _ => (i, Instruction::Illegal(byte)),
             },
             4 => match y {
                 0..=3 => {
@@ -264,7 +269,7 @@ pub fn parse_instruction(i: &[u8]) -> IResult<&[u8], Instruction> {
                     let condition = JumpTest::from_u8(y).unwrap();
                     (i, Instruction::Call(condition, address))
                 }
-                _ => panic!("non-existent instruction: {}", unreachable()),
+                _ => (i, Instruction::Illegal(byte)),
             },
             5 => match q {
                 0 => {
@@ -276,7 +281,7 @@ pub fn parse_instruction(i: &[u8]) -> IResult<&[u8], Instruction> {
                         let (i, address) = le_u16().parse(i)?;
                         (i, Instruction::Call(JumpTest::Always, address))
                     }
-                    _ => panic!("non-existent instruction: {}", unreachable()),
+                    _ => (i, Instruction::Illegal(byte)),
                 },
                 _ => unreachable!("{}", unreachable()),
             },
@@ -325,3 +330,81 @@ fn prefixed_instruction(i: &[u8]) -> IResult<&[u8], Instruction> {
         _ => unreachable!("{}", unreachable()),
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Opcodes which don't exist on the DMG
+    const ILLEGAL_OPCODES: [u8; 11] = [
+        0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+    ];
+
+    /// Decodes every primary and CB-prefixed opcode, making sure none of them hit a `todo!` or
+    /// `unreachable!`. There are currently no opcodes left unhandled.
+    #[test]
+    fn test_decode_all_opcodes() {
+        for opcode in 0x00..=0xFF {
+            let bytes = [opcode, 0x00, 0x00, 0x00];
+            let (_, instruction) = parse_instruction(&bytes).unwrap();
+            assert_eq!(
+                matches!(instruction, Instruction::Illegal(_)),
+                ILLEGAL_OPCODES.contains(&opcode),
+                "opcode {opcode:02X}"
+            );
+        }
+        for opcode in 0x00..=0xFF {
+            let bytes = [0xCB, opcode, 0x00, 0x00];
+            let (rest, instruction) = parse_instruction(&bytes).unwrap();
+            assert!(!matches!(instruction, Instruction::Illegal(_)));
+            assert_eq!(rest.len(), 2, "opcode CB {opcode:02X}");
+        }
+    }
+
+    #[test]
+    fn test_x0_z0_opcodes() {
+        let table = [
+            (0x00, Instruction::Nop, 1),
+            (
+                0x08,
+                Instruction::Ld(LoadType::FromSp(HLOrImmediate::Immediate(0x3412), 0)),
+                3,
+            ),
+            (0x10, Instruction::Stop, 2),
+            (0x18, Instruction::JR(JumpTest::Always, 0x12), 2),
+            (0x20, Instruction::JR(JumpTest::NotZero, 0x12), 2),
+            (0x28, Instruction::JR(JumpTest::Zero, 0x12), 2),
+            (0x30, Instruction::JR(JumpTest::NotCarry, 0x12), 2),
+            (0x38, Instruction::JR(JumpTest::Carry, 0x12), 2),
+        ];
+        for (opcode, expected, length) in table {
+            let bytes = [opcode, 0x12, 0x34, 0x56];
+            let (rest, instruction) = parse_instruction(&bytes).unwrap();
+            assert_eq!(instruction, expected, "opcode {opcode:02X}");
+            assert_eq!(bytes.len() - rest.len(), length, "opcode {opcode:02X}");
+        }
+    }
+
+    #[test]
+    fn test_length_matches_decoder() {
+        let primary = (0x00..=0xFF).map(|opcode| [opcode, 0x00, 0x00, 0x00]);
+        let prefixed = (0x00..=0xFF).map(|opcode| [0xCB, opcode, 0x00, 0x00]);
+        for bytes in primary.chain(prefixed) {
+            let (rest, instruction) = parse_instruction(&bytes).unwrap();
+            assert_eq!(
+                usize::from(instruction.length()),
+                bytes.len() - rest.len(),
+                "opcode {:02X?}",
+                &bytes[..2]
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_primary_opcodes() {
+        for opcode in (0x00..=0xFF).filter(|opcode| !ILLEGAL_OPCODES.contains(opcode)) {
+            let bytes = [opcode, 0x00, 0x00, 0x00];
+            assert!(parse_instruction(&bytes).is_ok(), "opcode {opcode:02X}");
+        }
+    }
+}
@@ -1,8 +1,10 @@
 #![allow(dead_code)]
+use std::fmt;
+
 use num_derive::FromPrimitive;
 use parse_display::Display;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     Ld(LoadType),
     Arithmetic(Alu, RegisterOrImmediate),
@@ -36,9 +38,138 @@ pub enum Instruction {
     Res(u8, Register),
     Set(u8, Register),
     Halt,
+    Stop,
+    /// One of the opcodes which don't exist on the DMG
+    Illegal(u8),
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Instruction {
+    /// How many bytes the instruction takes up, including the opcode and the CB prefix
+    pub const fn length(self) -> u8 {
+        match self {
+            Self::Ld(
+                LoadType::Indirect(LoadIndirect::Immediate(_), _)
+                | LoadType::Word(_, HLOrImmediate::Immediate(_))
+                | LoadType::FromSp(HLOrImmediate::Immediate(_), _),
+            )
+            | Self::JP(_, HLOrImmediate::Immediate(_))
+            | Self::Call(..) => 3,
+            Self::Ld(
+                LoadType::Byte(_, RegisterOrImmediate::Immediate(_))
+                | LoadType::LastByteAddress(COrImmediate::Immediate(_), _)
+                | LoadType::FromSp(HLOrImmediate::HL, _),
+            )
+            | Self::Arithmetic(_, RegisterOrImmediate::Immediate(_))
+            | Self::AddSp(_)
+            | Self::JR(..)
+            | Self::Stop
+            | Self::Rot(..)
+            | Self::Bit(..)
+            | Self::Res(..)
+            | Self::Set(..) => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Formats the instruction as assembly, with immediates written as `$`-prefixed hex
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // JumpTest includes the comma separating it from the next operand, and is empty for
+        // unconditional jumps
+        let condition = |test: &JumpTest| match test {
+            JumpTest::Always => String::new(),
+            _ => format!("{test} "),
+        };
+        let indirect = |indirect: &LoadIndirect| match indirect {
+            LoadIndirect::Immediate(address) => format!("${address:04X}"),
+            _ => indirect.to_opcode_string(),
+        };
+        let source = |source: &RegisterOrImmediate| match source {
+            RegisterOrImmediate::Register(register) => register.to_string(),
+            RegisterOrImmediate::Immediate(value) => format!("${value:02X}"),
+        };
+
+        match self {
+            Self::Ld(LoadType::Indirect(target, Direction::FromA)) => {
+                write!(f, "LD ({}), A", indirect(target))
+            }
+            Self::Ld(LoadType::Indirect(target, Direction::IntoA)) => {
+                write!(f, "LD A, ({})", indirect(target))
+            }
+            Self::Ld(LoadType::Byte(register, value)) => {
+                write!(f, "LD {register}, {}", source(value))
+            }
+            Self::Ld(LoadType::Word(register, HLOrImmediate::HL)) => write!(f, "LD {register}, HL"),
+            Self::Ld(LoadType::Word(register, HLOrImmediate::Immediate(value))) => {
+                write!(f, "LD {register}, ${value:04X}")
+            }
+            Self::Ld(LoadType::LastByteAddress(COrImmediate::C, Direction::FromA)) => {
+                write!(f, "LDH (C), A")
+            }
+            Self::Ld(LoadType::LastByteAddress(COrImmediate::C, Direction::IntoA)) => {
+                write!(f, "LDH A, (C)")
+            }
+            Self::Ld(LoadType::LastByteAddress(
+                COrImmediate::Immediate(offset),
+                Direction::FromA,
+            )) => {
+                write!(f, "LDH (${offset:02X}), A")
+            }
+            Self::Ld(LoadType::LastByteAddress(
+                COrImmediate::Immediate(offset),
+                Direction::IntoA,
+            )) => {
+                write!(f, "LDH A, (${offset:02X})")
+            }
+            Self::Ld(LoadType::FromSp(HLOrImmediate::Immediate(address), _)) => {
+                write!(f, "LD (${address:04X}), SP")
+            }
+            Self::Ld(LoadType::FromSp(HLOrImmediate::HL, offset)) => {
+                write!(f, "LD HL, SP{offset:+}")
+            }
+            Self::Arithmetic(alu, value) => write!(f, "{alu} {}", source(value)),
+            Self::AddHl(register) => write!(f, "ADD HL, {register}"),
+            Self::AddSp(offset) => write!(f, "ADD SP, {offset}"),
+            Self::Bit(bit, register) => write!(f, "BIT {bit}, {register}"),
+            Self::Res(bit, register) => write!(f, "RES {bit}, {register}"),
+            Self::Set(bit, register) => write!(f, "SET {bit}, {register}"),
+            Self::JR(test, offset) => write!(f, "JR {}{offset}", condition(test)),
+            Self::JP(_, HLOrImmediate::HL) => write!(f, "JP HL"),
+            Self::JP(test, HLOrImmediate::Immediate(address)) => {
+                write!(f, "JP {}${address:04X}", condition(test))
+            }
+            Self::Inc(register) => write!(f, "INC {register}"),
+            Self::Inc16(register) => write!(f, "INC {register}"),
+            Self::Dec(register) => write!(f, "DEC {register}"),
+            Self::Dec16(register) => write!(f, "DEC {register}"),
+            Self::Call(test, address) => write!(f, "CALL {}${address:04X}", condition(test)),
+            Self::Ret(JumpTest::Always) => write!(f, "RET"),
+            Self::Ret(test) => write!(f, "RET {}", test.to_string().trim_end_matches(',')),
+            Self::Push(register) => write!(f, "PUSH {register}"),
+            Self::Pop(register) => write!(f, "POP {register}"),
+            Self::Rot(rot, register) => write!(f, "{rot} {register}"),
+            Self::Reset(address) => write!(f, "RST ${address:02X}"),
+            Self::Illegal(opcode) => write!(f, "ILLEGAL ${opcode:02X}"),
+            Self::Rlca => write!(f, "RLCA"),
+            Self::Rrca => write!(f, "RRCA"),
+            Self::Rla => write!(f, "RLA"),
+            Self::Rra => write!(f, "RRA"),
+            Self::Di => write!(f, "DI"),
+            Self::Ei => write!(f, "EI"),
+            Self::Nop => write!(f, "NOP"),
+            Self::Daa => write!(f, "DAA"),
+            Self::Reti => write!(f, "RETI"),
+            Self::Cpl => write!(f, "CPL"),
+            Self::Scf => write!(f, "SCF"),
+            Self::Ccf => write!(f, "CCF"),
+            Self::Halt => write!(f, "HALT"),
+            Self::Stop => write!(f, "STOP"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LoadType {
     Indirect(LoadIndirect, Direction),
     Byte(Register, RegisterOrImmediate),
@@ -47,14 +178,14 @@ pub enum LoadType {
     FromSp(HLOrImmediate, i8),
 }
 
-#[derive(Debug, Clone, Copy, Display)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
 pub enum HLOrImmediate {
     HL,
     #[display("{0:04X}")]
     Immediate(u16),
 }
 
-#[derive(Debug, Clone, Copy, Display)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
 pub enum RegisterOrImmediate {
     #[display("{0}")]
     Register(Register),
@@ -62,7 +193,7 @@ pub enum RegisterOrImmediate {
     Immediate(u8),
 }
 
-#[derive(Debug, Clone, Copy, Display)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
 pub enum COrImmediate {
     C,
     #[display("{0:02X}")]
@@ -80,7 +211,7 @@ pub enum LoadByteTarget {
     HL,
 }
 
-#[derive(Debug, Clone, Copy, Display)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
 pub enum LoadIndirect {
     BC,
     DE,
@@ -103,7 +234,7 @@ impl LoadIndirect {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Direction {
     FromA,
     IntoA,
@@ -120,7 +251,7 @@ pub enum LoadByteDecTarget {
     HL,
 }
 
-#[derive(Debug, FromPrimitive, Clone, Copy, Display)]
+#[derive(Debug, FromPrimitive, Clone, Copy, PartialEq, Eq, Display)]
 pub enum JumpTest {
     #[display("NZ,")]
     NotZero,
@@ -148,14 +279,14 @@ pub enum Register {
     A,
 }
 
-#[derive(Debug, FromPrimitive, Clone, Copy, Display)]
+#[derive(Debug, FromPrimitive, Clone, Copy, PartialEq, Eq, Display)]
 pub enum Register16 {
     BC,
     DE,
     HL,
     SP,
 }
-#[derive(Debug, FromPrimitive, Clone, Copy, Display)]
+#[derive(Debug, FromPrimitive, Clone, Copy, PartialEq, Eq, Display)]
 pub enum Register16Alt {
     BC,
     DE,
@@ -0,0 +1,121 @@
+#![allow(dead_code)]
+//! Labels from RGBDS `.sym` files, so disassembly can show names instead of addresses
+
+use std::{collections::HashMap, str::FromStr};
+
+use parse_display::Display;
+
+use super::instruction::{HLOrImmediate, Instruction};
+
+/// Labels by address, with the bank each one is in
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    labels: HashMap<u16, Vec<(usize, String)>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[display("line {0}: expected `BB:AAAA label`")]
+pub struct ParseSymbolsError(pub usize);
+
+impl std::error::Error for ParseSymbolsError {}
+
+impl FromStr for Symbols {
+    type Err = ParseSymbolsError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut symbols = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parse = || {
+                let (location, label) = line.split_once(char::is_whitespace)?;
+                let (bank, address) = location.split_once(':')?;
+                Some((
+                    usize::from_str_radix(bank, 16).ok()?,
+                    u16::from_str_radix(address, 16).ok()?,
+                    label.trim(),
+                ))
+            };
+            let (bank, address, label) = parse().ok_or(ParseSymbolsError(index + 1))?;
+            symbols
+                .labels
+                .entry(address)
+                .or_default()
+                .push((bank, label.to_owned()));
+        }
+        Ok(symbols)
+    }
+}
+
+impl Symbols {
+    /// The label at `address`. In the switchable ROM bank it has to be in `rom_bank`, anywhere
+    /// else the first label at that address is used
+    pub fn label(&self, address: u16, rom_bank: usize) -> Option<&str> {
+        let labels = self.labels.get(&address)?;
+        let (_, label) = if (0x4000..0x8000).contains(&address) {
+            labels.iter().find(|(bank, _)| *bank == rom_bank)?
+        } else {
+            labels.first()?
+        };
+        Some(label)
+    }
+
+    /// `address` as its label if it has one, otherwise as hex
+    pub fn name(&self, address: u16, rom_bank: usize) -> String {
+        self.label(address, rom_bank)
+            .map_or_else(|| format!("{address:04X}"), str::to_owned)
+    }
+
+    /// Formats `instruction` like its `Display` does, but with the target of a jump or call
+    /// replaced by its label
+    pub fn disassemble(&self, instruction: Instruction, rom_bank: usize) -> String {
+        let text = instruction.to_string();
+        let (Instruction::Call(_, address) | Instruction::JP(_, HLOrImmediate::Immediate(address))) =
+            instruction
+        else {
+            return text;
+        };
+        match self.label(address, rom_bank) {
+            Some(label) => text.replace(&format!("${address:04X}"), label),
+            None => text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disassembler::instruction::JumpTest;
+
+    const SYMBOLS: &str = "\
+        ; File generated by rgblink\n\
+        00:0150 Start\n\
+        01:4000 Bank1Routine\n\
+        02:4000 Bank2Routine ; comment\n\
+        00:C000 wCounter\n";
+
+    #[test]
+    fn test_call_prints_label() {
+        let symbols: Symbols = SYMBOLS.parse().unwrap();
+        let call = Instruction::Call(JumpTest::Always, 0x0150);
+        assert_eq!(symbols.disassemble(call, 1), "CALL Start");
+        let call = Instruction::Call(JumpTest::NotZero, 0x0200);
+        assert_eq!(symbols.disassemble(call, 1), call.to_string());
+
+        let jump = Instruction::JP(JumpTest::Always, HLOrImmediate::Immediate(0x4000));
+        assert_eq!(symbols.disassemble(jump, 1), "JP Bank1Routine");
+        assert_eq!(symbols.disassemble(jump, 2), "JP Bank2Routine");
+        assert_eq!(symbols.disassemble(jump, 3), "JP $4000");
+        assert_eq!(symbols.name(0xC000, 3), "wCounter");
+    }
+
+    #[test]
+    fn test_rejects_malformed_lines() {
+        assert_eq!(
+            "00:0150 Start\nStart".parse::<Symbols>().unwrap_err(),
+            ParseSymbolsError(2)
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use crate::joypad::InputState;
+
+pub mod flashing;
+pub mod ghosting;
+pub mod overlay;
+pub mod shader;
+pub mod terminal;
+pub mod window;
+
+/// A front-end which frames are drawn to and input is read from
+pub trait Display {
+    /// Draws a native resolution RGB framebuffer
+    fn present(&mut self, rgb: &[u8]);
+    /// Returns which buttons are currently being held down
+    fn poll_input(&mut self) -> InputState;
+    /// Returns if the key toggling the sprite overlay was pressed since the last call
+    fn overlay_toggled(&mut self) -> bool {
+        false
+    }
+    fn is_open(&self) -> bool;
+}
+
+/// Display that never presses anything and closes after a number of frames, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct Headless {
+    frames_left: usize,
+    /// The most recent frame presented
+    pub last_frame: Vec<u8>,
+    pub frames_presented: usize,
+}
+
+#[cfg(test)]
+impl Headless {
+    pub const fn new(frames: usize) -> Self {
+        Self {
+            frames_left: frames,
+            last_frame: Vec::new(),
+            frames_presented: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Display for Headless {
+    fn present(&mut self, rgb: &[u8]) {
+        self.last_frame = rgb.to_vec();
+        self.frames_presented += 1;
+        self.frames_left = self.frames_left.saturating_sub(1);
+    }
+
+    fn poll_input(&mut self) -> InputState {
+        InputState::default()
+    }
+
+    fn is_open(&self) -> bool {
+        self.frames_left > 0
+    }
+}
@@ -0,0 +1,89 @@
+/// Softens sudden changes in overall brightness, like whole screen flashes, for players who are
+/// sensitive to them. When a frame's average brightness jumps too far from the last frame shown,
+/// it's blended towards that frame instead of being shown as is.
+#[derive(Debug)]
+pub struct FlashReduction {
+    /// The last frame presented, after filtering
+    previous: Option<Vec<u8>>,
+    /// How far the average brightness can move between frames before it counts as a flash
+    threshold: u8,
+    /// How much of the previous frame is kept during a flash, as a percentage
+    strength: u8,
+}
+
+impl FlashReduction {
+    pub fn new(threshold: u8, strength: u8) -> Self {
+        Self {
+            previous: None,
+            threshold,
+            strength: strength.min(100),
+        }
+    }
+
+    /// Returns `rgb`, blended with the previous frame if it would flash
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn filter(&mut self, rgb: &[u8]) -> Vec<u8> {
+        let output = match &self.previous {
+            Some(previous) if luminance(rgb).abs_diff(luminance(previous)) > self.threshold => {
+                let kept = u16::from(self.strength);
+                rgb.iter()
+                    .zip(previous)
+                    .map(|(&new, &old)| {
+                        // a weighted average of u8s always fits in a u8
+                        ((u16::from(old) * kept + u16::from(new) * (100 - kept)) / 100) as u8
+                    })
+                    .collect()
+            }
+            _ => rgb.to_vec(),
+        };
+        // comparing against what was shown means a lasting change still fades in over a few frames
+        self.previous = Some(output.clone());
+        output
+    }
+}
+
+/// The average brightness of an RGB frame, weighting each channel by how bright it looks
+#[allow(clippy::cast_possible_truncation)]
+fn luminance(rgb: &[u8]) -> u8 {
+    let pixels = rgb.len() as u64 / 3;
+    if pixels == 0 {
+        return 0;
+    }
+    let total: u64 = rgb
+        .chunks_exact(3)
+        .map(|pixel| {
+            u64::from(pixel[0]) * 299 + u64::from(pixel[1]) * 587 + u64::from(pixel[2]) * 114
+        })
+        .sum();
+    (total / 1000 / pixels) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpu::{HEIGHT, WIDTH};
+
+    #[test]
+    fn test_flash_is_attenuated() {
+        let mut filter = FlashReduction::new(64, 75);
+        let black = vec![0; WIDTH * HEIGHT * 3];
+        assert_eq!(filter.filter(&black), black);
+
+        let flash = filter.filter(&vec![0xFF; WIDTH * HEIGHT * 3]);
+        assert!(flash.iter().all(|&channel| channel == 63));
+        // back to black isn't a big enough jump from what was shown to be held back
+        assert_eq!(filter.filter(&black), black);
+    }
+
+    #[test]
+    fn test_motion_passes_through() {
+        let mut filter = FlashReduction::new(64, 75);
+        let mut frame = vec![0xFF; WIDTH * HEIGHT * 3];
+        filter.filter(&frame);
+        // an 8x8 sprite appearing barely changes the overall brightness
+        for y in 0..8 {
+            frame[y * WIDTH * 3..(y * WIDTH + 8) * 3].fill(0);
+        }
+        assert_eq!(filter.filter(&frame), frame);
+    }
+}
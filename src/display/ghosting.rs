@@ -0,0 +1,56 @@
+use std::collections::VecDeque;
+
+/// Blends each frame with the ones before it, like the slow pixel response of the DMG's LCD.
+/// Some games flicker sprites every other frame and rely on this to make them look transparent.
+#[derive(Debug)]
+pub struct Ghosting {
+    history: VecDeque<Vec<u8>>,
+    /// How many frames are averaged together, including the newest
+    depth: usize,
+}
+
+impl Ghosting {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            history: VecDeque::with_capacity(depth),
+            depth: depth.max(1),
+        }
+    }
+
+    /// Returns the average of `rgb` and the previous frames. The input is left untouched so
+    /// the emulator's own framebuffer stays sharp.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn blend(&mut self, rgb: &[u8]) -> Vec<u8> {
+        if self.history.len() == self.depth {
+            self.history.pop_front();
+        }
+        self.history.push_back(rgb.to_vec());
+
+        let frames = self.history.len() as u16;
+        (0..rgb.len())
+            .map(|index| {
+                let sum: u16 = self
+                    .history
+                    .iter()
+                    .map(|frame| u16::from(frame[index]))
+                    .sum();
+                // the average of u8s always fits in a u8
+                (sum / frames) as u8
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blends_previous_frames() {
+        let mut ghosting = Ghosting::new(2);
+        assert_eq!(ghosting.blend(&[255; 3]), [255; 3]);
+        assert_eq!(ghosting.blend(&[0; 3]), [127; 3]);
+        // the white frame has fallen out of the history
+        assert_eq!(ghosting.blend(&[0; 3]), [0; 3]);
+    }
+}
@@ -0,0 +1,111 @@
+//! Outlines around every sprite on screen, for tracking down sprite positioning bugs. It's drawn
+//! over a copy of the frame on its way to the display, so it never shows up in emulation
+
+use crate::gpu::{Gpu, HEIGHT, WIDTH};
+
+/// Drawn in a colour none of the palettes use, so it stands out
+const COLOUR: [u8; 3] = [0xFF, 0x00, 0xFF];
+
+/// A sprite's bounds in screen pixels. Partly offscreen sprites go past the edges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i16,
+    pub y: i16,
+    pub width: i16,
+    pub height: i16,
+}
+
+/// The bounds of every sprite which is at least partly on screen, in OAM order
+#[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+pub fn sprite_boxes(gpu: &Gpu) -> Vec<Rect> {
+    let height = i16::from(gpu.sprite_height());
+    gpu.oam_sprites()
+        .iter()
+        .map(|sprite| Rect {
+            // positions are offset so sprites can be scrolled in from the top and left
+            x: i16::from(sprite.x) - 8,
+            y: i16::from(sprite.y) - 16,
+            width: 8,
+            height,
+        })
+        .filter(|rect| {
+            rect.x + rect.width > 0
+                && rect.x < WIDTH as i16
+                && rect.y + rect.height > 0
+                && rect.y < HEIGHT as i16
+        })
+        .collect()
+}
+
+/// Draws the outline of each of `boxes` onto a native resolution RGB frame
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss
+)]
+pub fn draw(rgb: &mut [u8], boxes: &[Rect]) {
+    let mut plot = |x: i16, y: i16| {
+        if (0..WIDTH as i16).contains(&x) && (0..HEIGHT as i16).contains(&y) {
+            let index = (y as usize * WIDTH + x as usize) * 3;
+            rgb[index..index + 3].copy_from_slice(&COLOUR);
+        }
+    };
+    for rect in boxes {
+        let (right, bottom) = (rect.x + rect.width - 1, rect.y + rect.height - 1);
+        for x in rect.x..=right {
+            plot(x, rect.y);
+            plot(x, bottom);
+        }
+        for y in rect.y..=bottom {
+            plot(rect.x, y);
+            plot(right, y);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpu::LCDControl;
+
+    #[test]
+    fn test_sprite_boxes() {
+        let mut gpu = Gpu::default();
+        // one on screen and one partly off the top left corner. The rest are hidden offscreen
+        // at (0, 0)
+        for (index, (y, x)) in [(16, 8), (10, 4)].into_iter().enumerate() {
+            gpu.write_oam(index * 4, y);
+            gpu.write_oam(index * 4 + 1, x);
+        }
+        let rect = |x, y, height| Rect {
+            x,
+            y,
+            width: 8,
+            height,
+        };
+        assert_eq!(sprite_boxes(&gpu), [rect(0, 0, 8), rect(-4, -6, 8)]);
+
+        gpu.lcd_control = LCDControl::TallSprites.into();
+        assert_eq!(sprite_boxes(&gpu), [rect(0, 0, 16), rect(-4, -6, 16)]);
+    }
+
+    #[test]
+    fn test_draw_clips_to_screen() {
+        let mut rgb = vec![0; WIDTH * HEIGHT * 3];
+        let boxes = [Rect {
+            x: -4,
+            y: -6,
+            width: 8,
+            height: 8,
+        }];
+        draw(&mut rgb, &boxes);
+        let pixel = |x: usize, y: usize| &rgb[(y * WIDTH + x) * 3..][..3];
+        // only the bottom and right edges are on screen
+        for x in 0..=3 {
+            assert_eq!(pixel(x, 1), COLOUR);
+        }
+        assert_eq!(pixel(3, 0), COLOUR);
+        assert_eq!(pixel(0, 0), [0; 3]);
+        assert_eq!(pixel(4, 1), [0; 3]);
+    }
+}
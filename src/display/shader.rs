@@ -0,0 +1,69 @@
+use clap::ValueEnum;
+
+use crate::gpu::WIDTH;
+
+/// Post-processing applied to the upscaled frame in the window. It's only part of presentation,
+/// so screenshots and the emulator's own framebuffer stay untouched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Shader {
+    /// Plain nearest-neighbour upscaling
+    #[default]
+    None,
+    /// Darken the bottom and right edge of every pixel, like the gaps between the DMG LCD's
+    /// pixels. Needs a scale of at least 2
+    LcdGrid,
+}
+
+impl Shader {
+    /// Applies the effect in place to a 0RGB frame which was upscaled `scale` times
+    pub fn apply(self, buffer: &mut [u32], scale: usize) {
+        match self {
+            Self::None => {}
+            Self::LcdGrid => lcd_grid(buffer, scale),
+        }
+    }
+}
+
+fn lcd_grid(buffer: &mut [u32], scale: usize) {
+    // at 1x there's no room for a gap without losing the pixel
+    if scale < 2 {
+        return;
+    }
+    for (y, row) in buffer.chunks_exact_mut(WIDTH * scale).enumerate() {
+        let gap_row = y % scale == scale - 1;
+        for (x, pixel) in row.iter_mut().enumerate() {
+            if gap_row || x % scale == scale - 1 {
+                *pixel = darken(*pixel);
+            }
+        }
+    }
+}
+
+/// Takes each channel down to 3/4 of its brightness
+const fn darken(pixel: u32) -> u32 {
+    let [_, r, g, b] = pixel.to_be_bytes();
+    u32::from_be_bytes([0, r - r / 4, g - g / 4, b - b / 4])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpu::HEIGHT;
+
+    #[test]
+    fn test_lcd_grid() {
+        for scale in 1..=4 {
+            let mut buffer = vec![0x00FF_FFFF; WIDTH * HEIGHT * scale * scale];
+            Shader::LcdGrid.apply(&mut buffer, scale);
+            assert_eq!(buffer.len(), WIDTH * HEIGHT * scale * scale);
+
+            let gaps = buffer.iter().filter(|&&pixel| pixel == 0x00C0_C0C0).count();
+            let pixel_gaps = if scale == 1 { 0 } else { 2 * scale - 1 };
+            assert_eq!(gaps, WIDTH * HEIGHT * pixel_gaps, "{scale}x");
+        }
+
+        let mut buffer = vec![0x0012_3456; WIDTH * HEIGHT * 4];
+        Shader::None.apply(&mut buffer, 2);
+        assert!(buffer.iter().all(|&pixel| pixel == 0x0012_3456));
+    }
+}
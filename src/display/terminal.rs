@@ -0,0 +1,93 @@
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    display::Display,
+    gpu::{HEIGHT, WIDTH},
+    joypad::InputState,
+};
+
+/// Only every `DOWNSAMPLE`th pixel is drawn in each direction, so the frame fits in a terminal
+const DOWNSAMPLE: usize = 2;
+const COLUMNS: usize = WIDTH / DOWNSAMPLE;
+/// Each cell is a half block, so it covers two rows of sampled pixels
+const ROWS: usize = HEIGHT / DOWNSAMPLE / 2;
+
+type Rgb = (u8, u8, u8);
+
+/// Display which draws to the terminal with 24-bit colour half block characters
+pub struct TerminalDisplay {
+    /// Top and bottom colour of every cell currently on screen
+    previous: Vec<Option<(Rgb, Rgb)>>,
+    next_frame: Instant,
+}
+
+impl TerminalDisplay {
+    pub fn new() -> Self {
+        // clear the screen and hide the cursor
+        print!("\x1b[2J\x1b[?25l");
+        Self {
+            previous: vec![None; COLUMNS * ROWS],
+            next_frame: Instant::now(),
+        }
+    }
+}
+
+impl Drop for TerminalDisplay {
+    fn drop(&mut self) {
+        // reset colours and show the cursor again
+        print!("\x1b[0m\x1b[?25h");
+    }
+}
+
+impl Display for TerminalDisplay {
+    fn present(&mut self, rgb: &[u8]) {
+        let pixel = |x: usize, y: usize| -> Rgb {
+            let index = (y * WIDTH + x) * 3;
+            (rgb[index], rgb[index + 1], rgb[index + 2])
+        };
+
+        let mut output = String::new();
+        for row in 0..ROWS {
+            for column in 0..COLUMNS {
+                let x = column * DOWNSAMPLE;
+                let y = row * DOWNSAMPLE * 2;
+                let cell = (pixel(x, y), pixel(x, y + DOWNSAMPLE));
+                // only repaint cells which changed since the last frame
+                if self.previous[row * COLUMNS + column] == Some(cell) {
+                    continue;
+                }
+                self.previous[row * COLUMNS + column] = Some(cell);
+
+                let ((tr, tg, tb), (br, bg, bb)) = cell;
+                let _ = write!(
+                    output,
+                    "\x1b[{};{}H\x1b[38;2;{tr};{tg};{tb}m\x1b[48;2;{br};{bg};{bb}m▀",
+                    row + 1,
+                    column + 1
+                );
+            }
+        }
+        if !output.is_empty() {
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout.write_all(output.as_bytes());
+            let _ = stdout.flush();
+        }
+
+        // there's no vsync to block on, so cap to 60fps ourselves
+        std::thread::sleep_until(self.next_frame);
+        self.next_frame = Instant::now() + Duration::from_secs_f64(1.0 / 60.0);
+    }
+
+    fn poll_input(&mut self) -> InputState {
+        // FIXME: reading keys needs the terminal in raw mode
+        InputState::default()
+    }
+
+    fn is_open(&self) -> bool {
+        true
+    }
+}
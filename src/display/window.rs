@@ -0,0 +1,97 @@
+use jane_eyre::eyre::{self, eyre};
+use minifb::{Key, KeyRepeat, ScaleMode, Window, WindowOptions};
+
+use crate::{
+    display::{Display, shader::Shader},
+    gpu::{HEIGHT, WIDTH},
+    joypad::{Button, InputState},
+};
+
+/// Which key presses each button
+const KEYMAP: [(Key, Button); 8] = [
+    (Key::Z, Button::A),
+    (Key::X, Button::B),
+    (Key::Backspace, Button::Select),
+    (Key::Enter, Button::Start),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::Left, Button::Left),
+    (Key::Right, Button::Right),
+];
+
+/// Shows or hides outlines around sprites
+const OVERLAY_KEY: Key = Key::O;
+
+/// Display backed by a `minifb` window
+pub struct WindowDisplay {
+    window: Window,
+    scale: usize,
+    shader: Shader,
+}
+
+const fn from_u8_rgb(r: u8, g: u8, b: u8) -> u32 {
+    let (r, g, b) = (r as u32, g as u32, b as u32);
+    (r << 16) | (g << 8) | b
+}
+
+/// Converts the native 160x144 RGB framebuffer into a `scale`x nearest-neighbour upscaled buffer
+/// suitable for handing to `minifb`
+fn scale_buffer(rgb: &[u8], scale: usize) -> Vec<u32> {
+    let mut output = Vec::with_capacity(WIDTH * HEIGHT * scale * scale);
+    for row in rgb.chunks_exact(WIDTH * 3) {
+        let scaled_row: Vec<u32> = row
+            .chunks_exact(3)
+            .map(|rgb| from_u8_rgb(rgb[0], rgb[1], rgb[2]))
+            .flat_map(|pixel| std::iter::repeat_n(pixel, scale))
+            .collect();
+        for _ in 0..scale {
+            output.extend_from_slice(&scaled_row);
+        }
+    }
+    output
+}
+
+impl WindowDisplay {
+    /// Without `vsync`, presenting never waits for the next 60th of a second
+    pub fn new(scale: usize, resizable: bool, vsync: bool, shader: Shader) -> eyre::Result<Self> {
+        let options = WindowOptions {
+            resize: resizable,
+            scale_mode: ScaleMode::AspectRatioStretch,
+            ..WindowOptions::default()
+        };
+        let mut window = Window::new("gb-rs", WIDTH * scale, HEIGHT * scale, options)
+            .map_err(|x| eyre!("{x:?}"))?;
+        window.set_target_fps(if vsync { 60 } else { 0 });
+        Ok(Self {
+            window,
+            scale,
+            shader,
+        })
+    }
+}
+
+impl Display for WindowDisplay {
+    fn present(&mut self, rgb: &[u8]) {
+        let mut buffer = scale_buffer(rgb, self.scale);
+        self.shader.apply(&mut buffer, self.scale);
+        self.window
+            .update_with_buffer(&buffer, WIDTH * self.scale, HEIGHT * self.scale)
+            .unwrap();
+    }
+
+    fn poll_input(&mut self) -> InputState {
+        let mut input = InputState::default();
+        for (key, button) in KEYMAP {
+            input.set_button(button, self.window.is_key_down(key));
+        }
+        input
+    }
+
+    fn overlay_toggled(&mut self) -> bool {
+        self.window.is_key_pressed(OVERLAY_KEY, KeyRepeat::No)
+    }
+
+    fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+}
@@ -0,0 +1,14 @@
+use parse_display::Display;
+
+/// Errors which stop the emulator from making progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum EmuError {
+    /// The bytes at `pc` couldn't be decoded into an instruction
+    #[display("failed to decode instruction at {pc:04X}: {bytes:02X?}")]
+    Decode { pc: u16, bytes: [u8; 4] },
+    /// The cpu hit an opcode that doesn't exist, which hard locks real hardware
+    #[display("illegal opcode {opcode:02X} at {pc:04X}")]
+    IllegalOpcode { pc: u16, opcode: u8 },
+}
+
+impl std::error::Error for EmuError {}
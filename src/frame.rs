@@ -0,0 +1,91 @@
+//! Hands finished frames from the emulator thread to the display thread
+
+use std::{
+    sync::{Condvar, Mutex, atomic::AtomicBool},
+    time::Duration,
+};
+
+use crate::gpu::{HEIGHT, WIDTH};
+
+#[derive(Debug)]
+struct Frame {
+    rgb: Vec<u8>,
+    /// How many frames had been published when this one was
+    number: u64,
+}
+
+/// The latest whole frame the emulator has drawn
+#[derive(Debug)]
+pub struct SharedFrame {
+    frame: Mutex<Frame>,
+    published: Condvar,
+    /// Whether the emulator outlines sprites in the frames it publishes, toggled by the display
+    pub sprite_overlay: AtomicBool,
+}
+
+impl SharedFrame {
+    pub fn new() -> Self {
+        Self {
+            frame: Mutex::new(Frame {
+                rgb: vec![0; WIDTH * HEIGHT * 3],
+                number: 0,
+            }),
+            published: Condvar::new(),
+            sprite_overlay: AtomicBool::new(false),
+        }
+    }
+
+    /// Replaces the frame, waking the display if it's waiting for one
+    pub fn publish(&self, rgb: &[u8]) {
+        {
+            let mut frame = self.frame.lock().unwrap();
+            frame.rgb.copy_from_slice(rgb);
+            frame.number += 1;
+        }
+        self.published.notify_all();
+    }
+
+    /// How many frames have been published so far
+    #[cfg(test)]
+    pub fn published(&self) -> u64 {
+        self.frame.lock().unwrap().number
+    }
+
+    /// Copies out the latest frame along with its number
+    pub fn latest(&self) -> (u64, Vec<u8>) {
+        let frame = self.frame.lock().unwrap();
+        (frame.number, frame.rgb.clone())
+    }
+
+    /// Waits up to `timeout` for a frame newer than `seen`, then copies out the latest frame
+    /// whether or not one arrived
+    pub fn wait_for_newer(&self, seen: u64, timeout: Duration) -> (u64, Vec<u8>) {
+        let frame = self.frame.lock().unwrap();
+        self.published
+            .wait_timeout_while(frame, timeout, |frame| frame.number <= seen)
+            .map(|(frame, _)| (frame.number, frame.rgb.clone()))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_wait_for_newer() {
+        let shared = Arc::new(SharedFrame::new());
+        let (number, _) = shared.wait_for_newer(0, Duration::from_millis(1));
+        assert_eq!(number, 0);
+
+        let publisher = Arc::clone(&shared);
+        let thread = std::thread::spawn(move || publisher.publish(&vec![0xFF; WIDTH * HEIGHT * 3]));
+        let (number, rgb) = shared.wait_for_newer(0, Duration::from_secs(10));
+        thread.join().unwrap();
+        assert_eq!(number, 1);
+        assert!(rgb.iter().all(|&byte| byte == 0xFF));
+        assert_eq!(shared.published(), 1);
+    }
+}
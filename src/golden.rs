@@ -0,0 +1,119 @@
+//! Golden file tests, which run a ROM for a fixed number of instructions and compare every state
+//! along the way against a committed log. Run with `UPDATE_GOLDEN=1` to rewrite the logs after an
+//! intended change
+
+use std::{fmt::Write as _, path::Path};
+
+use crate::cpu::{Cpu, memorybus::MemoryBus};
+
+/// Lines of unchanged context shown around each difference
+const CONTEXT: usize = 3;
+
+/// The state before each of the first `instructions` instructions, in the Gameboy Doctor format.
+/// LY is stubbed out like in `--log`, so the log doesn't depend on PPU timing. It always runs as a
+/// DMG, so the log doesn't depend on whether the `cgb` feature is enabled either
+pub fn trace(rom: &[u8], instructions: usize) -> String {
+    let mut bus = MemoryBus::new(None, rom, true);
+    bus.cgb_mode = false;
+    bus.gpu.cgb_mode = false;
+    bus.oam_bug = true;
+    let mut cpu = Cpu::power_on(bus);
+    let mut log = String::new();
+    for _ in 0..instructions {
+        log.push_str(&cpu.format_state());
+        cpu.step().unwrap();
+    }
+    log
+}
+
+/// Panics with a diff if `actual` doesn't match the golden file at `path`, relative to the crate
+/// root
+pub fn assert_golden(path: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "couldn't read {}: {e}. Run with UPDATE_GOLDEN=1 to create it",
+            path.display()
+        )
+    });
+    assert!(
+        expected == actual,
+        "{} doesn't match. Run with UPDATE_GOLDEN=1 if this is intended\n{}",
+        path.display(),
+        diff(&expected, actual)
+    );
+}
+
+/// A unified diff of two logs, comparing them line by line. Traces have one line per
+/// instruction, so they never need realigning
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<_> = expected.lines().collect();
+    let actual: Vec<_> = actual.lines().collect();
+    let lines = expected.len().max(actual.len());
+    let differs = |line: usize| expected.get(line) != actual.get(line);
+
+    let mut output = String::from("--- expected\n+++ actual\n");
+    let mut line = 0;
+    while line < lines {
+        if !differs(line) {
+            line += 1;
+            continue;
+        }
+        // a hunk runs until there are more than 2 * CONTEXT matching lines in a row
+        let start = line.saturating_sub(CONTEXT);
+        let mut end = line;
+        while end < lines && (end..(end + 2 * CONTEXT + 1).min(lines)).any(differs) {
+            end += 1;
+        }
+        let end = (end + CONTEXT).min(lines);
+        let count = |log: &[&str]| log.len().clamp(start, end) - start;
+        writeln!(
+            output,
+            "@@ -{},{} +{},{} @@",
+            start + 1,
+            count(&expected),
+            start + 1,
+            count(&actual)
+        )
+        .unwrap();
+        for line in start..end {
+            match (expected.get(line), actual.get(line)) {
+                (Some(old), Some(new)) if old == new => writeln!(output, " {old}").unwrap(),
+                (old, new) => {
+                    if let Some(old) = old {
+                        writeln!(output, "-{old}").unwrap();
+                    }
+                    if let Some(new) = new {
+                        writeln!(output, "+{new}").unwrap();
+                    }
+                }
+            }
+        }
+        line = end;
+    }
+    output
+}
+
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ld_r_r_trace() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        assert_golden("test_roms/golden/06-ld r,r.txt", &trace(rom, 500));
+    }
+
+    #[test]
+    fn test_diff() {
+        let expected = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let actual = "a\nb\nc\nd\ne\nF\ng\nh\ni\nj\nk\n";
+        assert_eq!(
+            diff(expected, actual),
+            "--- expected\n+++ actual\n@@ -3,8 +3,9 @@\n c\n d\n e\n-f\n+F\n g\n h\n i\n j\n+k\n"
+        );
+    }
+}
@@ -1,10 +1,20 @@
 #![allow(dead_code)]
 
-use bitvec::{BitArr, array::BitArray, order::Lsb0};
-use enumflags2::{BitFlags, bitflags};
+use std::ops::Range;
+
+use bitvec::array::BitArray;
+use enumflags2::{BitFlag, BitFlags, bitflags};
 use num_derive::FromPrimitive;
 
-use crate::gpu::tile::{ColourIndex, Tile, TileRow, empty_tile};
+use crate::{
+    cpu::memorybus::{InterruptFlag, io::IoDevice},
+    gpu::{
+        cgb::TileAttributes,
+        palette::{CgbPalettes, DmgPalettes, PaletteProvider},
+        tile::{ColourIndex, Tile, TileRow, empty_tile},
+    },
+    state::{Snapshot, StateError, StateReader, StateWriter},
+};
 
 pub const VRAM_BEGIN: usize = 0x8000;
 pub const VRAM_END: usize = 0x9FFF;
@@ -17,6 +27,11 @@ pub const OAM_SIZE: usize = OAM_END - OAM_BEGIN + 1;
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
 
+/// Hardware limit on how many sprites can be drawn on a single line
+pub const SPRITES_PER_LINE: usize = 10;
+
+pub mod cgb;
+pub mod palette;
 pub mod tile;
 
 #[derive(Debug, Clone, Copy)]
@@ -56,20 +71,108 @@ pub enum LCDControl {
     BackgroundEnabled = 1 << 0,
 }
 
+/// The sources which can raise the STAT interrupt
+#[bitflags]
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatInterrupt {
+    LineCompare = 1 << 6,
+    OamScan = 1 << 5,
+    VBlank = 1 << 4,
+    HBlank = 1 << 3,
+}
+
+#[bitflags]
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpriteFlag {
+    /// Background colours 1-3 are drawn over the sprite
+    BehindBackground = 1 << 7,
+    YFlip = 1 << 6,
+    XFlip = 1 << 5,
+    /// On = OBP1, Off = OBP0
+    Palette = 1 << 4,
+}
+
+/// A copy of the PPU's state for debugging tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpuDebug {
+    pub mode: Mode,
+    pub line: u8,
+    /// Cycles spent in the current mode
+    pub cycles: u16,
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+    pub window_x: u8,
+    pub window_y: u8,
+    pub lcd_control: BitFlags<LCDControl>,
+    pub stat_interrupts: BitFlags<StatInterrupt>,
+    pub line_compare: u8,
+}
+
+/// A decoded OAM entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sprite {
+    /// Position in OAM
+    pub index: usize,
+    /// Screen Y position + 16
+    pub y: u8,
+    /// Screen X position + 8
+    pub x: u8,
+    pub tile: u8,
+    pub flags: BitFlags<SpriteFlag>,
+}
+
 #[derive(Debug)]
 pub struct Gpu {
-    vram: [u8; VRAM_SIZE],
+    /// Only the first bank is used on DMG
+    vram: [[u8; VRAM_SIZE]; 2],
     oam: [u8; OAM_SIZE],
-    tile_set: [Tile; 384],
+    tile_set: [[Tile; 384]; 2],
+    /// VBK, which bank of VRAM the CPU sees
+    vram_bank: u8,
     pub buffer: Box<[u8; WIDTH * HEIGHT * 3]>,
     cycles: u16,
+    /// How long the current line's Drawing mode lasts, `HBlank` takes up the rest of the line
+    drawing_cycles: u16,
     pub line: u8,
     pub mode: Mode,
+    /// Frames finished since power on, counted on entering `VBlank` and when the LCD turns off and
+    /// blanks the screen. Front ends present once each time this changes, and can tell they've
+    /// missed a frame if it jumps by more than one
+    pub frames: u64,
 
     pub lcd_control: BitFlags<LCDControl>,
-    pub background_colours: BitArr!(for 8, in u8, Lsb0),
+    pub palettes: DmgPalettes,
+    /// Draw the background with the tile attributes in VRAM bank 1 and colour palettes
+    pub cgb_mode: bool,
+    pub cgb_palettes: CgbPalettes,
     pub scroll_y: u8,
     pub scroll_x: u8,
+    /// WY
+    pub window_y: u8,
+    /// WX, which is offset by 7
+    pub window_x: u8,
+    /// Which line of the window is drawn next. It only moves on when the window is drawn, so
+    /// hiding it partway down the screen doesn't skip any of it
+    window_line: u8,
+    /// STAT bits 3-6
+    pub stat_interrupts: BitFlags<StatInterrupt>,
+    /// LYC
+    pub line_compare: u8,
+    /// The STAT interrupt only fires when this goes from low to high, so overlapping sources
+    /// block each other
+    stat_line: bool,
+
+    /// Draw pixels as Drawing goes on instead of the whole line at once, so mid-line register
+    /// writes show up
+    pub per_pixel_rendering: bool,
+    /// How much of the current line `per_pixel_rendering` has drawn
+    pixels_drawn: usize,
+
+    /// How many sprites are drawn per line. Raising this above [`SPRITES_PER_LINE`] gets rid of
+    /// flicker in sprite heavy games.
+    pub sprite_limit: usize,
 }
 
 trait LCDExt {
@@ -106,50 +209,88 @@ impl LCDExt for BitFlags<LCDControl> {
 impl Default for Gpu {
     fn default() -> Self {
         Self {
-            vram: [0; VRAM_SIZE],
+            vram: [[0; VRAM_SIZE]; 2],
             oam: [0; OAM_SIZE],
-            tile_set: [empty_tile(); 384],
+            tile_set: [[empty_tile(); 384]; 2],
+            vram_bank: 0,
             buffer: vec![0; WIDTH * HEIGHT * 3]
                 .into_boxed_slice()
                 .try_into()
                 .unwrap(),
             cycles: 0,
+            drawing_cycles: 172,
             line: 0,
             mode: Mode::HBlank,
+            frames: 0,
             lcd_control: BitFlags::EMPTY,
-            background_colours: BitArray::ZERO,
+            palettes: DmgPalettes::default(),
+            cgb_mode: false,
+            cgb_palettes: CgbPalettes::default(),
             scroll_y: 0,
             scroll_x: 0,
+            window_y: 0,
+            window_x: 0,
+            window_line: 0,
+            stat_interrupts: BitFlags::EMPTY,
+            line_compare: 0,
+            stat_line: false,
+            per_pixel_rendering: false,
+            pixels_drawn: 0,
+            sprite_limit: SPRITES_PER_LINE,
         }
     }
 }
 
 impl Gpu {
-    pub fn step(&mut self, cycles: u8) {
+    pub fn new(cgb_mode: bool) -> Self {
+        Self {
+            cgb_mode,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the interrupts which should be triggered
+    pub fn step(&mut self, cycles: u8) -> BitFlags<InterruptFlag> {
         if !self.lcd_control.contains(LCDControl::DisplayEnabled) {
-            return;
+            return BitFlags::EMPTY;
         }
+        let mut interrupts = BitFlags::EMPTY;
         self.cycles = self.cycles.wrapping_add(u16::from(cycles));
         match self.mode {
             Mode::OamScan => {
                 if self.cycles >= 80 {
                     self.cycles %= 80;
                     self.mode = Mode::Drawing;
+                    self.drawing_cycles = self.drawing_duration();
                 }
             }
             Mode::Drawing => {
-                if self.cycles >= 172 {
-                    self.cycles %= 172;
+                if self.cycles >= self.drawing_cycles {
+                    self.cycles -= self.drawing_cycles;
                     self.mode = Mode::HBlank;
-                    self.render_line();
+                    self.render_span(self.pixels_drawn..WIDTH);
+                    self.pixels_drawn = 0;
+                    if self.window_on_line() {
+                        self.window_line = self.window_line.wrapping_add(1);
+                    }
+                } else if self.per_pixel_rendering {
+                    // roughly a pixel a cycle, after fetching the first tiles
+                    let drawn = usize::from(self.cycles.saturating_sub(12)).min(WIDTH);
+                    self.render_span(self.pixels_drawn..drawn);
+                    self.pixels_drawn = drawn;
                 }
             }
             Mode::HBlank => {
-                if self.cycles >= 204 {
-                    self.cycles %= 204;
+                // the whole line always takes 456 cycles, so HBlank shrinks as Drawing grows
+                let hblank_cycles = 456 - 80 - self.drawing_cycles;
+                if self.cycles >= hblank_cycles {
+                    self.cycles -= hblank_cycles;
                     self.line += 1;
                     if self.line >= 144 {
                         self.mode = Mode::VBlank;
+                        self.frames += 1;
+                        self.window_line = 0;
+                        interrupts |= InterruptFlag::VBlank;
                     } else {
                         self.mode = Mode::OamScan;
                     }
@@ -167,13 +308,182 @@ impl Gpu {
                 }
             }
         }
+
+        interrupts | self.update_stat_line()
+    }
+
+    pub fn read_stat(&self) -> u8 {
+        let mode = if self.lcd_enabled() {
+            self.mode as u8
+        } else {
+            0
+        };
+        let coincidence = u8::from(self.line == self.line_compare) << 2;
+        0b1000_0000 | self.stat_interrupts.bits() | coincidence | mode
+    }
+
+    /// Returns the interrupts which should be triggered. With `write_bug`, this behaves like a
+    /// DMG, where every source is briefly enabled during the write and can fire a spurious
+    /// interrupt in `HBlank`, `VBlank` or when LY=LYC
+    pub fn write_stat(&mut self, value: u8, write_bug: bool) -> BitFlags<InterruptFlag> {
+        let mut interrupts = BitFlags::EMPTY;
+        if write_bug {
+            self.stat_interrupts =
+                StatInterrupt::HBlank | StatInterrupt::VBlank | StatInterrupt::LineCompare;
+            interrupts |= self.update_stat_line();
+        }
+        self.stat_interrupts = BitFlags::from_bits_truncate(value);
+        interrupts | self.update_stat_line()
     }
-    pub const fn read_vram(&self, index: usize) -> u8 {
-        self.vram[index]
+
+    /// Returns the interrupts which should be triggered
+    pub fn write_line_compare(&mut self, value: u8) -> BitFlags<InterruptFlag> {
+        self.line_compare = value;
+        self.update_stat_line()
+    }
+
+    fn update_stat_line(&mut self) -> BitFlags<InterruptFlag> {
+        let sources = self.stat_interrupts;
+        let line = self.lcd_enabled()
+            && match self.mode {
+                Mode::HBlank => sources.contains(StatInterrupt::HBlank),
+                Mode::VBlank => sources.contains(StatInterrupt::VBlank),
+                Mode::OamScan => sources.contains(StatInterrupt::OamScan),
+                Mode::Drawing => false,
+            }
+            || self.lcd_enabled()
+                && sources.contains(StatInterrupt::LineCompare)
+                && self.line == self.line_compare;
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        if rising {
+            InterruptFlag::LcdStat.into()
+        } else {
+            BitFlags::EMPTY
+        }
+    }
+
+    pub const fn debug_info(&self) -> GpuDebug {
+        GpuDebug {
+            mode: self.mode,
+            line: self.line,
+            cycles: self.cycles,
+            scroll_x: self.scroll_x,
+            scroll_y: self.scroll_y,
+            window_x: self.window_x,
+            window_y: self.window_y,
+            lcd_control: self.lcd_control,
+            stat_interrupts: self.stat_interrupts,
+            line_compare: self.line_compare,
+        }
+    }
+
+    /// A stable FNV-1a hash of the framebuffer, so tests can check a frame without storing it
+    pub fn frame_hash(&self) -> u64 {
+        self.buffer
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcd_control.contains(LCDControl::DisplayEnabled)
+    }
+
+    pub fn set_lcd_control(&mut self, lcd_control: BitFlags<LCDControl>) {
+        let was_enabled = self.lcd_control.contains(LCDControl::DisplayEnabled);
+        self.lcd_control = lcd_control;
+        if was_enabled && !lcd_control.contains(LCDControl::DisplayEnabled) {
+            // the screen goes blank while the LCD is off, which is the lightest shade on DMG and
+            // white on CGB
+            let blank: [u8; 3] = if self.cgb_mode {
+                [255; 3]
+            } else {
+                self.palettes.shades.0[0].into()
+            };
+            for pixel in self.buffer.chunks_exact_mut(3) {
+                pixel.copy_from_slice(&blank);
+            }
+            // nothing else finishes a frame while the LCD is off, so the blank screen counts
+            // as one
+            self.frames += 1;
+        }
+    }
+
+    /// Drawing takes 172 cycles, plus the pixels discarded from SCX fine scrolling, plus a
+    /// penalty for every sprite fetched on this line
+    #[allow(clippy::cast_possible_truncation)]
+    fn drawing_duration(&self) -> u16 {
+        let sprites = self.sprites_on_line().take(SPRITES_PER_LINE).count() as u16;
+        172 + u16::from(self.scroll_x % 8) + 6 * sprites
+    }
+
+    pub fn sprite_height(&self) -> u8 {
+        if self.lcd_control.contains(LCDControl::TallSprites) {
+            16
+        } else {
+            8
+        }
+    }
+
+    pub fn sprites(&self) -> impl Iterator<Item = Sprite> + '_ {
+        self.oam
+            .chunks_exact(4)
+            .enumerate()
+            .map(|(index, sprite)| Sprite {
+                index,
+                y: sprite[0],
+                x: sprite[1],
+                tile: sprite[2],
+                flags: BitFlags::from_bits_truncate(sprite[3]),
+            })
+    }
+
+    /// Every OAM entry decoded, for debuggers
+    pub fn oam_sprites(&self) -> [Sprite; OAM_SIZE / 4] {
+        let mut sprites = self.sprites();
+        std::array::from_fn(|_| sprites.next().unwrap())
+    }
+
+    /// Sprites overlapping the current line, in OAM order
+    fn sprites_on_line(&self) -> impl Iterator<Item = Sprite> + '_ {
+        let enabled = self.lcd_control.contains(LCDControl::SpritesEnabled);
+        // sprite y positions are offset by 16 so they can be scrolled in from the top
+        let line = u16::from(self.line) + 16;
+        let height = u16::from(self.sprite_height());
+        self.sprites().filter(move |sprite| {
+            let y = u16::from(sprite.y);
+            enabled && (y..y + height).contains(&line)
+        })
+    }
+
+    /// Whether the window covers any of the current line. WX values past 166 put it entirely
+    /// off the right of the screen
+    fn window_on_line(&self) -> bool {
+        self.lcd_control.contains(LCDControl::WindowEnabled)
+            && self.line >= self.window_y
+            && self.window_x < 167
+    }
+
+    /// The CPU can't access VRAM while the PPU is drawing from it
+    pub fn vram_accessible(&self) -> bool {
+        !self.lcd_control.contains(LCDControl::DisplayEnabled) || self.mode != Mode::Drawing
+    }
+
+    /// The CPU can't access OAM while the PPU is scanning or drawing from it
+    pub fn oam_accessible(&self) -> bool {
+        !self.lcd_control.contains(LCDControl::DisplayEnabled)
+            || matches!(self.mode, Mode::HBlank | Mode::VBlank)
+    }
+
+    pub fn read_vram(&self, index: usize) -> u8 {
+        self.vram[usize::from(self.vram_bank)][index]
     }
 
     pub fn write_vram(&mut self, index: usize, value: u8) {
-        self.vram[index] = value;
+        let bank = usize::from(self.vram_bank);
+        self.vram[bank][index] = value;
         // if we're not writing to the tile set storage, return early
         if index >= 0x1800 {
             return;
@@ -184,7 +494,7 @@ impl Gpu {
         let normalized_index = index & (!1);
 
         let tile_row = TileRow::from_bytes(
-            self.vram[normalized_index..=normalized_index + 1]
+            self.vram[bank][normalized_index..=normalized_index + 1]
                 .try_into()
                 .unwrap(),
         );
@@ -192,13 +502,18 @@ impl Gpu {
         let tile_index = index / 16;
         let row_index = (index % 16) / 2;
 
-        self.tile_set[tile_index][row_index] = tile_row;
+        self.tile_set[bank][tile_index][row_index] = tile_row;
+    }
+
+    pub const fn read_vram_bank(&self) -> u8 {
+        0b1111_1110 | self.vram_bank
+    }
+
+    pub const fn write_vram_bank(&mut self, value: u8) {
+        self.vram_bank = value & 1;
     }
 
     pub const fn read_oam(&self, address: usize) -> u8 {
-        if !matches!(self.mode, Mode::HBlank | Mode::VBlank) {
-            return 0xFF;
-        }
         self.oam[address]
     }
 
@@ -206,54 +521,272 @@ impl Gpu {
         self.oam[address] = value;
     }
 
-    #[allow(clippy::similar_names)]
+    /// The DMG's OAM bug, for a write like a 16 bit INC or DEC of a register pointing into OAM.
+    /// During OAM scan the row of 8 bytes the PPU is reading gets mixed with the row before it
+    pub fn corrupt_oam_row(&mut self) {
+        if !self.lcd_control.contains(LCDControl::DisplayEnabled) || self.mode != Mode::OamScan {
+            return;
+        }
+        // one row is read every 4 cycles, and the first row is never corrupted
+        let row = usize::from(self.cycles / 4);
+        if row == 0 || row >= OAM_SIZE / 8 {
+            return;
+        }
+        let current = row * 8;
+        let previous = current - 8;
+        let word = |index: usize| u16::from_le_bytes([self.oam[index], self.oam[index + 1]]);
+        let (a, b, c) = (word(current), word(previous), word(previous + 4));
+        let first = ((a ^ c) & (b ^ c)) ^ c;
+        self.oam[current..current + 2].copy_from_slice(&first.to_le_bytes());
+        self.oam.copy_within(previous + 2..current, current + 2);
+    }
+
     fn render_line(&mut self) {
-        let lookup_colour = |pixel: ColourIndex| -> (u8, u8, u8) {
-            let bit = pixel as usize * 2;
-            let value = &self.background_colours[bit..=bit + 1];
-            let value = u8::from(value[0]) << 1 | u8::from(value[1]);
-            match value {
-                0 => (255, 255, 255),
-                1 => (170, 170, 170),
-                2 => (85, 85, 85),
-                3 => (0, 0, 0),
-                _ => unreachable!(),
+        self.render_span(0..WIDTH);
+    }
+
+    /// Draws `columns` of the current line using the registers as they are right now
+    #[allow(clippy::cast_possible_truncation)]
+    fn render_span(&mut self, columns: Range<usize>) {
+        let y = self.line.wrapping_add(self.scroll_y);
+        let tile_map = self.lcd_control.bg_tilemap_address() - VRAM_BEGIN + 32 * usize::from(y / 8);
+        let sprites = self.line_sprite_pixels();
+        let window_map = self.window_on_line().then(|| {
+            self.lcd_control.window_tilemap_address() - VRAM_BEGIN
+                + 32 * usize::from(self.window_line / 8)
+        });
+
+        // on DMG LCDC bit 0 blanks the background and window, on CGB it only takes away their
+        // priority over sprites
+        let background_enabled = self.lcd_control.contains(LCDControl::BackgroundEnabled);
+
+        let line = usize::from(self.line);
+        let palettes: &dyn PaletteProvider = if self.cgb_mode {
+            &self.cgb_palettes
+        } else {
+            &self.palettes
+        };
+        for x in columns {
+            // WX is offset by 7, so below 7 the start of the window is cut off by the left edge
+            let window_x = (x + 7).checked_sub(usize::from(self.window_x));
+            let (background, attributes) = if !background_enabled && !self.cgb_mode {
+                (0, TileAttributes::default())
+            } else if let Some((window_map, window_x)) = window_map.zip(window_x) {
+                let window_x = window_x as u8;
+                let map_index = window_map + usize::from(window_x / 8);
+                self.background_colour(map_index, window_x, self.window_line)
+            } else {
+                // the background map is 256 pixels wide and wraps around
+                let background_x = self.scroll_x.wrapping_add(x as u8);
+                let map_index = tile_map + usize::from(background_x / 8);
+                self.background_colour(map_index, background_x, y)
+            };
+
+            let rgb: [u8; 3] = match sprites[x] {
+                Some((colour, flags))
+                    if background == 0
+                        || !background_enabled
+                        || !flags.contains(SpriteFlag::BehindBackground)
+                            && !attributes.priority() =>
+                {
+                    // FIXME: CGB sprites should use OAM bits 0-3 for their palette and bank
+                    let palette = u8::from(flags.contains(SpriteFlag::Palette));
+                    palettes.sprite(palette, colour)
+                }
+                _ => palettes.background(attributes.palette().value(), background),
             }
+            .into();
+            let pixel = (line * WIDTH + x) * 3;
+            self.buffer[pixel..pixel + 3].copy_from_slice(&rgb);
+        }
+    }
+
+    /// The colour of a background pixel, and the attributes of its tile which are all zero on DMG
+    fn background_colour(
+        &self,
+        map_index: usize,
+        background_x: u8,
+        y: u8,
+    ) -> (ColourIndex, TileAttributes) {
+        let tile_number = self.vram[0][map_index];
+        let attributes = if self.cgb_mode {
+            TileAttributes::from(self.vram[1][map_index])
+        } else {
+            TileAttributes::default()
+        };
+        let row = if attributes.y_flip() {
+            7 - y % 8
+        } else {
+            y % 8
         };
-        let tile_x_coordinate = usize::from(self.scroll_x / 8); // FIXME: Wrapping might be broken
-        let tile_y_coordinate = self.line.wrapping_add(self.scroll_y);
-        let background_tile_map = self.lcd_control.bg_tilemap_address();
-        let offset = 32 * (usize::from(tile_y_coordinate) / 8);
-        let address = background_tile_map - VRAM_BEGIN + offset;
+        let column = if attributes.x_flip() {
+            7 - background_x % 8
+        } else {
+            background_x % 8
+        };
+        let tile_row = self.tile_set[usize::from(attributes.bank())][usize::from(tile_number)]
+            [usize::from(row)];
+        (tile_row.get_colour(column), attributes)
+    }
 
-        let pixels = self.vram[address + tile_x_coordinate..]
-            .iter()
-            .map(|tile_number| {
-                &self.tile_set[usize::from(*tile_number)][usize::from(tile_y_coordinate) % 8]
-            })
-            .flat_map(|row| row.iter())
-            .skip(usize::from(self.scroll_x) % 8);
+    /// The opaque sprite pixel which wins at each x position on the current line
+    fn line_sprite_pixels(&self) -> [Option<(ColourIndex, BitFlags<SpriteFlag>)>; WIDTH] {
+        let mut pixels = [None; WIDTH];
 
-        self.buffer
-            .chunks_exact_mut(3)
-            .skip(self.line as usize * WIDTH)
-            .take(WIDTH)
-            .zip(pixels)
-            .for_each(|(buf, pixel)| {
-                let (r, g, b) = lookup_colour(pixel);
-                buf[0] = r;
-                buf[1] = g;
-                buf[2] = b;
-            });
+        // sprites are picked in OAM order, and any past the limit aren't drawn at all
+        let mut sprites: Vec<Sprite> = self.sprites_on_line().take(self.sprite_limit).collect();
+        // on DMG the sprite with the lowest X is drawn on top, with ties going to OAM order
+        sprites.sort_by_key(|sprite| sprite.x);
+
+        let height = self.sprite_height();
+        for sprite in sprites {
+            let mut row = self.line + 16 - sprite.y;
+            if sprite.flags.contains(SpriteFlag::YFlip) {
+                row = height - 1 - row;
+            }
+            let tile = if height == 16 {
+                (sprite.tile & !1) + row / 8
+            } else {
+                sprite.tile
+            };
+            let tile_row = self.tile_set[0][usize::from(tile)][usize::from(row % 8)];
+
+            for column in 0..8 {
+                let Some(x) = (usize::from(sprite.x) + usize::from(column))
+                    .checked_sub(8)
+                    .filter(|x| *x < WIDTH)
+                else {
+                    continue;
+                };
+                let column = if sprite.flags.contains(SpriteFlag::XFlip) {
+                    7 - column
+                } else {
+                    column
+                };
+                let colour = tile_row.get_colour(column);
+                // colour 0 is transparent, so sprites underneath can still show through
+                if pixels[x].is_none() && colour != 0 {
+                    pixels[x] = Some((colour, sprite.flags));
+                }
+            }
+        }
+
+        pixels
+    }
+}
+
+/// The LCD registers. STAT and LYC writes can raise an interrupt, so the bus handles those itself
+impl IoDevice for Gpu {
+    fn read(&self, address: usize) -> u8 {
+        match address {
+            0xFF40 => self.lcd_control.bits(),
+            0xFF41 => self.read_stat(),
+            0xFF42 => self.scroll_y,
+            0xFF43 => self.scroll_x,
+            0xFF44 => self.line,
+            0xFF45 => self.line_compare,
+            0xFF47 => self.palettes.background.data[0],
+            0xFF48 => self.palettes.sprites[0].data[0],
+            0xFF49 => self.palettes.sprites[1].data[0],
+            0xFF4A => self.window_y,
+            0xFF4B => self.window_x,
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u8) {
+        match address {
+            0xFF40 => self.set_lcd_control(LCDControl::from_bits_truncate(value)),
+            0xFF42 => self.scroll_y = value,
+            0xFF43 => self.scroll_x = value,
+            0xFF47 => self.palettes.background = BitArray::new([value]),
+            0xFF48 => self.palettes.sprites[0] = BitArray::new([value]),
+            0xFF49 => self.palettes.sprites[1] = BitArray::new([value]),
+            0xFF4A => self.window_y = value,
+            0xFF4B => self.window_x = value,
+            _ => {}
+        }
+    }
+}
+
+/// The framebuffer isn't saved, and is filled back in as the next frame is drawn
+impl Snapshot for Gpu {
+    fn save(&self, state: &mut StateWriter) {
+        for bank in &self.vram {
+            state.bytes(bank);
+        }
+        state.u8(self.vram_bank);
+        state.bytes(&self.oam);
+        state.u16(self.cycles);
+        state.u16(self.drawing_cycles);
+        state.u8(self.line);
+        state.u8(self.mode as u8);
+        state.u8(self.lcd_control.bits());
+        state.u8(self.palettes.background.data[0]);
+        state.u8(self.palettes.sprites[0].data[0]);
+        state.u8(self.palettes.sprites[1].data[0]);
+        self.cgb_palettes.background.save(state);
+        self.cgb_palettes.sprites.save(state);
+        state.u8(self.scroll_y);
+        state.u8(self.scroll_x);
+        state.u8(self.window_y);
+        state.u8(self.window_x);
+        state.u8(self.window_line);
+        state.u8(self.stat_interrupts.bits());
+        state.u8(self.line_compare);
+        state.bool(self.stat_line);
+        state.usize(self.pixels_drawn);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        // written through write_vram so the decoded tiles are rebuilt
+        let mut vram = [0; VRAM_SIZE];
+        for bank in 0..2 {
+            state.bytes(&mut vram)?;
+            self.vram_bank = bank;
+            for (index, value) in vram.iter().enumerate() {
+                self.write_vram(index, *value);
+            }
+        }
+        self.write_vram_bank(state.u8()?);
+        state.bytes(&mut self.oam)?;
+        self.cycles = state.u16()?;
+        self.drawing_cycles = state.u16()?;
+        self.line = state.u8()?;
+        self.mode = match state.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            3 => Mode::Drawing,
+            _ => return Err(StateError::Invalid("PPU mode")),
+        };
+        self.lcd_control = BitFlags::from_bits_truncate(state.u8()?);
+        self.palettes.background = BitArray::new([state.u8()?]);
+        self.palettes.sprites[0] = BitArray::new([state.u8()?]);
+        self.palettes.sprites[1] = BitArray::new([state.u8()?]);
+        self.cgb_palettes.background.load(state)?;
+        self.cgb_palettes.sprites.load(state)?;
+        self.scroll_y = state.u8()?;
+        self.scroll_x = state.u8()?;
+        self.window_y = state.u8()?;
+        self.window_x = state.u8()?;
+        self.window_line = state.u8()?;
+        self.stat_interrupts = BitFlags::from_bits_truncate(state.u8()?);
+        self.line_compare = state.u8()?;
+        self.stat_line = state.bool()?;
+        self.pixels_drawn = state.usize()?.min(WIDTH);
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use bitvec::array::BitArray;
     use num_traits::ToPrimitive;
     use std::fmt::Write as _;
 
     use super::*;
+    use crate::gpu::palette::{ParseShadesError, Shades};
 
     #[test]
     fn test_tilerow() {
@@ -273,4 +806,444 @@ mod test {
             "1111111123333332300001033000102330010213301021233102122323333332"
         );
     }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    fn test_oam_bug_write_corruption() {
+        let mut gpu = Gpu {
+            lcd_control: LCDControl::DisplayEnabled.into(),
+            mode: Mode::OamScan,
+            // the PPU is reading row 2
+            cycles: 8,
+            ..Gpu::default()
+        };
+        for (index, byte) in gpu.oam.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        gpu.corrupt_oam_row();
+
+        // ((a ^ c) & (b ^ c)) ^ c with a = $1110, b = $0908, c = $0D0C
+        let expected = [0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F];
+        assert_eq!(gpu.oam[0x10..0x18], expected);
+        assert_eq!(gpu.oam[0x08..0x10], [8, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(gpu.oam[0x18], 0x18);
+
+        // nothing happens outside of OAM scan
+        gpu.mode = Mode::HBlank;
+        gpu.oam[0x10] = 0x10;
+        gpu.corrupt_oam_row();
+        assert_eq!(gpu.oam[0x10], 0x10);
+    }
+
+    #[test]
+    fn test_frame_counter() {
+        let mut gpu = Gpu {
+            lcd_control: LCDControl::DisplayEnabled.into(),
+            mode: Mode::OamScan,
+            ..Gpu::default()
+        };
+        let run = |gpu: &mut Gpu, lines: u32| {
+            for _ in 0..lines * 456 / 4 {
+                gpu.step(4);
+            }
+        };
+
+        run(&mut gpu, 143);
+        assert_eq!(gpu.frames, 0);
+        run(&mut gpu, 1);
+        assert_eq!(gpu.frames, 1);
+        run(&mut gpu, 10);
+        assert_eq!((gpu.line, gpu.frames), (0, 1));
+        run(&mut gpu, 154);
+        assert_eq!(gpu.frames, 2);
+    }
+
+    /// Renders a frame of noisy VRAM and OAM through every palette register
+    fn render_test_frame(gpu: &mut Gpu) {
+        gpu.lcd_control =
+            LCDControl::DisplayEnabled | LCDControl::SpritesEnabled | LCDControl::BackgroundEnabled;
+        gpu.scroll_x = 3;
+        gpu.scroll_y = 5;
+        let mut value = 11u8;
+        for index in 0..VRAM_SIZE {
+            gpu.write_vram(index, value);
+            value = value.wrapping_add(37);
+        }
+        for index in 0..OAM_SIZE {
+            gpu.write_oam(index, value);
+            value = value.wrapping_add(29);
+        }
+        for line in 0..HEIGHT {
+            gpu.line = line.try_into().unwrap();
+            gpu.render_line();
+        }
+    }
+
+    #[test]
+    fn test_dmg_palettes() {
+        let mut gpu = Gpu {
+            palettes: DmgPalettes {
+                background: BitArray::new([0xE4]),
+                sprites: [BitArray::new([0xD2]), BitArray::new([0x1B])],
+                ..DmgPalettes::default()
+            },
+            ..Gpu::default()
+        };
+        render_test_frame(&mut gpu);
+        assert_eq!(gpu.frame_hash(), 14_036_598_868_960_477_442);
+    }
+
+    #[test]
+    fn test_custom_shades() {
+        let shades = "#9BBC0F\n8BAC0F ; light\n\n306230\n0F380F\n";
+        let mut gpu = Gpu {
+            lcd_control: LCDControl::DisplayEnabled
+                | LCDControl::TileDataSelect
+                | LCDControl::BackgroundEnabled,
+            palettes: DmgPalettes {
+                background: BitArray::new([0xE4]),
+                shades: shades.parse().unwrap(),
+                ..DmgPalettes::default()
+            },
+            ..Gpu::default()
+        };
+        // the first 4 pixels of tile 0 are colours 0, 1, 2 and 3
+        gpu.write_vram(0, 0b0101_0000);
+        gpu.write_vram(1, 0b0011_0000);
+
+        gpu.render_line();
+        assert_eq!(
+            gpu.buffer[..12],
+            [
+                0x9B, 0xBC, 0x0F, 0x8B, 0xAC, 0x0F, 0x30, 0x62, 0x30, 0x0F, 0x38, 0x0F
+            ]
+        );
+
+        assert_eq!(
+            "9BBC0F\n8BAC0F\n306230".parse::<Shades>(),
+            Err(ParseShadesError::WrongCount(3))
+        );
+        assert_eq!(
+            "9BBC0F\ngreen".parse::<Shades>(),
+            Err(ParseShadesError::InvalidColour(2))
+        );
+    }
+
+    #[test]
+    fn test_fine_scroll() {
+        let render = |scroll_x, scroll_y| {
+            let mut gpu = Gpu {
+                lcd_control: LCDControl::DisplayEnabled
+                    | LCDControl::TileDataSelect
+                    | LCDControl::BackgroundEnabled,
+                palettes: DmgPalettes {
+                    background: BitArray::new([0xE4]),
+                    ..DmgPalettes::default()
+                },
+                scroll_x,
+                scroll_y,
+                ..Gpu::default()
+            };
+            // noisy tiles, and a map using them in no particular order
+            let mut value = 11u8;
+            for index in 0..0x1C00 {
+                gpu.write_vram(index, value);
+                value = value.wrapping_add(37);
+            }
+            for line in 0..HEIGHT {
+                gpu.line = line.try_into().unwrap();
+                gpu.render_line();
+            }
+            gpu.buffer.to_vec()
+        };
+
+        let still = render(0, 0);
+        let scrolled = render(3, 3);
+        let pixel = |buffer: &[u8], x: usize, y: usize| buffer[(y * WIDTH + x) * 3];
+        for y in 0..HEIGHT - 3 {
+            for x in 0..WIDTH - 3 {
+                assert_eq!(
+                    pixel(&scrolled, x, y),
+                    pixel(&still, x + 3, y + 3),
+                    "({x}, {y})"
+                );
+            }
+        }
+        assert_ne!(scrolled, still);
+    }
+
+    #[test]
+    fn test_window_x_edges() {
+        let render = |window_x| {
+            let mut gpu = Gpu {
+                lcd_control: LCDControl::DisplayEnabled
+                    | LCDControl::BackgroundEnabled
+                    | LCDControl::WindowEnabled
+                    | LCDControl::WindowTileMap
+                    | LCDControl::TileDataSelect,
+                palettes: DmgPalettes {
+                    background: BitArray::new([0xE4]),
+                    ..DmgPalettes::default()
+                },
+                window_x,
+                ..Gpu::default()
+            };
+            // the window is all tile 1, which is colour 1 apart from its last column in colour 3
+            gpu.write_vram(0x10, 0xFF);
+            gpu.write_vram(0x11, 0x01);
+            for index in 0x1C00..0x1C20 {
+                gpu.write_vram(index, 1);
+            }
+            gpu.render_line();
+            (0..WIDTH).map(|x| gpu.buffer[x * 3]).collect::<Vec<_>>()
+        };
+        let (background, window, window_edge) = (255, 170, 0);
+
+        let line = render(7);
+        assert_eq!(
+            line[..9],
+            [
+                window,
+                window,
+                window,
+                window,
+                window,
+                window,
+                window,
+                window_edge,
+                window
+            ]
+        );
+        // the first 7 columns of the window are off the left of the screen
+        let line = render(0);
+        assert_eq!(line[..2], [window_edge, window]);
+        let line = render(166);
+        assert_eq!(line[WIDTH - 2..], [background, window]);
+        let line = render(167);
+        assert!(line.iter().all(|&shade| shade == background));
+    }
+
+    #[test]
+    fn test_cgb_background_attributes() {
+        let mut gpu = Gpu {
+            lcd_control: LCDControl::DisplayEnabled.into(),
+            cgb_mode: true,
+            ..Gpu::default()
+        };
+        // palette 2, colour 1 is pure red
+        gpu.cgb_palettes.background.data[2 * 8 + 2] = 0x1F;
+        gpu.write_vram_bank(1);
+        // tile 0 in bank 1 is solid colour 1, and the first tile on the map uses it with palette 2
+        for index in (0..0x10).step_by(2) {
+            gpu.write_vram(index, 0xFF);
+        }
+        gpu.write_vram(0x1800, 0b0000_1010);
+        gpu.write_vram_bank(0);
+
+        gpu.render_line();
+        assert_eq!(gpu.buffer[..3], [0xFF, 0, 0]);
+        // the next tile is tile 0 from bank 0, which is colour 0 of palette 0
+        assert_eq!(gpu.buffer[8 * 3..9 * 3], [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_mid_line_scroll() {
+        let render = |per_pixel_rendering| {
+            let mut gpu = Gpu {
+                lcd_control: LCDControl::DisplayEnabled | LCDControl::BackgroundEnabled,
+                palettes: DmgPalettes {
+                    background: BitArray::new([0xE4]),
+                    ..DmgPalettes::default()
+                },
+                mode: Mode::OamScan,
+                per_pixel_rendering,
+                ..Gpu::default()
+            };
+            // tile 1 is solid colour 3, and fills the right half of the background
+            for index in 0x10..0x20 {
+                gpu.write_vram(index, 0xFF);
+            }
+            for index in 0x1810..0x1820 {
+                gpu.write_vram(index, 1);
+            }
+            for _ in 0..(80 + 80) / 4 {
+                gpu.step(4);
+            }
+            gpu.scroll_x = 128;
+            while gpu.mode == Mode::Drawing {
+                gpu.step(4);
+            }
+            let shade = |x: usize| gpu.buffer[x * 3];
+            (shade(10), shade(100))
+        };
+
+        assert_eq!(render(false), (0, 0));
+        // the left of the line was drawn before the write
+        assert_eq!(render(true), (255, 0));
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let mut gpu = Gpu {
+            lcd_control: LCDControl::DisplayEnabled | LCDControl::SpritesEnabled,
+            palettes: DmgPalettes {
+                sprites: [BitArray::new([0xFF]); 2],
+                ..DmgPalettes::default()
+            },
+            ..Gpu::default()
+        };
+        // tile 1 is solid colour 3
+        for index in 0x10..0x20 {
+            gpu.write_vram(index, 0xFF);
+        }
+        // 12 sprites side by side on line 0
+        for (sprite, x) in (8..).step_by(8).take(12).enumerate() {
+            gpu.write_oam(sprite * 4, 16);
+            gpu.write_oam(sprite * 4 + 1, x);
+            gpu.write_oam(sprite * 4 + 2, 1);
+        }
+        let black_pixels = |gpu: &Gpu| {
+            gpu.buffer[..WIDTH * 3]
+                .chunks_exact(3)
+                .filter(|rgb| rgb == &[0, 0, 0])
+                .count()
+        };
+
+        gpu.render_line();
+        assert_eq!(black_pixels(&gpu), 10 * 8);
+
+        gpu.sprite_limit = 40;
+        gpu.render_line();
+        assert_eq!(black_pixels(&gpu), 12 * 8);
+    }
+
+    #[test]
+    fn test_background_disabled() {
+        let mut gpu = Gpu {
+            lcd_control: LCDControl::DisplayEnabled
+                | LCDControl::BackgroundEnabled
+                | LCDControl::SpritesEnabled
+                | LCDControl::TileDataSelect,
+            palettes: DmgPalettes {
+                background: BitArray::new([0xE4]),
+                sprites: [BitArray::new([0x80]); 2],
+                ..DmgPalettes::default()
+            },
+            ..Gpu::default()
+        };
+        // tile 1 is solid colour 3, and fills the background
+        for index in 0x10..0x20 {
+            gpu.write_vram(index, 0xFF);
+        }
+        for index in 0x1800..0x1820 {
+            gpu.write_vram(index, 1);
+        }
+        // a sprite behind the background in the top left corner
+        for (offset, value) in [16, 8, 1, 0b1000_0000].into_iter().enumerate() {
+            gpu.write_oam(offset, value);
+        }
+        let shade = |gpu: &Gpu, x: usize| gpu.buffer[x * 3];
+
+        gpu.render_line();
+        assert_eq!((shade(&gpu, 0), shade(&gpu, 20)), (0, 0));
+
+        // the background goes white, and can't hide the sprite any more
+        gpu.lcd_control.remove(LCDControl::BackgroundEnabled);
+        gpu.render_line();
+        assert_eq!((shade(&gpu, 0), shade(&gpu, 20)), (85, 255));
+    }
+
+    #[test]
+    fn test_oam_sprites() {
+        let mut gpu = Gpu::default();
+        for (offset, value) in [0x20, 0x18, 0x42, 0b0110_0000].into_iter().enumerate() {
+            gpu.write_oam(5 * 4 + offset, value);
+        }
+        let sprites = gpu.oam_sprites();
+        assert_eq!(
+            sprites[5],
+            Sprite {
+                index: 5,
+                y: 0x20,
+                x: 0x18,
+                tile: 0x42,
+                flags: SpriteFlag::YFlip | SpriteFlag::XFlip,
+            }
+        );
+        assert_eq!(sprites[39].index, 39);
+        assert_eq!(sprites[6].tile, 0);
+    }
+
+    #[test]
+    fn test_frame_hash() {
+        let mut gpu = Gpu::default();
+        gpu.buffer.fill(255);
+        assert_eq!(gpu.frame_hash(), 0xfc88_4941_52e4_3925);
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let mut gpu = Gpu {
+            lcd_control: LCDControl::DisplayEnabled.into(),
+            mode: Mode::OamScan,
+            scroll_x: 12,
+            window_y: 34,
+            ..Gpu::default()
+        };
+        for _ in 0..21 {
+            gpu.step(4);
+        }
+        let info = gpu.debug_info();
+        assert_eq!(info.mode, Mode::Drawing);
+        assert_eq!(info.line, 0);
+        assert_eq!(info.cycles, 4);
+        assert_eq!((info.scroll_x, info.window_y), (12, 34));
+        assert_eq!(info.lcd_control, LCDControl::DisplayEnabled);
+    }
+
+    #[test]
+    fn test_lcd_disable_blanks_screen() {
+        let mut gpu = Gpu::default();
+        gpu.set_lcd_control(LCDControl::DisplayEnabled.into());
+        assert!(gpu.buffer.iter().all(|&byte| byte == 0));
+        gpu.set_lcd_control(BitFlags::EMPTY);
+        assert!(gpu.buffer.iter().all(|&byte| byte == 255));
+        assert_eq!(gpu.frames, 1);
+        gpu.set_lcd_control(BitFlags::EMPTY);
+        assert_eq!(gpu.frames, 1);
+
+        gpu.palettes.shades = "#9BBC0F\n#8BAC0F\n#306230\n#0F380F".parse().unwrap();
+        gpu.set_lcd_control(LCDControl::DisplayEnabled.into());
+        gpu.set_lcd_control(BitFlags::EMPTY);
+        assert!(
+            gpu.buffer
+                .chunks_exact(3)
+                .all(|pixel| pixel == [0x9B, 0xBC, 0x0F])
+        );
+
+        gpu.cgb_mode = true;
+        gpu.set_lcd_control(LCDControl::DisplayEnabled.into());
+        gpu.set_lcd_control(BitFlags::EMPTY);
+        assert!(gpu.buffer.iter().all(|&byte| byte == 255));
+    }
+
+    #[test]
+    fn test_drawing_duration_scroll_x() {
+        let mut gpu = Gpu {
+            lcd_control: LCDControl::DisplayEnabled.into(),
+            mode: Mode::OamScan,
+            scroll_x: 5,
+            ..Gpu::default()
+        };
+        gpu.step(80);
+        gpu.step(176);
+        assert_eq!(gpu.mode, Mode::Drawing);
+        gpu.step(1);
+        assert_eq!(gpu.mode, Mode::HBlank);
+        // HBlank shrinks by the same amount so the line still takes 456 cycles
+        gpu.step(198);
+        assert_eq!(gpu.mode, Mode::HBlank);
+        gpu.step(1);
+        assert_eq!(gpu.mode, Mode::OamScan);
+    }
 }
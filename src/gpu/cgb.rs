@@ -0,0 +1,17 @@
+use bilge::prelude::*;
+
+/// A background tile's attributes, stored in VRAM bank 1 at the same position in the tile map
+/// as the tile number
+#[bitsize(8)]
+#[derive(DebugBits, Clone, Copy, FromBits, Default)]
+pub struct TileAttributes {
+    // bilge bitfields are LSB at the top
+    pub palette: u3,
+    /// Which VRAM bank the tile data comes from
+    pub bank: bool,
+    reserved: bool,
+    pub x_flip: bool,
+    pub y_flip: bool,
+    /// Background colours 1-3 are drawn over sprites
+    pub priority: bool,
+}
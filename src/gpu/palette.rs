@@ -0,0 +1,225 @@
+use std::str::FromStr;
+
+use bitvec::{BitArr, array::BitArray, order::Lsb0};
+use parse_display::Display;
+
+use crate::{
+    gpu::tile::ColourIndex,
+    state::{Snapshot, StateError, StateReader, StateWriter},
+};
+
+pub type Rgb = (u8, u8, u8);
+
+/// Turns the colour indices stored in tiles into the colours which end up on screen
+pub trait PaletteProvider {
+    /// `palette` comes from the background tile's attributes, and is always 0 on DMG
+    fn background(&self, palette: u8, colour: ColourIndex) -> Rgb;
+    /// `palette` comes from the sprite's attributes
+    fn sprite(&self, palette: u8, colour: ColourIndex) -> Rgb;
+}
+
+/// The colours the DMG's 4 shades are drawn with, from lightest to darkest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shades(pub [Rgb; 4]);
+
+impl Default for Shades {
+    fn default() -> Self {
+        Self([(255, 255, 255), (170, 170, 170), (85, 85, 85), (0, 0, 0)])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum ParseShadesError {
+    #[display("line {0}: expected a hex colour like `#9BBC0F`")]
+    InvalidColour(usize),
+    #[display("expected 4 colours, found {0}")]
+    WrongCount(usize),
+}
+
+impl std::error::Error for ParseShadesError {}
+
+/// One hex colour per line, with or without a leading `#`. Blank lines and anything after a `;`
+/// are ignored
+impl FromStr for Shades {
+    type Err = ParseShadesError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut colours = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let hex = line.strip_prefix('#').unwrap_or(line);
+            let [_, r, g, b] = u32::from_str_radix(hex, 16)
+                .ok()
+                .filter(|_| hex.len() == 6)
+                .ok_or(ParseShadesError::InvalidColour(index + 1))?
+                .to_be_bytes();
+            colours.push((r, g, b));
+        }
+        colours
+            .as_slice()
+            .try_into()
+            .map(Self)
+            .map_err(|_| ParseShadesError::WrongCount(colours.len()))
+    }
+}
+
+/// BGP, OBP0 and OBP1, which map colours onto 4 shades
+#[derive(Debug, Clone, Copy)]
+pub struct DmgPalettes {
+    pub background: BitArr!(for 8, in u8, Lsb0),
+    pub sprites: [BitArr!(for 8, in u8, Lsb0); 2],
+    /// What each shade looks like on screen
+    pub shades: Shades,
+}
+
+impl Default for DmgPalettes {
+    fn default() -> Self {
+        Self {
+            background: BitArray::ZERO,
+            sprites: [BitArray::ZERO; 2],
+            shades: Shades::default(),
+        }
+    }
+}
+
+impl DmgPalettes {
+    /// Looks up the shade of a colour in a palette register
+    fn shade(&self, palette: BitArr!(for 8, in u8, Lsb0), colour: ColourIndex) -> Rgb {
+        let bit = usize::from(colour) * 2;
+        let value = u8::from(palette[bit + 1]) << 1 | u8::from(palette[bit]);
+        self.shades.0[usize::from(value)]
+    }
+}
+
+impl PaletteProvider for DmgPalettes {
+    fn background(&self, _palette: u8, colour: ColourIndex) -> Rgb {
+        self.shade(self.background, colour)
+    }
+
+    /// Only the lowest bit of `palette` is used, picking between OBP0 and OBP1
+    fn sprite(&self, palette: u8, colour: ColourIndex) -> Rgb {
+        self.shade(self.sprites[usize::from(palette & 1)], colour)
+    }
+}
+
+/// 8 palettes of 4 little endian BGR555 colours, accessed one byte at a time through an index
+/// register (BCPS/OCPS) and a data register (BCPD/OCPD)
+#[derive(Debug, Clone, Copy)]
+pub struct PaletteRam {
+    pub data: [u8; 64],
+    /// Index register bits 0-5
+    index: u8,
+    /// Index register bit 7, the index goes up after every write to the data register
+    auto_increment: bool,
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        Self {
+            data: [0; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+}
+
+impl PaletteRam {
+    pub fn read_index(&self) -> u8 {
+        u8::from(self.auto_increment) << 7 | 0b0100_0000 | self.index
+    }
+
+    pub const fn write_index(&mut self, value: u8) {
+        self.index = value & 0b0011_1111;
+        self.auto_increment = value & 0b1000_0000 != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[usize::from(self.index)]
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data[usize::from(self.index)] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0b0011_1111;
+        }
+    }
+}
+
+/// CGB palette RAM, for both backgrounds and sprites
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CgbPalettes {
+    pub background: PaletteRam,
+    pub sprites: PaletteRam,
+}
+
+impl CgbPalettes {
+    #[allow(clippy::cast_possible_truncation)]
+    fn lookup(ram: &PaletteRam, palette: u8, colour: ColourIndex) -> Rgb {
+        let index = usize::from(palette & 0b111) * 8 + usize::from(colour) * 2;
+        let bgr = u16::from_le_bytes([ram.data[index], ram.data[index + 1]]);
+        // scale 5 bits up to 8, so 0x1F becomes 0xFF
+        let channel = |shift: u16| {
+            let value = (bgr >> shift & 0x1F) as u8;
+            value << 3 | value >> 2
+        };
+        (channel(0), channel(5), channel(10))
+    }
+}
+
+impl PaletteProvider for CgbPalettes {
+    fn background(&self, palette: u8, colour: ColourIndex) -> Rgb {
+        Self::lookup(&self.background, palette, colour)
+    }
+
+    fn sprite(&self, palette: u8, colour: ColourIndex) -> Rgb {
+        Self::lookup(&self.sprites, palette, colour)
+    }
+}
+
+impl Snapshot for PaletteRam {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        state.u8(self.read_index());
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes(&mut self.data)?;
+        self.write_index(state.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cgb_palette_ram() {
+        let mut palettes = CgbPalettes::default();
+        // palette 2, colour 3 is pure red
+        palettes.background.data[2 * 8 + 3 * 2] = 0x1F;
+        assert_eq!(palettes.background(2, 3), (0xFF, 0, 0));
+        assert_eq!(palettes.background(2, 2), (0, 0, 0));
+        // the upper bits of the palette number are ignored
+        assert_eq!(palettes.background(0b1010, 3), (0xFF, 0, 0));
+    }
+
+    #[test]
+    fn test_palette_ram_auto_increment() {
+        let mut ram = PaletteRam::default();
+        ram.write_index(0b1011_1111);
+        ram.write_data(0x12);
+        ram.write_data(0x34);
+        // the index wraps around within the 64 bytes
+        assert_eq!(ram.read_index(), 0b1100_0001);
+        assert_eq!((ram.data[0x3F], ram.data[0]), (0x12, 0x34));
+
+        ram.write_index(0x3E);
+        ram.write_data(0x56);
+        assert_eq!(ram.read_index(), 0b0111_1110);
+        assert_eq!(ram.read_data(), 0x56);
+    }
+}
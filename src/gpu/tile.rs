@@ -31,7 +31,7 @@ impl TileRow {
         msb << 1 | lsb
     }
 
-    pub const fn iter(&self) -> TileRowIterator {
+    pub const fn iter(&self) -> TileRowIterator<'_> {
         TileRowIterator {
             tile_row: self,
             index: 0,
@@ -0,0 +1,106 @@
+//! Checks on the cartridge header at 0x0100-0x014F, which the boot ROM uses to decide whether a
+//! cartridge is genuine
+
+use std::ops::Range;
+
+/// The logo every cartridge has to carry, which the boot ROM scrolls down the screen
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// Where the logo lives in the header
+pub const LOGO: Range<usize> = 0x104..0x134;
+/// The bytes the header checksum covers, from the title to the version number
+const CHECKSUMMED: Range<usize> = 0x134..0x14D;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM: Range<usize> = 0x14E..0x150;
+
+/// A view of a whole cartridge ROM for checking its header. A ROM too short to have a header
+/// fails every check
+#[derive(Debug, Clone, Copy)]
+pub struct CartridgeHeader<'a> {
+    rom: &'a [u8],
+}
+
+impl<'a> CartridgeHeader<'a> {
+    pub const fn new(rom: &'a [u8]) -> Self {
+        Self { rom }
+    }
+
+    /// Whether the logo matches Nintendo's. The boot ROM locks up if it doesn't
+    pub fn logo_valid(&self) -> bool {
+        self.rom.get(LOGO) == Some(&NINTENDO_LOGO[..])
+    }
+
+    /// Whether 0x014D matches the checksum of 0x0134-0x014C. The boot ROM locks up if it doesn't
+    pub fn header_checksum_valid(&self) -> bool {
+        let Some(bytes) = self.rom.get(CHECKSUMMED) else {
+            return false;
+        };
+        let checksum = bytes
+            .iter()
+            .fold(0_u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+        self.rom.get(HEADER_CHECKSUM) == Some(&checksum)
+    }
+
+    /// Whether 0x014E-0x014F hold the sum of every other byte in the ROM. Nothing on real
+    /// hardware checks this
+    pub fn global_checksum_valid(&self) -> bool {
+        let Some(&[high, low]) = self.rom.get(GLOBAL_CHECKSUM) else {
+            return false;
+        };
+        let sum = self
+            .rom
+            .iter()
+            .enumerate()
+            .filter(|(address, _)| !GLOBAL_CHECKSUM.contains(address))
+            .fold(0_u16, |sum, (_, byte)| sum.wrapping_add(u16::from(*byte)));
+        sum == u16::from_be_bytes([high, low])
+    }
+}
+
+/// Overwrites the cartridge's logo with Nintendo's, so the boot ROM will start it
+pub fn patch_logo(rom: &mut Vec<u8>) {
+    if rom.len() < LOGO.end {
+        rom.resize(LOGO.end, 0);
+    }
+    rom[LOGO].copy_from_slice(&NINTENDO_LOGO);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_real_header() {
+        let rom = include_bytes!("../test_roms/instr_timing/instr_timing.gb");
+        let header = CartridgeHeader::new(rom);
+        assert!(header.logo_valid());
+        assert!(header.header_checksum_valid());
+        assert!(header.global_checksum_valid());
+
+        let boot_rom = include_bytes!("../dmg_boot.bin");
+        assert_eq!(boot_rom[0xA8..0xD8], NINTENDO_LOGO);
+    }
+
+    #[test]
+    fn test_corrupted_header() {
+        let mut rom = include_bytes!("../test_roms/instr_timing/instr_timing.gb").to_vec();
+        rom[0x110] ^= 0xFF;
+        rom[0x134] ^= 0xFF;
+        let header = CartridgeHeader::new(&rom);
+        assert!(!header.logo_valid());
+        assert!(!header.header_checksum_valid());
+        assert!(!header.global_checksum_valid());
+
+        assert!(!CartridgeHeader::new(&rom[..0x140]).header_checksum_valid());
+
+        patch_logo(&mut rom);
+        assert!(CartridgeHeader::new(&rom).logo_valid());
+        let mut rom = Vec::new();
+        patch_logo(&mut rom);
+        assert!(CartridgeHeader::new(&rom).logo_valid());
+    }
+}
@@ -0,0 +1,69 @@
+use std::fmt::Write as _;
+
+/// Formats `bytes` as a classic hex and ASCII dump, 16 bytes per row, with rows labelled by
+/// address starting from `start`
+pub fn hexdump(start: u16, bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let address = usize::from(start) + row * 16;
+        let _ = write!(out, "{address:04X}  ");
+        for column in 0..16 {
+            match chunk.get(column) {
+                Some(byte) => {
+                    let _ = write!(out, "{byte:02X} ");
+                }
+                None => out.push_str("   "),
+            }
+            if column == 7 {
+                out.push(' ');
+            }
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    char::from(byte)
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(out, " |{ascii}|");
+    }
+    out
+}
+
+/// Parses a hex number, with or without a `$` or `0x` prefix
+pub fn parse_hex(value: &str) -> Result<u16, String> {
+    let digits = value
+        .strip_prefix('$')
+        .or_else(|| value.strip_prefix("0x"))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16).map_err(|e| format!("{value:?} isn't a hex number: {e}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hexdump() {
+        let bytes: Vec<u8> = (0x3A..0x5A).chain([0x00, 0xFF]).collect();
+        assert_eq!(
+            hexdump(0xC000, &bytes),
+            "\
+C000  3A 3B 3C 3D 3E 3F 40 41  42 43 44 45 46 47 48 49  |:;<=>?@ABCDEFGHI|
+C010  4A 4B 4C 4D 4E 4F 50 51  52 53 54 55 56 57 58 59  |JKLMNOPQRSTUVWXY|
+C020  00 FF                                             |..|
+"
+        );
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("C000"), Ok(0xC000));
+        assert_eq!(parse_hex("$ff80"), Ok(0xFF80));
+        assert_eq!(parse_hex("0x10"), Ok(0x10));
+        assert!(parse_hex("hello").is_err());
+    }
+}
@@ -1,13 +1,83 @@
+#![allow(dead_code)]
+
+use std::cell::Cell;
+
 use bilge::prelude::*;
 
+use crate::{cpu::memorybus::io::IoDevice, diagnostics::notice};
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Joypad {
     pub input_select: UpperNibble,
 
+    /// Everything the front end has held down, before `block_opposing_directions` is applied
+    pub input: InputState,
+    /// Release both directions when opposite directions are held together. Real hardware
+    /// allows it, but some games glitch out
+    pub block_opposing_directions: bool,
+}
+
+/// Which buttons a front-end currently has held down
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputState {
     pub buttons: Buttons,
     pub dpad: Dpad,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl InputState {
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::A => self.buttons.set_a(pressed),
+            Button::B => self.buttons.set_b(pressed),
+            Button::Select => self.buttons.set_select(pressed),
+            Button::Start => self.buttons.set_start(pressed),
+            Button::Up => self.dpad.set_up(pressed),
+            Button::Down => self.dpad.set_down(pressed),
+            Button::Left => self.dpad.set_left(pressed),
+            Button::Right => self.dpad.set_right(pressed),
+        }
+    }
+
+    pub fn is_pressed(self, button: Button) -> bool {
+        match button {
+            Button::A => self.buttons.a(),
+            Button::B => self.buttons.b(),
+            Button::Select => self.buttons.select(),
+            Button::Start => self.buttons.start(),
+            Button::Up => self.dpad.up(),
+            Button::Down => self.dpad.down(),
+            Button::Left => self.dpad.left(),
+            Button::Right => self.dpad.right(),
+        }
+    }
+
+    /// Releases both directions of any opposing pair which are held together
+    #[must_use]
+    pub fn without_opposing_directions(mut self) -> Self {
+        if self.dpad.up() && self.dpad.down() {
+            self.dpad.set_up(false);
+            self.dpad.set_down(false);
+        }
+        if self.dpad.left() && self.dpad.right() {
+            self.dpad.set_left(false);
+            self.dpad.set_right(false);
+        }
+        self
+    }
+}
+
 #[bitsize(4)]
 #[derive(DebugBits, Clone, Copy, FromBits, Default)]
 pub struct UpperNibble {
@@ -46,6 +116,23 @@ pub struct Dpad {
 }
 
 impl Joypad {
+    pub const fn set_input(&mut self, input: InputState) {
+        self.input = input;
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.input.set_button(button, pressed);
+    }
+
+    /// What the game sees as held, which leaves out opposing directions if they're blocked
+    fn pressed(self) -> InputState {
+        if self.block_opposing_directions {
+            self.input.without_opposing_directions()
+        } else {
+            self.input
+        }
+    }
+
     pub fn write_joypad(&mut self, value: u8) {
         // lower nibble is read-only
         self.input_select = UpperNibble::from(u4::extract_u8(value, 4));
@@ -53,25 +140,159 @@ impl Joypad {
 
     pub fn read_joypad(self) -> u8 {
         let upper = self.input_select.value.as_u8();
+        let pressed = self.pressed();
         let lower = match self.input_select.select() {
-            NibbleSelect::Button => self.button_nibble(),
-            NibbleSelect::Dpad => self.dpad_nibble(),
+            NibbleSelect::Button => Self::button_nibble(pressed),
+            NibbleSelect::Dpad => Self::dpad_nibble(pressed),
             // If both are selected, bits are set to 0 if *either* of the buttons assigned to that
             // bit are pressed
-            NibbleSelect::Both => self.button_nibble() & self.dpad_nibble(),
+            NibbleSelect::Both => Self::button_nibble(pressed) & Self::dpad_nibble(pressed),
             NibbleSelect::None => 0xF,
         };
-        upper << 4 | lower
+        // the top two bits aren't connected, and always read as 1
+        0b1100_0000 | upper << 4 | lower
     }
 
-    fn button_nibble(self) -> u8 {
+    fn button_nibble(pressed: InputState) -> u8 {
         // invert the bits because a button being pressed is seen as that bit being 0
         // flip them before converting to a u8 so the upper nibble isn't touched
-        (!self.buttons.value).as_u8()
+        (!pressed.buttons.value).as_u8()
     }
-    fn dpad_nibble(self) -> u8 {
+    fn dpad_nibble(pressed: InputState) -> u8 {
         // invert the bits because a button being pressed is seen as that bit being 0
         // flip them before converting to a u8 so the upper nibble isn't touched
-        (!self.dpad.value).as_u8()
+        (!pressed.dpad.value).as_u8()
+    }
+}
+
+/// Measures how many frames pass between a button going down in the front end and the game
+/// reading it from P1
+#[derive(Debug, Default)]
+pub struct InputLatency {
+    /// The frame the oldest unread press arrived on, and which buttons and directions were
+    /// pressed since
+    pending: Cell<Option<(u64, u8, u8)>>,
+    /// The most recent measurement, in frames
+    pub last: Cell<Option<u64>>,
+}
+
+impl InputLatency {
+    /// Notes any buttons which are held in `after` but weren't in `before`, and forgets any
+    /// which were released before the game saw them
+    pub fn input_changed(&self, before: InputState, after: InputState, frame: u64) {
+        let buttons = (after.buttons.value & !before.buttons.value).as_u8();
+        let dpad = (after.dpad.value & !before.dpad.value).as_u8();
+        let still_held = self
+            .pending
+            .get()
+            .map(|(pressed_at, old_buttons, old_dpad)| {
+                (
+                    pressed_at,
+                    old_buttons & after.buttons.value.as_u8(),
+                    old_dpad & after.dpad.value.as_u8(),
+                )
+            })
+            .filter(|&(_, old_buttons, old_dpad)| old_buttons != 0 || old_dpad != 0);
+        let pending = match still_held {
+            Some((pressed_at, old_buttons, old_dpad)) => {
+                Some((pressed_at, old_buttons | buttons, old_dpad | dpad))
+            }
+            None if buttons != 0 || dpad != 0 => Some((frame, buttons, dpad)),
+            None => None,
+        };
+        self.pending.set(pending);
+    }
+
+    /// Called with each value the game reads from P1, which counts as having seen a press if
+    /// one of the new buttons shows up in the selected group
+    pub fn read(&self, value: u8, frame: u64) {
+        let Some((pressed_at, buttons, dpad)) = self.pending.get() else {
+            return;
+        };
+        let held = !value & 0x0F;
+        let buttons_seen = value & 0x20 == 0 && held & buttons != 0;
+        let dpad_seen = value & 0x10 == 0 && held & dpad != 0;
+        if buttons_seen || dpad_seen {
+            let frames = frame - pressed_at;
+            notice!("input latency: {frames} frames");
+            self.last.set(Some(frames));
+            self.pending.set(None);
+        }
+    }
+}
+
+/// P1
+impl IoDevice for Joypad {
+    fn read(&self, _address: usize) -> u8 {
+        self.read_joypad()
+    }
+
+    fn write(&mut self, _address: usize, value: u8) {
+        self.write_joypad(value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_button_bits() {
+        // P1 select value, and the bit which should read as 0
+        let expected = [
+            (Button::A, 0x10, 0),
+            (Button::B, 0x10, 1),
+            (Button::Select, 0x10, 2),
+            (Button::Start, 0x10, 3),
+            (Button::Right, 0x20, 0),
+            (Button::Left, 0x20, 1),
+            (Button::Up, 0x20, 2),
+            (Button::Down, 0x20, 3),
+        ];
+        for (button, select, bit) in expected {
+            let mut joypad = Joypad::default();
+            joypad.set_button(button, true);
+            joypad.write_joypad(select);
+            assert_eq!(joypad.read_joypad() & 0xF, !(1 << bit) & 0xF, "{button:?}");
+            // the other group is unaffected
+            joypad.write_joypad(select ^ 0x30);
+            assert_eq!(joypad.read_joypad() & 0xF, 0xF, "{button:?}");
+        }
+    }
+
+    #[test]
+    fn test_unused_bits_read_high() {
+        let mut joypad = Joypad::default();
+        joypad.write_joypad(0x00);
+        assert_eq!(joypad.read_joypad(), 0xCF);
+        joypad.write_joypad(0x10);
+        assert_eq!(joypad.read_joypad(), 0xDF);
+    }
+
+    #[test]
+    fn test_nothing_selected() {
+        let mut joypad = Joypad::default();
+        for button in [Button::A, Button::Start, Button::Up, Button::Right] {
+            joypad.set_button(button, true);
+        }
+        joypad.write_joypad(0x30);
+        assert_eq!(joypad.read_joypad(), 0xFF);
+    }
+
+    #[test]
+    fn test_block_opposing_directions() {
+        let mut joypad = Joypad::default();
+        joypad.write_joypad(0x20);
+        joypad.set_button(Button::Left, true);
+        joypad.set_button(Button::Right, true);
+        assert_eq!(joypad.read_joypad() & 0xF, 0b1100);
+
+        joypad.block_opposing_directions = true;
+        joypad.set_button(Button::Up, true);
+        assert_eq!(joypad.read_joypad() & 0xF, 0b1011);
+
+        // left is still held, so it comes back once right is let go
+        joypad.set_button(Button::Right, false);
+        assert_eq!(joypad.read_joypad() & 0xF, 0b1001);
     }
 }
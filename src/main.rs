@@ -1,46 +1,730 @@
 #![feature(thread_sleep_until)]
 use std::{
     fs::File,
-    io::{BufWriter, Write as _},
-    sync::{Arc, Mutex},
+    io::{BufRead as _, BufReader, BufWriter, Write as _},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
     time::{Duration, Instant},
 };
 
-use clap::Parser;
-use jane_eyre::eyre::{self, eyre};
-use minifb::{Key, Window, WindowOptions};
-use tracing::{debug, warn};
+use clap::{Parser, ValueEnum};
+use jane_eyre::eyre;
+use tracing::{error, info, warn};
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
-    cpu::Cpu,
-    gpu::{HEIGHT, Mode, WIDTH},
+    clock::{BurstBudget, Clock, RealTimeClock, VirtualClock},
+    coverage::Coverage,
+    cpu::{CYCLES_PER_FRAME, Cpu},
+    disassembler::symbols::Symbols,
+    display::{
+        Display, flashing::FlashReduction, ghosting::Ghosting, overlay, shader::Shader,
+        terminal::TerminalDisplay, window::WindowDisplay,
+    },
+    frame::SharedFrame,
+    gpu::{HEIGHT, OAM_SIZE, WIDTH, palette::Shades},
+    header::CartridgeHeader,
+    hexdump::{hexdump, parse_hex},
+    joypad::{InputLatency, InputState},
+    reload::RomWatcher,
+    save::{AutoSave, write_atomically},
+    script::Script,
+    trace::{LogWindow, TraceComparer, TraceRing},
+    watchdog::Watchdog,
 };
 
+mod apu;
+mod assembler;
+mod clock;
+mod coverage;
 mod cpu;
+mod diagnostics;
 mod disassembler;
+mod display;
+mod error;
+mod frame;
+#[cfg(test)]
+mod golden;
 mod gpu;
+mod header;
+mod hexdump;
 mod joypad;
-
+#[cfg(test)]
+mod link;
+mod png;
+mod reload;
+mod save;
+mod script;
+mod serial;
+#[cfg(feature = "sgb")]
+mod sgb;
+mod state;
 mod timer;
+mod trace;
+mod watchdog;
+
+/// Presents the shared frame until the display is closed, publishing its input state
+fn run_display(
+    display: &mut impl Display,
+    mut ghosting: Option<Ghosting>,
+    mut flash_reduction: Option<FlashReduction>,
+    shared: &SharedFrame,
+    input: &Mutex<InputState>,
+    vsync: Vsync,
+) {
+    let mut seen = 0;
+    while display.is_open() {
+        // FIXME: copies 92KB 60 times a second...
+        let (number, frame) = match vsync {
+            Vsync::On => shared.latest(),
+            // nothing paces the display, so sleep until there's something new to show. Input is
+            // still polled at 60Hz if the emulator stalls
+            Vsync::Off => shared.wait_for_newer(seen, Duration::from_secs(1) / 60),
+        };
+        seen = number;
+        if display.overlay_toggled() {
+            shared.sprite_overlay.fetch_xor(true, Ordering::Relaxed);
+        }
+        let frame = match ghosting.as_mut() {
+            Some(ghosting) => ghosting.blend(&frame),
+            None => frame,
+        };
+        match flash_reduction.as_mut() {
+            Some(flash_reduction) => display.present(&flash_reduction.filter(&frame)),
+            None => display.present(&frame),
+        }
+        *input.lock().unwrap() = display.poll_input();
+    }
+}
+
+/// Whether bursts wait for wall time. `--fast` and `--vsync off` run as fast as the host allows
+fn paced_to_wall_time(args: &Args) -> bool {
+    !args.fast && args.vsync == Vsync::On
+}
+
+/// Builds the cpu described by the command line
+fn create_cpu(args: &Args) -> Cpu {
+    let boot_rom = if args.use_boot_rom {
+        Some(include_bytes!("../dmg_boot.bin"))
+    } else {
+        None
+    };
+    let mut game_rom = load_game_rom(args);
+    let header = CartridgeHeader::new(&game_rom);
+    let (logo_valid, header_checksum_valid) = (header.logo_valid(), header.header_checksum_valid());
+    info!(
+        global_checksum_valid = header.global_checksum_valid(),
+        "cartridge header"
+    );
+    if boot_rom.is_some() && !logo_valid {
+        if args.skip_logo_check {
+            header::patch_logo(&mut game_rom);
+        } else {
+            warn!(
+                "the cartridge's logo doesn't match the boot ROM's, so the boot ROM will lock up. \
+                Pass --skip-logo-check to start it anyway"
+            );
+        }
+    }
+    if boot_rom.is_some() && !header_checksum_valid {
+        warn!("the cartridge's header checksum is wrong, so the boot ROM will lock up");
+    }
+    // reference logs are taken with LY stubbed out
+    let test_mode = args.log || args.trace_compare.is_some();
+    let mut cpu = if args.boot_sequence {
+        Cpu::new_with_boot_sequence(&game_rom, test_mode)
+    } else {
+        Cpu::new(boot_rom, &game_rom, test_mode)
+    };
+    cpu.bus.restrict_ppu_access = !args.lax_ppu_access;
+    cpu.bus.stat_write_bug = !args.lax_ppu_access;
+    cpu.bus.oam_bug &= !args.lax_ppu_access;
+    cpu.bus.oam_dma_conflicts = !args.lax_ppu_access;
+    cpu.bus.gpu.per_pixel_rendering = args.per_pixel_rendering;
+    cpu.bus.joypad.block_opposing_directions = args.block_opposing_directions;
+    if args.input_latency {
+        cpu.bus.input_latency = Some(InputLatency::default());
+    }
+    if let Some(symbols) = &args.symbols {
+        cpu.symbols = symbols.clone();
+    }
+    if args.no_sprite_limit {
+        cpu.bus.gpu.sprite_limit = OAM_SIZE / 4;
+    }
+    if let Some(shades) = args.palette_file {
+        cpu.bus.gpu.palettes.shades = shades;
+    }
+    info!(mbc = ?cpu.bus.mbc, features = ?cpu.bus.cartridge, "cartridge");
+    cpu
+}
+
+/// The ROM being watched if there is one, or the bundled test ROM
+fn load_game_rom(args: &Args) -> Vec<u8> {
+    args.watch
+        .as_ref()
+        .and_then(|path| {
+            std::fs::read(path)
+                .inspect_err(|e| {
+                    error!(
+                        "failed to read {}, using the bundled ROM: {e}",
+                        path.display()
+                    );
+                })
+                .ok()
+        })
+        .unwrap_or_else(|| include_bytes!("../test_roms/instr_timing/instr_timing.gb").to_vec())
+}
 
-const fn from_u8_rgb(r: u8, g: u8, b: u8) -> u32 {
-    let (r, g, b) = (r as u32, g as u32, b as u32);
-    (r << 16) | (g << 8) | b
+/// Reads the 4 DMG shades from a file of hex colours
+fn load_shades(path: &str) -> eyre::Result<Shades> {
+    Ok(std::fs::read_to_string(path)?.parse()?)
+}
+
+/// Reads an RGBDS `.sym` file
+fn load_symbols(path: &str) -> eyre::Result<Symbols> {
+    Ok(std::fs::read_to_string(path)?.parse()?)
+}
+
+/// Prints a hexdump of `len` bytes from `start`, after running to `break_at` if it's given
+fn run_dump(args: &Args, start: u16, len: u16) {
+    let mut cpu = create_cpu(args);
+    if let Some(break_at) = args.break_at {
+        // give up after a minute of emulated time
+        let limit = 60 * 60 * u64::from(CYCLES_PER_FRAME);
+        while cpu.pc != break_at {
+            if cpu.total_cycles >= limit {
+                warn!("never reached {break_at:04X}, dumping anyway");
+                break;
+            }
+            if let Err(e) = cpu.step() {
+                error!("emulation stopped: {e}");
+                return;
+            }
+        }
+    }
+
+    let bytes: Vec<u8> = (start..=u16::MAX)
+        .take(len.into())
+        .map(|address| cpu.bus.read_byte(address))
+        .collect();
+    print!("{}", hexdump(start, &bytes));
+}
+
+/// Runs `bursts` bursts as fast as possible without a display, and reports how long it took
+fn run_bench(args: &Args, bursts: u64) {
+    let mut cpu = create_cpu(args);
+    let mut clock = VirtualClock::default();
+    let mut budget = BurstBudget::default();
+    let start = Instant::now();
+    while clock.bursts < bursts {
+        match cpu.step_cycles(budget.next_burst()) {
+            Ok(cycles) => budget.ran(cycles),
+            Err(e) => {
+                error!("emulation stopped: {e}");
+                return;
+            }
+        }
+        clock.wait_for_next_burst();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "emulated {:?} in {elapsed:?} ({:.1}x speed), frame hash {:016X}",
+        clock.elapsed(),
+        clock.elapsed().as_secs_f64() / elapsed.as_secs_f64(),
+        cpu.bus.gpu.frame_hash()
+    );
+}
+
+/// Runs a test ROM without a display until it reports a result over serial, printing what it
+/// sent. Fails if there's no result after `seconds` of emulated time
+fn run_serial_test(args: &Args, seconds: u64) -> ExitCode {
+    let mut cpu = create_cpu(args);
+    let mut clock = VirtualClock::default();
+    let mut budget = BurstBudget::default();
+    let verdict = loop {
+        match cpu.step_cycles(budget.next_burst()) {
+            Ok(cycles) => budget.ran(cycles),
+            Err(e) => {
+                error!("emulation stopped: {e}");
+                break Some(false);
+            }
+        }
+        clock.wait_for_next_burst();
+
+        let verdict = serial::test_verdict(&cpu.bus.serial.output);
+        if verdict.is_some() {
+            break verdict;
+        }
+        if clock.elapsed().as_secs() >= seconds {
+            error!("no result after {seconds}s");
+            break None;
+        }
+    };
+
+    print!("{}", String::from_utf8_lossy(&cpu.bus.serial.output));
+    if verdict == Some(true) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Runs `frames` frames without a display, then saves the last one as a PNG
+fn run_screenshot(args: &Args, frames: u32, path: &Path) -> ExitCode {
+    let mut cpu = create_cpu(args);
+    for _ in 0..frames {
+        if let Err(e) = cpu.run_frame() {
+            error!("emulation stopped: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    let png = png::encode(WIDTH as u32, HEIGHT as u32, &*cpu.bus.gpu.buffer);
+    if let Err(e) = std::fs::write(path, png) {
+        error!("failed to write {}: {e}", path.display());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Runs the commands in the script at `path` without a display, printing what it reads
+fn run_script(args: &Args, path: &Path) -> ExitCode {
+    let script = match std::fs::read_to_string(path)
+        .map_err(eyre::Report::from)
+        .and_then(|text| Ok(text.parse::<Script>()?))
+    {
+        Ok(script) => script,
+        Err(e) => {
+            error!("failed to load {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut cpu = create_cpu(args);
+    match script.run(&mut cpu) {
+        Ok(reads) => {
+            for (address, value) in reads {
+                println!("{address:04X}: {value:02X}");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("script failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The `.gb` files in `dir`, sorted by name
+fn roms_in(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut roms = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "gb") {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    Ok(roms)
+}
+
+/// Runs every ROM in `roms`, writing which opcodes they cover to `path` as markdown
+fn run_coverage_report(roms: &Path, path: &Path) -> ExitCode {
+    let roms = match roms_in(roms) {
+        Ok(paths) if !paths.is_empty() => paths,
+        Ok(_) => {
+            error!("no ROMs in {}", roms.display());
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            error!("failed to read {}: {e}", roms.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut coverage = Coverage::new();
+    for rom in roms {
+        let bytes = match std::fs::read(&rom) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("failed to read {}: {e}", rom.display());
+                return ExitCode::FAILURE;
+            }
+        };
+        let result = if coverage.run_rom(&bytes, 120) {
+            "passed"
+        } else {
+            "failed"
+        };
+        info!("{}: {result}", rom.display());
+    }
+    if let Err(e) = std::fs::write(path, coverage.report()) {
+        error!("failed to write {}: {e}", path.display());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Loads the save state at `path`, if there is one
+fn load_state(cpu: &mut Cpu, path: &Path) -> eyre::Result<()> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(state::load(cpu, &bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `--log`, which writes states to log.txt in the Gameboy Doctor format
+struct StateLog {
+    file: BufWriter<File>,
+    window: LogWindow,
+}
+
+impl StateLog {
+    fn new(args: &Args) -> Option<Self> {
+        args.log.then(|| Self {
+            file: BufWriter::new(File::create("log.txt").unwrap()),
+            window: LogWindow::new(
+                args.log_from_pc,
+                args.log_after.unwrap_or_default(),
+                args.log_count,
+            ),
+        })
+    }
+
+    /// Logs the cpu's state if it's inside the window
+    fn write(&mut self, cpu: &Cpu) {
+        if self.window.should_log(cpu.pc, cpu.total_instructions) {
+            self.file
+                .write_all(cpu.format_state().as_bytes())
+                .unwrap_or_else(|e| warn!("failed to write to buffer {e}"));
+        }
+    }
+}
+
+/// Hands the finished frame to the display, outlining sprites on a copy if that's turned on
+/// Publishes the frame if one has finished since `last_frame`. Only whole frames are published,
+/// so the display never shows half of one frame and half of the last
+fn publish_new_frame(cpu: &Cpu, shared: &SharedFrame, last_frame: &mut u64) {
+    if cpu.bus.gpu.frames != *last_frame {
+        publish_frame(cpu, shared);
+        *last_frame = cpu.bus.gpu.frames;
+    }
+}
+
+fn publish_frame(cpu: &Cpu, shared: &SharedFrame) {
+    if shared.sprite_overlay.load(Ordering::Relaxed) {
+        let mut frame = cpu.bus.gpu.buffer.to_vec();
+        overlay::draw(&mut frame, &overlay::sprite_boxes(&cpu.bus.gpu));
+        shared.publish(&frame);
+    } else {
+        shared.publish(&*cpu.bus.gpu.buffer);
+    }
+}
+
+/// Resets with the watched ROM if it's been rebuilt
+fn reload_if_changed(cpu: &mut Cpu, watcher: &mut RomWatcher, keep_ram: bool) {
+    match watcher.poll() {
+        Some(Ok(rom)) => {
+            info!("ROM changed, reloading");
+            let mut reloaded = cpu.with_cartridge(&rom, keep_ram);
+            reloaded.bus.take_io_devices(&mut cpu.bus);
+            *cpu = reloaded;
+        }
+        Some(Err(e)) => warn!("failed to reload the ROM: {e}"),
+        None => {}
+    }
+}
+
+/// Runs `cpu` until `running` is cleared, publishing frames to `shared` and reading the joypad
+/// from `input`
+#[allow(clippy::too_many_lines)]
+fn run_emulator(
+    args: &Args,
+    mut cpu: Cpu,
+    shared: &SharedFrame,
+    input: &Mutex<InputState>,
+    running: &AtomicBool,
+) {
+    let mut trace = match &args.trace_compare {
+        Some(path) => match File::open(path) {
+            Ok(file) => Some(TraceComparer::new(
+                BufReader::new(file).lines().map_while(Result::ok),
+            )),
+            Err(e) => {
+                error!("failed to open {}: {e}", path.display());
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let mut log = StateLog::new(args);
+    if let Some(log) = log.as_mut() {
+        // log initial state
+        log.write(&cpu);
+    }
+    if let Some(Err(e)) = trace.as_mut().map(|trace| trace.check(&cpu)) {
+        error!("trace comparison failed: {e}");
+        return;
+    }
+
+    if args.save_file.is_some() && !cpu.bus.cartridge.battery {
+        warn!("the cartridge has no battery, so the game won't expect its RAM to be kept");
+    }
+    let mut autosave = args.save_file.as_ref().map(|path| {
+        AutoSave::new(
+            path.clone(),
+            Duration::from_secs(args.autosave_interval.into()),
+        )
+    });
+    if let Some(Err(e)) = autosave.as_ref().map(|save| save.load(&mut cpu.bus)) {
+        error!("failed to load save: {e}");
+        return;
+    }
+
+    let mut clock: Box<dyn Clock> = if paced_to_wall_time(args) {
+        Box::new(RealTimeClock::new())
+    } else {
+        Box::new(VirtualClock::default())
+    };
+    let mut watchdog = args.watchdog.map(Watchdog::new);
+    let mut rom_watcher = args.watch.clone().map(RomWatcher::new);
+    let mut crash_trace = args.crash_trace.map(TraceRing::new);
+    let mut last_frame = cpu.bus.gpu.frames;
+    let mut budget = BurstBudget::default();
+    while running.load(Ordering::Relaxed) {
+        let burst_cycles = budget.next_burst();
+        let mut cycles_elapsed = 0;
+        while cycles_elapsed < burst_cycles {
+            let was_halted = cpu.halted;
+            let cycles = match cpu.step() {
+                Ok(cycles) => cycles,
+                Err(e) => {
+                    error!("emulation stopped: {e}");
+                    crash_trace.iter().for_each(TraceRing::dump);
+                    return;
+                }
+            };
+            cycles_elapsed += u32::from(cycles);
+            if let Some(ring) = crash_trace.as_mut() {
+                ring.record(&cpu);
+            }
+            if let Some(hang) = watchdog.as_mut().and_then(|watchdog| watchdog.check(&cpu)) {
+                warn!("{hang}");
+            }
+
+            // matches what Gameboy Doctor expects a log line for
+            let logged = cycles > 0 && cpu.pc != 0x50 && !(was_halted && cpu.halted);
+            if let Some(log) = log.as_mut().filter(|_| logged) {
+                log.write(&cpu);
+            }
+            if let Some(trace) = trace.as_mut().filter(|_| logged)
+                && let Err(e) = trace.check(&cpu)
+            {
+                error!("trace comparison failed: {e}");
+                crash_trace.iter().for_each(TraceRing::dump);
+                return;
+            }
+
+            publish_new_frame(&cpu, shared, &mut last_frame);
+        }
+        budget.ran(cycles_elapsed);
+
+        cpu.bus.set_input(*input.lock().unwrap());
+
+        if let Some(watcher) = rom_watcher.as_mut() {
+            reload_if_changed(&mut cpu, watcher, args.watch_keep_ram);
+        }
+
+        if let Some(log) = log.as_mut() {
+            // flush after every 1/60th burst
+            log.file
+                .flush()
+                .unwrap_or_else(|e| warn!("failed to flush to file {e}"));
+        }
+        if let Some(Err(e)) = autosave.as_mut().map(|save| save.tick(&mut cpu.bus)) {
+            warn!("failed to save: {e}");
+        }
+
+        clock.wait_for_next_burst();
+    }
+
+    if let Some(path) = &args.state
+        && let Err(e) = write_atomically(path, &state::save(&cpu))
+    {
+        error!("failed to save state: {e}");
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum DisplayKind {
+    /// A native window
+    #[default]
+    Window,
+    /// Half block characters drawn straight to the terminal
+    Terminal,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum Vsync {
+    /// Emulate in real time and present at 60fps
+    #[default]
+    On,
+    /// Emulate as fast as possible and present every frame produced
+    Off,
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
     #[arg(short, long)]
     log: bool,
+    /// Only start logging once PC reaches this address (in hex)
+    #[arg(long, value_name = "PC", value_parser = parse_hex, requires = "log")]
+    log_from_pc: Option<u16>,
+    /// Only start logging after this many instructions
+    #[arg(long, value_name = "INSTRUCTIONS", requires = "log")]
+    log_after: Option<u64>,
+    /// Stop logging after this many lines
+    #[arg(long, value_name = "LINES", requires = "log")]
+    log_count: Option<u64>,
     #[arg(short, long)]
     use_boot_rom: bool,
+    /// Patch the cartridge's logo so the boot ROM doesn't lock up on a mismatch
+    #[arg(long, requires = "use_boot_rom")]
+    skip_logo_check: bool,
+    /// Play the boot animation without needing the boot ROM
+    #[arg(long, conflicts_with = "use_boot_rom")]
+    boot_sequence: bool,
+    /// Run as fast as possible, with time only advancing as cycles are emulated
     #[arg(short, long)]
     fast: bool,
+    /// Run BURSTS sixtieths of a second without a display as fast as possible, and report the
+    /// speed
+    #[arg(long, value_name = "BURSTS")]
+    bench: Option<u64>,
+    /// Integer multiple to open the window at
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..))]
+    scale: u8,
+    /// Allow the window to be resized freely, letterboxing to preserve the aspect ratio
+    #[arg(short, long)]
+    resizable: bool,
+    /// Effect drawn over the upscaled frame in the window. Screenshots are left without it
+    #[arg(long, value_enum, default_value_t)]
+    shader: Shader,
+    /// Let the CPU access VRAM and OAM while the PPU is using them, and the rest of memory during
+    /// OAM DMA, and turn off the STAT write and OAM bugs
+    #[arg(long)]
+    lax_ppu_access: bool,
+    /// Draw each line a few pixels at a time so mid-line effects show up, at the cost of speed
+    #[arg(long)]
+    per_pixel_rendering: bool,
+    /// Draw every sprite on a line instead of stopping at 10, which gets rid of flicker
+    #[arg(long)]
+    no_sprite_limit: bool,
+    /// Run in lockstep with a Gameboy Doctor format log, stopping at the first divergence
+    #[arg(long, value_name = "LOGFILE")]
+    trace_compare: Option<PathBuf>,
+    /// Blend each frame with the previous FRAMES - 1 frames to mimic the DMG's blurry LCD
+    #[arg(long, value_name = "FRAMES", num_args = 0..=1, default_missing_value = "2",
+        value_parser = clap::value_parser!(u8).range(2..=4))]
+    lcd_ghosting: Option<u8>,
+    /// Soften flashes by blending frames whose average brightness jumps by more than THRESHOLD
+    /// (out of 255) with the frame before
+    #[arg(long, value_name = "THRESHOLD", num_args = 0..=1, default_missing_value = "64")]
+    reduce_flashing: Option<u8>,
+    /// How much of the previous frame is kept when softening a flash, as a percentage
+    #[arg(long, value_name = "PERCENT", default_value_t = 75, requires = "reduce_flashing",
+        value_parser = clap::value_parser!(u8).range(0..=100))]
+    flash_strength: u8,
+    /// Print a hexdump of LEN bytes of memory from START instead of opening a display. Both
+    /// are in hex
+    #[arg(long, num_args = 2, value_names = ["START", "LEN"], value_parser = parse_hex)]
+    dump: Option<Vec<u16>>,
+    /// Run until PC reaches this address (in hex) before dumping
+    #[arg(long, value_name = "PC", value_parser = parse_hex, requires = "dump")]
+    break_at: Option<u16>,
+    /// Ignore opposite directions on the dpad being held at the same time
+    #[arg(long)]
+    block_opposing_directions: bool,
+    /// Log how many frames pass between pressing a button and the game reading it
+    #[arg(long)]
+    input_latency: bool,
+    /// Run without a display until the ROM prints Passed or Failed over serial, exiting with
+    /// the result. Gives up after SECONDS of emulated time
+    #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "120")]
+    exit_on_serial: Option<u64>,
+    /// Run the ROMs in --coverage-roms, then write a markdown report of which opcodes pass,
+    /// fail or aren't tested to PATH and exit
+    #[arg(long, value_name = "PATH")]
+    coverage_report: Option<PathBuf>,
+    /// The directory of ROMs --coverage-report runs
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = "test_roms/cpu_instrs/individual"
+    )]
+    coverage_roms: PathBuf,
+    /// Keep the last N states in memory, and print them if emulation stops with an error or
+    /// panics
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "1024")]
+    crash_trace: Option<usize>,
+    /// Run FRAMES frames without a display, then save the screen to --screenshot and exit
+    #[arg(long, value_name = "FRAMES", requires = "screenshot")]
+    frames: Option<u32>,
+    /// Run the commands in FILE without a display, then exit. See src/script.rs for the
+    /// commands
+    #[arg(long, value_name = "FILE")]
+    script: Option<PathBuf>,
+    /// Where --frames saves the screen, as a PNG
+    #[arg(long, value_name = "PATH", requires = "frames")]
+    screenshot: Option<PathBuf>,
+    /// Battery backed save file, loaded on start and written to as the game saves
+    #[arg(long, value_name = "PATH")]
+    save_file: Option<PathBuf>,
+    /// How often the save file is updated, in seconds
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 5,
+        requires = "save_file"
+    )]
+    autosave_interval: u32,
+    /// Load the ROM from PATH instead of the bundled one, and reload it and reset whenever the
+    /// file changes
+    #[arg(long, value_name = "PATH")]
+    watch: Option<PathBuf>,
+    /// Keep cartridge RAM when --watch reloads the ROM
+    #[arg(long, requires = "watch")]
+    watch_keep_ram: bool,
+    /// Warn when PC stays in one place for this many steps, or jumps to itself with interrupts
+    /// off
+    #[arg(long, value_name = "STEPS")]
+    watchdog: Option<u64>,
+    /// Save state loaded on start if it exists, and written back on exit
+    #[arg(long, value_name = "PATH")]
+    state: Option<PathBuf>,
+    /// An RGBDS .sym file naming the targets of jumps and calls in traces
+    #[arg(long, value_name = "FILE", value_parser = load_symbols)]
+    symbols: Option<Symbols>,
+    /// A file of 4 hex colours like `#9BBC0F`, one per line from lightest to darkest, to draw
+    /// the DMG's shades with
+    #[arg(long, value_name = "FILE", value_parser = load_shades)]
+    palette_file: Option<Shades>,
+    /// Where frames are drawn to
+    #[arg(short, long, value_enum, default_value_t)]
+    display: DisplayKind,
+    /// Whether emulation is paced to the display. Off is useful for stress testing the renderer
+    #[arg(long, value_enum, default_value_t)]
+    vsync: Vsync,
 }
 
-fn main() -> eyre::Result<()> {
+fn main() -> eyre::Result<ExitCode> {
     jane_eyre::install()?;
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
@@ -52,105 +736,135 @@ fn main() -> eyre::Result<()> {
         .init();
 
     let args = Args::parse();
+    if let Some(path) = &args.coverage_report {
+        return Ok(run_coverage_report(&args.coverage_roms, path));
+    }
+    if let Some(seconds) = args.exit_on_serial {
+        return Ok(run_serial_test(&args, seconds));
+    }
+    if let (Some(frames), Some(path)) = (args.frames, &args.screenshot) {
+        return Ok(run_screenshot(&args, frames, path));
+    }
+    if let Some(path) = &args.script {
+        return Ok(run_script(&args, path));
+    }
+    if let Some(bursts) = args.bench {
+        run_bench(&args, bursts);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some([start, len]) = args.dump.as_deref() {
+        run_dump(&args, *start, *len);
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    let buffer = Arc::new(Mutex::new(vec![0; WIDTH * HEIGHT * 3]));
-    let gui_buffer = Arc::clone(&buffer);
-    let gui_thread = std::thread::spawn(move || {
-        let mut window = Window::new("gb-rs", WIDTH, HEIGHT, WindowOptions::default())
-            .map_err(|x| eyre!("{x:?}"))
-            .unwrap();
-        window.set_target_fps(60);
-
-        while window.is_open() && !window.is_key_down(Key::Escape) {
-            // FIXME: copies 92KB 60 times a second...
-            let buffer: Vec<u32> = gui_buffer
-                .lock()
-                .unwrap()
-                .clone() // releases the lock
-                .chunks_exact(3)
-                .map(|rgb| from_u8_rgb(rgb[0], rgb[1], rgb[2]))
-                .collect();
-            window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
+    // a state that can't be loaded stops everything before a window opens
+    let mut cpu = create_cpu(&args);
+    if let Some(path) = &args.state
+        && let Err(e) = load_state(&mut cpu, path)
+    {
+        error!("failed to load state from {}: {e}", path.display());
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let shared = Arc::new(SharedFrame::new());
+    let gui_shared = Arc::clone(&shared);
+    let input = Arc::new(Mutex::new(InputState::default()));
+    let gui_input = Arc::clone(&input);
+    let scale = usize::from(args.scale);
+    let resizable = args.resizable;
+    let shader = args.shader;
+    let display = args.display;
+    let vsync = args.vsync;
+    let ghosting = args.lcd_ghosting.map(|frames| Ghosting::new(frames.into()));
+    let flash_reduction = args
+        .reduce_flashing
+        .map(|threshold| FlashReduction::new(threshold, args.flash_strength));
+    let gui_thread = std::thread::spawn(move || match display {
+        DisplayKind::Window => {
+            let mut display =
+                WindowDisplay::new(scale, resizable, vsync == Vsync::On, shader).unwrap();
+            run_display(
+                &mut display,
+                ghosting,
+                flash_reduction,
+                &gui_shared,
+                &gui_input,
+                vsync,
+            );
+        }
+        DisplayKind::Terminal => {
+            let mut display = TerminalDisplay::new();
+            run_display(
+                &mut display,
+                ghosting,
+                flash_reduction,
+                &gui_shared,
+                &gui_input,
+                vsync,
+            );
         }
     });
 
-    let _ = std::thread::spawn(move || {
-        let boot_rom = if args.use_boot_rom {
-            Some(include_bytes!("../dmg_boot.bin"))
-        } else {
-            None
-        };
-        let test_rom = include_bytes!("../test_roms/instr_timing/instr_timing.gb");
-        let mut cpu = Cpu::new(boot_rom, test_rom, args.log);
-        let mut f = if args.log {
-            Some(BufWriter::new(File::create("log.txt").unwrap()))
-        } else {
-            None
-        };
+    let running = Arc::new(AtomicBool::new(true));
+    let emu_running = Arc::clone(&running);
+    let emu_thread =
+        std::thread::spawn(move || run_emulator(&args, cpu, &shared, &input, &emu_running));
 
-        if args.log {
-            // log initial state
-            f.as_mut()
-                .unwrap()
-                .write_all(&cpu.format_state().into_bytes())
-                .unwrap_or_else(|e| warn!("failed to write to buffer {e}"));
-        }
+    let _ = gui_thread.join();
+    // once the display is closed the emulator finishes its current burst, and saves if needed
+    running.store(false, Ordering::Relaxed);
+    let _ = emu_thread.join();
 
-        let cycles_per_second = 4_190_000;
-        let frame_duration = Duration::from_secs_f64(1.0 / 60.0);
-        let target_cycles = cycles_per_second / 60;
-
-        let mut next_frame = Instant::now() + frame_duration;
-        let mut last_mode = cpu.bus.gpu.mode;
-        loop {
-            // do 60 bursts of cycles per second
-            let mut cycles_elapsed = 0;
-            while cycles_elapsed < target_cycles {
-                let was_halted = cpu.halted;
-                let cycles = cpu.step();
-                cycles_elapsed += u32::from(cycles);
-
-                if args.log && cycles > 0 && cpu.pc != 0x50 && !(was_halted && cpu.halted) {
-                    f.as_mut()
-                        .unwrap()
-                        .write_all(&cpu.format_state().into_bytes())
-                        .unwrap_or_else(|e| warn!("failed to write to buffer {e}"));
-                }
+    Ok(ExitCode::SUCCESS)
+}
 
-                if cpu.bus.gpu.mode == Mode::HBlank && last_mode != Mode::HBlank {
-                    let mut buffer = buffer.lock().unwrap();
-                    buffer.copy_from_slice(&*cpu.bus.gpu.buffer);
-                }
-                last_mode = cpu.bus.gpu.mode;
-            }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{display::Headless, joypad::Button};
 
-            if args.log {
-                // flush after every 1/60th burst
-                f.as_mut()
-                    .unwrap()
-                    .flush()
-                    .unwrap_or_else(|e| warn!("failed to flush to file {e}"));
-            }
+    fn parse(args: &[&str]) -> Args {
+        Args::parse_from(std::iter::once("gb-rs").chain(args.iter().copied()))
+    }
 
-            debug!(
-                delta = ?(frame_duration.saturating_sub(next_frame.duration_since(Instant::now()))),
-                target = ?frame_duration,
-                "frame took"
-            );
-            if !next_frame.elapsed().is_zero() {
-                warn!("lagging by {:?}", next_frame.elapsed());
-            }
+    #[test]
+    fn test_vsync_off_is_uncapped() {
+        assert!(paced_to_wall_time(&parse(&[])));
+        assert!(!paced_to_wall_time(&parse(&["--vsync", "off"])));
+        assert!(!paced_to_wall_time(&parse(&["--fast"])));
+        assert!(!paced_to_wall_time(&parse(&["--fast", "--vsync", "on"])));
+    }
 
-            if !args.fast {
-                std::thread::sleep_until(next_frame);
-            }
-            next_frame = Instant::now() + frame_duration;
-        }
-    });
+    #[test]
+    fn test_lcd_off_publishes_blank_frame() {
+        let mut cpu = Cpu::new(None, &[], false);
+        let shared = SharedFrame::new();
+        cpu.bus.write_byte(0xFF40, 0x91);
+        cpu.bus.gpu.buffer.fill(0);
+        let mut last_frame = cpu.bus.gpu.frames;
+        publish_new_frame(&cpu, &shared, &mut last_frame);
+        assert_eq!(shared.published(), 0);
 
-    let _ = gui_thread.join();
-    // let _ = emu_thread.join();
-    // if `gui_thread` has ended it means we should just kill the emulator
+        cpu.bus.write_byte(0xFF40, 0x00);
+        publish_new_frame(&cpu, &shared, &mut last_frame);
+        assert_eq!(shared.published(), 1);
+        assert!(shared.latest().1.iter().all(|&byte| byte == 255));
+    }
+
+    #[test]
+    fn test_run_display() {
+        let shared = SharedFrame::new();
+        let frame = vec![0x42; WIDTH * HEIGHT * 3];
+        shared.publish(&frame);
+        let mut input = InputState::default();
+        input.set_button(Button::Start, true);
+        let input = Mutex::new(input);
 
-    Ok(())
+        // returns once the display closes
+        let mut display = Headless::new(3);
+        run_display(&mut display, None, None, &shared, &input, Vsync::On);
+        assert_eq!(display.frames_presented, 3);
+        assert_eq!(display.last_frame, frame);
+        assert!(!input.lock().unwrap().is_pressed(Button::Start));
+    }
 }
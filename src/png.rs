@@ -0,0 +1,118 @@
+//! Just enough of a PNG encoder to save screenshots. The image data is stored uncompressed, which
+//! keeps it simple at the cost of bigger files
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// The biggest block deflate can store uncompressed
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+/// Encodes tightly packed 8 bit RGB pixels
+pub fn encode(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let row_len = width as usize * 3;
+    assert_eq!(rgb.len(), row_len * height as usize);
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bit depth, RGB, deflate, no filtering, no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // every row starts with its filter type, which is always none
+    let mut scanlines = Vec::with_capacity(rgb.len() + height as usize);
+    for row in rgb.chunks_exact(row_len) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, *b"IHDR", &header);
+    write_chunk(&mut png, *b"IDAT", &zlib_stored(&scanlines));
+    write_chunk(&mut png, *b"IEND", &[]);
+    png
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_chunk(png: &mut Vec<u8>, kind: [u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(&kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data`, which can't be empty, in a zlib stream made of uncompressed deflate blocks
+#[allow(clippy::cast_possible_truncation)]
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32K window, and the lowest compression level
+    let mut stream = vec![0x78, 0x01];
+    let blocks = data.chunks(STORED_BLOCK_SIZE);
+    let count = blocks.len();
+    for (index, block) in blocks.enumerate() {
+        let len = block.len() as u16;
+        stream.push(u8::from(index + 1 == count));
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+            if crc & 1 == 1 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1, 0), |(a, b), byte| {
+        let a = (a + u32::from(*byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cpu::Cpu,
+        gpu::{HEIGHT, WIDTH},
+    };
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    fn test_encode_screenshot() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let screenshot = || {
+            let mut cpu = Cpu::new(None, rom, false);
+            for _ in 0..30 {
+                cpu.run_frame().unwrap();
+            }
+            encode(WIDTH as u32, HEIGHT as u32, &*cpu.bus.gpu.buffer)
+        };
+        let png = screenshot();
+        assert_eq!(png, screenshot());
+
+        assert_eq!(png[..8], SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..24], [0, 0, 0, 160, 0, 0, 0, 144]);
+        assert_eq!(png[png.len() - 8..png.len() - 4], *b"IEND");
+        // the framebuffer plus a filter byte per row, split into 2 stored blocks
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap());
+        assert_eq!(idat_len as usize, 2 + (WIDTH * 3 + 1) * HEIGHT + 2 * 5 + 4);
+    }
+}
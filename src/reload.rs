@@ -0,0 +1,97 @@
+//! `--watch`, which reloads the ROM whenever it's rebuilt so homebrew can be tested without
+//! restarting the emulator
+
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Polls a ROM file for changes. Builds write the file in pieces, so a change is only reported
+/// once the file has stopped changing for the debounce time
+#[derive(Debug)]
+pub struct RomWatcher {
+    path: PathBuf,
+    /// How long the file has to stay the same before it's reloaded, which is long enough for a
+    /// build to finish writing it
+    debounce: Duration,
+    /// The modification time and length last seen, or `None` if the file couldn't be read
+    seen: Option<(SystemTime, u64)>,
+    /// When the file last changed, if it hasn't been reloaded since
+    changed_at: Option<Instant>,
+}
+
+impl RomWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        let mut watcher = Self {
+            path,
+            debounce: Duration::from_millis(250),
+            seen: None,
+            changed_at: None,
+        };
+        watcher.seen = watcher.metadata();
+        watcher
+    }
+
+    fn metadata(&self) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(&self.path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    /// Returns the new ROM once it's changed and settled
+    pub fn poll(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let metadata = self.metadata();
+        if metadata != self.seen {
+            self.seen = metadata;
+            self.changed_at = Some(Instant::now());
+        }
+        // a missing file is most likely part way through being rebuilt
+        if self.seen.is_none() || self.changed_at?.elapsed() < self.debounce {
+            return None;
+        }
+        self.changed_at = None;
+        Some(fs::read(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn test_reload_on_change() {
+        let path = std::env::temp_dir().join(format!("gb-rs-test-{}.gb", std::process::id()));
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x149] = 0x02; // 8KB
+        fs::write(&path, &rom).unwrap();
+        let mut watcher = RomWatcher::new(path.clone());
+        watcher.debounce = Duration::ZERO;
+        assert!(watcher.poll().is_none());
+
+        let mut cpu = Cpu::new(None, &rom, false);
+        cpu.bus.write_byte(0x0000, 0x0A);
+        cpu.bus.write_byte(0xA000, 0x42);
+        cpu.step_unwrap();
+
+        // a rebuild which happens to grow the ROM
+        let mut rebuilt = rom.clone();
+        rebuilt[0x100] = 0x3C; // INC A
+        rebuilt.resize(0x1_0000, 0);
+        fs::write(&path, &rebuilt).unwrap();
+        let new_rom = watcher.poll().unwrap().unwrap();
+        assert_eq!(new_rom, rebuilt);
+        assert!(watcher.poll().is_none());
+        fs::remove_file(&path).unwrap();
+
+        let mut reloaded = cpu.with_cartridge(&new_rom, true);
+        assert_eq!(reloaded.pc, 0x100);
+        assert_eq!(reloaded.bus.read_byte(0x100), 0x3C);
+        reloaded.bus.write_byte(0x0000, 0x0A);
+        assert_eq!(reloaded.bus.read_byte(0xA000), 0x42);
+        let mut wiped = cpu.with_cartridge(&new_rom, false);
+        wiped.bus.write_byte(0x0000, 0x0A);
+        assert_eq!(wiped.bus.read_byte(0xA000), 0);
+    }
+}
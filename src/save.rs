@@ -0,0 +1,94 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::cpu::memorybus::MemoryBus;
+
+/// Writes battery backed external RAM to a save file every so often, so a crash loses at most
+/// one interval of progress
+#[derive(Debug)]
+pub struct AutoSave {
+    path: PathBuf,
+    interval: Duration,
+    last_save: Instant,
+}
+
+impl AutoSave {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        Self {
+            path,
+            interval,
+            last_save: Instant::now(),
+        }
+    }
+
+    /// Copies an existing save into external RAM. A missing save isn't an error
+    pub fn load(&self, bus: &mut MemoryBus) -> io::Result<()> {
+        match fs::read(&self.path) {
+            Ok(bytes) => {
+                bus.load_external_ram(&bytes);
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Saves if external RAM has changed and the interval has passed
+    pub fn tick(&mut self, bus: &mut MemoryBus) -> io::Result<()> {
+        if self.last_save.elapsed() < self.interval {
+            return Ok(());
+        }
+        self.last_save = Instant::now();
+        self.flush(bus)
+    }
+
+    /// Saves straight away if external RAM has changed
+    pub fn flush(&self, bus: &mut MemoryBus) -> io::Result<()> {
+        if !bus.external_ram_dirty {
+            return Ok(());
+        }
+        write_atomically(&self.path, bus.external_ram())?;
+        bus.external_ram_dirty = false;
+        Ok(())
+    }
+}
+
+/// Writes to a temporary file and renames it over `path`, so a crash part way through leaves
+/// the old save intact
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, bytes)?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dirty_flag() {
+        let path = std::env::temp_dir().join(format!("gb-rs-test-{}.sav", std::process::id()));
+        let save = AutoSave::new(path.clone(), Duration::ZERO);
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x149] = 0x02; // 8KB
+        let mut bus = MemoryBus::new(None, &rom, false);
+        assert!(!bus.external_ram_dirty);
+
+        bus.write_byte(0x0000, 0x0A);
+        bus.write_byte(0xA000, 0x42);
+        assert!(bus.external_ram_dirty);
+        save.flush(&mut bus).unwrap();
+        assert!(!bus.external_ram_dirty);
+
+        let mut loaded = MemoryBus::new(None, &rom, false);
+        save.load(&mut loaded).unwrap();
+        loaded.write_byte(0x0000, 0x0A);
+        assert_eq!(loaded.read_byte(0xA000), 0x42);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+//! `--script`, a plain text list of commands which drives the emulator without a display, for
+//! playing up to a point and checking the state there. One command per line, with numbers in hex
+//! and anything after a `#` ignored:
+//!
+//! - `run FRAMES`
+//! - `press BUTTON FRAMES`, holding the button for that many frames then letting go
+//! - `break PC`, running until PC gets there
+//! - `read ADDRESS`
+//! - `write ADDRESS VALUE`
+//! - `assert TARGET VALUE`, where the target is an address or a register like `a`, `hl` or `pc`
+
+use std::str::FromStr;
+
+use parse_display::Display;
+
+use crate::{
+    cpu::{CYCLES_PER_FRAME, Cpu},
+    error::EmuError,
+    hexdump::parse_hex,
+    joypad::Button,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum ScriptError {
+    #[display("line {0}: unknown command")]
+    UnknownCommand(usize),
+    #[display("line {0}: wrong or missing arguments")]
+    BadArguments(usize),
+    #[display("line {line}: expected {expected:04X}, found {actual:04X}")]
+    AssertionFailed {
+        line: usize,
+        expected: u16,
+        actual: u16,
+    },
+    #[display("line {line}: never reached {pc:04X}")]
+    BreakNotReached { line: usize, pc: u16 },
+    #[display("line {line}: {error}")]
+    Emulation { line: usize, error: EmuError },
+}
+
+impl std::error::Error for ScriptError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    A,
+    F,
+    Bc,
+    De,
+    Hl,
+    Sp,
+    Pc,
+    Address(u16),
+}
+
+impl Target {
+    fn value(self, cpu: &Cpu) -> u16 {
+        match self {
+            Self::A => cpu.registers.a.into(),
+            Self::F => cpu.registers.f.bits().into(),
+            Self::Bc => cpu.registers.bc(),
+            Self::De => cpu.registers.de(),
+            Self::Hl => cpu.registers.hl(),
+            Self::Sp => cpu.sp,
+            Self::Pc => cpu.pc,
+            Self::Address(address) => cpu.bus.read_byte(address).into(),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Ok(match text.to_ascii_lowercase().as_str() {
+            "a" => Self::A,
+            "f" => Self::F,
+            "bc" => Self::Bc,
+            "de" => Self::De,
+            "hl" => Self::Hl,
+            "sp" => Self::Sp,
+            "pc" => Self::Pc,
+            _ => Self::Address(parse_hex(text)?),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Run(u16),
+    Press(Button, u16),
+    Break(u16),
+    Read(u16),
+    Write(u16, u8),
+    Assert(Target, u16),
+}
+
+fn parse_button(name: &str) -> Option<Button> {
+    Some(match name.to_ascii_lowercase().as_str() {
+        "a" => Button::A,
+        "b" => Button::B,
+        "select" => Button::Select,
+        "start" => Button::Start,
+        "up" => Button::Up,
+        "down" => Button::Down,
+        "left" => Button::Left,
+        "right" => Button::Right,
+        _ => return None,
+    })
+}
+
+/// A parsed script, with the line each command came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    commands: Vec<(usize, Command)>,
+}
+
+impl FromStr for Script {
+    type Err = ScriptError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut commands = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let words: Vec<_> = line
+                .split('#')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect();
+            let Some((&name, arguments)) = words.split_first() else {
+                continue;
+            };
+            let bad_arguments = ScriptError::BadArguments(line_number);
+            let hex = |index: usize| {
+                arguments
+                    .get(index)
+                    .and_then(|word| parse_hex(word).ok())
+                    .ok_or(bad_arguments)
+            };
+            let expected_arguments = match name {
+                "run" | "break" | "read" => 1,
+                "press" | "write" | "assert" => 2,
+                _ => return Err(ScriptError::UnknownCommand(line_number)),
+            };
+            if arguments.len() != expected_arguments {
+                return Err(bad_arguments);
+            }
+            let command = match name {
+                "run" => Command::Run(hex(0)?),
+                "press" => {
+                    Command::Press(parse_button(arguments[0]).ok_or(bad_arguments)?, hex(1)?)
+                }
+                "break" => Command::Break(hex(0)?),
+                "read" => Command::Read(hex(0)?),
+                "write" => {
+                    Command::Write(hex(0)?, u8::try_from(hex(1)?).map_err(|_| bad_arguments)?)
+                }
+                _ => Command::Assert(arguments[0].parse().map_err(|_| bad_arguments)?, hex(1)?),
+            };
+            commands.push((line_number, command));
+        }
+        Ok(Self { commands })
+    }
+}
+
+impl Script {
+    /// Runs every command in order, stopping at the first failure. Returns each `read`'s address
+    /// and the byte found there
+    pub fn run(&self, cpu: &mut Cpu) -> Result<Vec<(u16, u8)>, ScriptError> {
+        let mut reads = Vec::new();
+        for &(line, command) in &self.commands {
+            let emulation = |error| ScriptError::Emulation { line, error };
+            match command {
+                Command::Run(frames) => {
+                    for _ in 0..frames {
+                        cpu.run_frame().map_err(emulation)?;
+                    }
+                }
+                Command::Press(button, frames) => {
+                    cpu.bus.joypad.set_button(button, true);
+                    for _ in 0..frames {
+                        cpu.run_frame().map_err(emulation)?;
+                    }
+                    cpu.bus.joypad.set_button(button, false);
+                }
+                Command::Break(pc) => {
+                    // give up after a minute of emulated time, like --break-at
+                    let limit = cpu.total_cycles + 60 * 60 * u64::from(CYCLES_PER_FRAME);
+                    while cpu.pc != pc {
+                        if cpu.total_cycles >= limit {
+                            return Err(ScriptError::BreakNotReached { line, pc });
+                        }
+                        cpu.step().map_err(emulation)?;
+                    }
+                }
+                Command::Read(address) => reads.push((address, cpu.bus.read_byte(address))),
+                Command::Write(address, value) => cpu.bus.write_byte(address, value),
+                Command::Assert(target, expected) => {
+                    let actual = target.value(cpu);
+                    if actual != expected {
+                        return Err(ScriptError::AssertionFailed {
+                            line,
+                            expected,
+                            actual,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(reads)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Selects the buttons, then copies P1 to 0xC000 forever
+    const POLL_BUTTONS: [u8; 11] = [
+        0x3E, 0x10, // LD A, $10
+        0xE0, 0x00, // LDH ($00), A
+        0xF0, 0x00, // LDH A, ($00)
+        0xEA, 0x00, 0xC0, // LD ($C000), A
+        0x18, 0xF9, // JR -7
+    ];
+
+    fn cpu() -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + POLL_BUTTONS.len()].copy_from_slice(&POLL_BUTTONS);
+        Cpu::new(None, &rom, false)
+    }
+
+    #[test]
+    fn test_press_start() {
+        let script: Script = "
+            press start 2 # start is bit 3
+            read C000
+            run 1
+            read $C000
+            assert c000 DF
+            break 0104
+            assert pc 104
+        "
+        .parse()
+        .unwrap();
+        let mut cpu = cpu();
+        assert_eq!(
+            script.run(&mut cpu),
+            Ok(vec![(0xC000, 0xD7), (0xC000, 0xDF)])
+        );
+
+        let script: Script = "write C000 12\nassert C000 34".parse().unwrap();
+        assert_eq!(
+            script.run(&mut cpu),
+            Err(ScriptError::AssertionFailed {
+                line: 2,
+                expected: 0x34,
+                actual: 0x12
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            "run 1\njump 100".parse::<Script>(),
+            Err(ScriptError::UnknownCommand(2))
+        );
+        assert_eq!(
+            "press turbo 1".parse::<Script>(),
+            Err(ScriptError::BadArguments(1))
+        );
+        assert_eq!(
+            "write C000 100".parse::<Script>(),
+            Err(ScriptError::BadArguments(1))
+        );
+        assert_eq!("run".parse::<Script>(), Err(ScriptError::BadArguments(1)));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Cycles it takes to shift out a single bit using the internal clock
+const CYCLES_PER_BIT: u16 = 512;
+
+#[derive(Debug, Default, Clone)]
+pub struct Serial {
+    /// SB
+    pub data: u8,
+    /// SC
+    control: u8,
+
+    /// Cycles left until the current transfer finishes
+    cycles_left: u16,
+    /// Every byte sent, which is how test ROMs report their results
+    pub output: Vec<u8>,
+    /// The byte from the last transfer this side clocked, until whatever is on the other end of
+    /// the cable takes it
+    pub completed: Option<u8>,
+}
+
+impl Serial {
+    pub const fn read_control(&self) -> u8 {
+        // unused bits always read as 1
+        self.control | 0b0111_1110
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.control = value;
+        if self.is_transferring() && self.is_internal_clock() {
+            self.cycles_left = 8 * CYCLES_PER_BIT;
+            self.output.push(self.data);
+        }
+    }
+
+    /// Returns if interrupt should be triggered
+    pub const fn step(&mut self, cycles: u8) -> bool {
+        // with an external clock the transfer only progresses when the other side drives it,
+        // and there is never another side
+        if !self.is_transferring() || !self.is_internal_clock() {
+            return false;
+        }
+
+        self.cycles_left = self.cycles_left.saturating_sub(cycles as u16);
+        if self.cycles_left > 0 {
+            return false;
+        }
+
+        // nothing is connected, so all 1s are shifted in. A link replaces this with the byte
+        // from the other side
+        self.completed = Some(self.data);
+        self.data = 0xFF;
+        self.control &= !0b1000_0000;
+        true
+    }
+
+    /// Called when the other end of the cable clocks `byte` over. If this side is waiting on the
+    /// external clock it's swapped with SB, returning the byte sent back. The serial interrupt
+    /// should fire when it does. Only the link cable test harness has another end to plug in
+    #[cfg(test)]
+    pub const fn receive_external(&mut self, byte: u8) -> Option<u8> {
+        if !self.is_transferring() || self.is_internal_clock() {
+            return None;
+        }
+        let sent = self.data;
+        self.data = byte;
+        self.control &= !0b1000_0000;
+        Some(sent)
+    }
+
+    const fn is_transferring(&self) -> bool {
+        self.control & 0b1000_0000 != 0
+    }
+
+    const fn is_internal_clock(&self) -> bool {
+        self.control & 0b1 != 0
+    }
+}
+
+/// Whether a blargg style test ROM has reported passing or failing over serial, or `None` if it
+/// hasn't finished yet
+pub fn test_verdict(output: &[u8]) -> Option<bool> {
+    let output = String::from_utf8_lossy(output);
+    if output.contains("Passed") {
+        Some(true)
+    } else if output.contains("Failed") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Captured output isn't part of the state
+impl Snapshot for Serial {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.data);
+        state.u8(self.control);
+        state.u16(self.cycles_left);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data = state.u8()?;
+        self.control = state.u8()?;
+        self.cycles_left = state.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_test_verdict() {
+        assert_eq!(test_verdict(b"instr_timing\n\n"), None);
+        assert_eq!(test_verdict(b"instr_timing\n\n\nPassed\n"), Some(true));
+        assert_eq!(test_verdict(b"01-special\n\nFailed #3\n"), Some(false));
+    }
+
+    #[test]
+    fn test_captures_output() {
+        let mut serial = Serial::default();
+        for byte in *b"Hi" {
+            serial.data = byte;
+            serial.write_control(0x81);
+            while !serial.step(4) {}
+        }
+        assert_eq!(serial.output, b"Hi");
+    }
+}
@@ -0,0 +1,133 @@
+//! Super Game Boy detection, and a receiver for the command packets SGB games send through P1.
+//! The commands aren't acted on, they're only captured and logged
+
+use tracing::debug;
+
+pub const PACKET_SIZE: usize = 16;
+
+/// Whether the cartridge header says the game uses SGB functions
+pub fn supported(game_rom: &[u8]) -> bool {
+    // the old licensee code has to be 0x33 as well, or the SGB ignores the flag
+    game_rom.get(0x146) == Some(&0x03) && game_rom.get(0x14B) == Some(&0x33)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum State {
+    /// Waiting for a reset pulse to start a packet
+    #[default]
+    Idle,
+    /// Shifting in the packet's bits, LSB first
+    Receiving { bits: usize },
+    /// All 128 bits are in, waiting for the 0 stop bit
+    Stop,
+}
+
+/// Decodes the pulses written to P1 into packets. Pulling both P14 and P15 low is a reset which
+/// starts a packet, then each pulse on P14 alone is a 0 and on P15 alone is a 1
+#[derive(Debug, Clone, Default)]
+pub struct CommandReceiver {
+    state: State,
+    packet: [u8; PACKET_SIZE],
+    /// Whether both lines were released since the last pulse
+    released: bool,
+    /// Every packet received
+    pub packets: Vec<[u8; PACKET_SIZE]>,
+}
+
+impl CommandReceiver {
+    pub fn write_p1(&mut self, value: u8) {
+        let (p14, p15) = (value & 0x10 != 0, value & 0x20 != 0);
+        if p14 && p15 {
+            self.released = true;
+            return;
+        }
+        if !p14 && !p15 {
+            self.state = State::Receiving { bits: 0 };
+            self.packet = [0; PACKET_SIZE];
+            self.released = false;
+            return;
+        }
+        if !self.released {
+            return;
+        }
+        self.released = false;
+
+        let bit = !p15;
+        match self.state {
+            State::Idle => {}
+            State::Receiving { bits } => {
+                self.packet[bits / 8] |= u8::from(bit) << (bits % 8);
+                self.state = if bits + 1 == PACKET_SIZE * 8 {
+                    State::Stop
+                } else {
+                    State::Receiving { bits: bits + 1 }
+                };
+            }
+            State::Stop => {
+                self.state = State::Idle;
+                if bit {
+                    debug!("SGB packet without a stop bit {:02X?}", self.packet);
+                } else {
+                    debug!(
+                        command = self.packet[0] >> 3,
+                        "SGB packet {:02X?}", self.packet
+                    );
+                    self.packets.push(self.packet);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cpu::memorybus::MemoryBus, joypad::Button};
+
+    /// The P1 writes which send `packet`
+    fn pulses(packet: &[u8; PACKET_SIZE]) -> Vec<u8> {
+        let mut writes = vec![0x00, 0x30];
+        for bit in 0..PACKET_SIZE * 8 {
+            let one = packet[bit / 8] >> (bit % 8) & 1 == 1;
+            writes.extend([if one { 0x10 } else { 0x20 }, 0x30]);
+        }
+        writes.extend([0x20, 0x30]);
+        writes
+    }
+
+    #[test]
+    fn test_packets_are_captured() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x146] = 0x03;
+        rom[0x14B] = 0x33;
+        let mut bus = MemoryBus::new(None, &rom, false);
+        let mut plain = MemoryBus::new(None, &vec![0; 0x8000], false);
+        assert!(bus.sgb.is_some());
+        assert!(plain.sgb.is_none());
+
+        // MLT_REQ asking for two players, which is how games check for an SGB
+        let mut mlt_req = [0; PACKET_SIZE];
+        mlt_req[..2].copy_from_slice(&[0x89, 0x01]);
+        for bus in [&mut bus, &mut plain] {
+            bus.joypad.set_button(Button::A, true);
+            bus.joypad.set_button(Button::Left, true);
+        }
+        for value in pulses(&mlt_req) {
+            bus.write_byte(0xFF00, value);
+            plain.write_byte(0xFF00, value);
+            assert_eq!(bus.read_byte(0xFF00), plain.read_byte(0xFF00));
+        }
+        assert_eq!(bus.sgb.unwrap().packets, [mlt_req]);
+    }
+
+    #[test]
+    fn test_joypad_polling_isnt_a_packet() {
+        let mut receiver = CommandReceiver::default();
+        for _ in 0..100 {
+            for value in [0x20, 0x10, 0x30] {
+                receiver.write_p1(value);
+            }
+        }
+        assert!(receiver.packets.is_empty());
+    }
+}
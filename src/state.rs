@@ -0,0 +1,252 @@
+//! Save states, snapshots of everything needed to pick a run back up later
+
+use parse_display::Display;
+
+use crate::cpu::{Cpu, memorybus::MemoryBus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum StateError {
+    #[display("save state ended early")]
+    Truncated,
+    #[display("save state has {0} bytes left over")]
+    TrailingBytes(usize),
+    #[display("not a save state")]
+    NotAState,
+    #[display("save state is version {found}, but this build only loads version {expected}")]
+    UnsupportedVersion { found: u16, expected: u16 },
+    #[display("save state is for a different ROM")]
+    RomMismatch,
+    #[display("save state has an invalid {0}")]
+    Invalid(&'static str),
+}
+
+impl std::error::Error for StateError {}
+
+/// The first bytes of every save state
+const MAGIC: [u8; 4] = *b"GBRS";
+/// Bumped whenever the layout of a state changes, so states from other builds are turned away
+/// instead of being loaded as garbage
+pub const VERSION: u16 = 1;
+
+/// Something which can be saved into and restored from a save state. Fields are written and read
+/// back in the same order, without any tagging
+pub trait Snapshot {
+    fn save(&self, state: &mut StateWriter);
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(u8::from(value));
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub const fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let (value, rest) = self
+            .bytes
+            .split_first_chunk()
+            .ok_or(StateError::Truncated)?;
+        self.bytes = rest;
+        Ok(*value)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    pub fn usize(&mut self) -> Result<usize, StateError> {
+        usize::try_from(self.u64()?).map_err(|_| StateError::Invalid("size"))
+    }
+
+    /// Fills all of `bytes`
+    pub fn bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        let (value, rest) = self
+            .bytes
+            .split_at_checked(bytes.len())
+            .ok_or(StateError::Truncated)?;
+        bytes.copy_from_slice(value);
+        self.bytes = rest;
+        Ok(())
+    }
+
+    pub const fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+/// Identifies the cartridge a state belongs to, with an FNV-1a hash of its header. The header
+/// ends with the global checksum, so this covers the whole ROM too
+fn rom_id(bus: &MemoryBus) -> u64 {
+    bus.cartridge_header()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+pub fn save(cpu: &Cpu) -> Vec<u8> {
+    let mut state = StateWriter::default();
+    state.bytes(&MAGIC);
+    state.u16(VERSION);
+    state.u64(rom_id(&cpu.bus));
+    cpu.save(&mut state);
+    state.into_bytes()
+}
+
+/// Restores a state saved with the same ROM. The cpu is left alone if it can't be loaded
+pub fn load(cpu: &mut Cpu, bytes: &[u8]) -> Result<(), StateError> {
+    let mut state = StateReader::new(bytes);
+    if state.take::<4>() != Ok(MAGIC) {
+        return Err(StateError::NotAState);
+    }
+    let version = state.u16()?;
+    if version != VERSION {
+        return Err(StateError::UnsupportedVersion {
+            found: version,
+            expected: VERSION,
+        });
+    }
+    if state.u64()? != rom_id(&cpu.bus) {
+        return Err(StateError::RomMismatch);
+    }
+    let mut loaded = cpu.power_cycled();
+    loaded.load(&mut state)?;
+    if state.remaining() > 0 {
+        return Err(StateError::TrailingBytes(state.remaining()));
+    }
+    loaded.bus.take_io_devices(&mut cpu.bus);
+    *cpu = loaded;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CYCLES_PER_FRAME;
+
+    #[test]
+    fn test_round_trip_through_file() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let mut cpu = Cpu::new(None, rom, false);
+        cpu.step_cycles(CYCLES_PER_FRAME * 10).unwrap();
+
+        let path = std::env::temp_dir().join(format!("gb-rs-test-{}.state", std::process::id()));
+        std::fs::write(&path, save(&cpu)).unwrap();
+        let mut loaded = Cpu::new(None, rom, false);
+        load(&mut loaded, &std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.format_state(), cpu.format_state());
+        assert_eq!(loaded.total_cycles, cpu.total_cycles);
+
+        // both carry on the same way
+        for _ in 0..5 {
+            cpu.run_frame().unwrap();
+            loaded.run_frame().unwrap();
+        }
+        assert_eq!(loaded.format_state(), cpu.format_state());
+        assert_eq!(loaded.bus.gpu.frame_hash(), cpu.bus.gpu.frame_hash());
+    }
+
+    #[test]
+    fn test_rejects_other_roms() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let other = include_bytes!("../test_roms/instr_timing/instr_timing.gb");
+        let state = save(&Cpu::new(None, rom, false));
+
+        let mut cpu = Cpu::new(None, other, false);
+        assert_eq!(load(&mut cpu, &state), Err(StateError::RomMismatch));
+        let mut cpu = Cpu::new(None, rom, false);
+        cpu.step_unwrap();
+        assert_eq!(
+            load(&mut cpu, &state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        // a failed load doesn't change anything
+        assert_eq!(cpu.total_instructions, 1);
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let mut cpu = Cpu::new(None, rom, false);
+        let mut state = save(&cpu);
+        state[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let error = load(&mut cpu, &state).unwrap_err();
+        assert_eq!(
+            error,
+            StateError::UnsupportedVersion {
+                found: VERSION + 1,
+                expected: VERSION
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "save state is version {}, but this build only loads version {VERSION}",
+                VERSION + 1
+            )
+        );
+
+        assert_eq!(load(&mut cpu, b"PNG"), Err(StateError::NotAState));
+        assert_eq!(load(&mut cpu, &[0; 64]), Err(StateError::NotAState));
+    }
+}
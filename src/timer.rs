@@ -1,75 +1,216 @@
+use crate::{
+    cpu::memorybus::io::IoDevice,
+    state::{Snapshot, StateError, StateReader, StateWriter},
+};
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Timer {
     pub control: u8,
-    /// DIV
+    /// DIV, the upper byte of the internal counter
     pub divider: u8,
     /// TIMA
     pub counter: u8,
     /// TMA
     pub modulo: u8,
 
-    /// Internal counter to track how many cycles its been since the last divider increment
+    /// The lower byte of the internal counter, which goes up every cycle
     divider_counter: u8,
-    /// Internal counter to track how many cycles its been since timer was last incremented
-    counter_counter: u16,
 }
 
 impl Timer {
     /// Returns if interrupt should be triggered
+    #[allow(clippy::cast_possible_truncation)]
     pub fn step(&mut self, cycles: u8) -> bool {
-        let (divider, div_counter) = self.increment_div(cycles);
-        let mut did_overflow = false;
+        let before = u32::from(self.internal_counter());
+        let after = before + u32::from(cycles);
+        [self.divider, self.divider_counter] = (after as u16).to_be_bytes();
 
-        self.divider = divider;
-        self.divider_counter = div_counter;
+        let mut did_overflow = false;
         if self.is_enabled() {
-            let cycle_target = self.cycle_speed() * 4;
-            self.counter_counter += u16::from(cycles);
-
-            // the longest an instruction can take is at least 20 cycles, and the timer can step as
-            // quickly as every 16 cycles, so we need to loop here
-            while self.counter_counter >= cycle_target {
-                self.counter_counter -= cycle_target;
-                let (counter, overflow) = self.counter.overflowing_add(1);
-
-                // FIXME: If a TMA write is executed on the same M-cycle as the content of TMA
-                // is transferred to TIMA due to a timer overflow, the old value is transferred
-                // to TIMA.
-
-                self.counter = if overflow { self.modulo } else { counter };
-                if overflow {
-                    did_overflow = true;
-                }
+            // TIMA goes up whenever the selected bit of the internal counter falls, which happens
+            // every time the bits below it wrap around. The timer can step as quickly as every 16
+            // cycles, so an instruction can tick it more than once.
+            let shift = self.counter_bit() + 1;
+            for _ in 0..(after >> shift) - (before >> shift) {
+                did_overflow |= self.increment_counter();
             }
         }
 
         did_overflow
     }
 
+    /// Writing to DIV clears the whole internal counter. If the bit TIMA is watching was set, that
+    /// counts as it falling and TIMA goes up early. Returns if interrupt should be triggered
+    pub const fn reset_divider(&mut self) -> bool {
+        let was_set = self.timer_signal();
+        self.divider = 0;
+        self.divider_counter = 0;
+        was_set && self.increment_counter()
+    }
+
+    /// Writes TAC. TIMA watches the selected bit and the enable bit together, so switching to a
+    /// bit which is clear, or turning the timer off, while the old bit was set is a falling edge
+    /// and TIMA goes up. Returns if interrupt should be triggered
+    pub const fn write_control(&mut self, value: u8) -> bool {
+        let was_set = self.timer_signal();
+        self.control = value;
+        was_set && !self.timer_signal() && self.increment_counter()
+    }
+
+    /// The signal whose falling edge clocks TIMA
+    const fn timer_signal(self) -> bool {
+        self.is_enabled() && self.internal_counter() >> self.counter_bit() & 1 == 1
+    }
+
     pub const fn is_enabled(self) -> bool {
         self.control & 0b100 == 0b100
     }
 
-    /// Returns the clock speed in M-states
-    pub const fn cycle_speed(self) -> u16 {
+    /// Which bit of the internal counter clocks TIMA
+    pub const fn counter_bit(self) -> u32 {
         let clock_select = self.control & 0b11;
         match clock_select {
-            0b00 => 256, // 256 M-states
-            0b01 => 4,   // 4 M-states
-            0b10 => 16,  // 16 M-states
-            0b11 => 64,  // 64 M-states
+            0b00 => 9, // 256 M-states
+            0b01 => 3, // 4 M-states
+            0b10 => 5, // 16 M-states
+            0b11 => 7, // 64 M-states
             _ => unreachable!(),
         }
     }
 
-    const fn increment_div(self, cycles: u8) -> (u8, u8) {
-        // divider is incremented at a rate of 16,384Hz - every 256 T-states
-        let (counter, overflow) = self.divider_counter.overflowing_add(cycles);
-        let divider = if overflow {
-            self.divider.wrapping_add(1)
-        } else {
-            self.divider
+    const fn internal_counter(self) -> u16 {
+        u16::from_be_bytes([self.divider, self.divider_counter])
+    }
+
+    /// Returns if TIMA overflowed
+    const fn increment_counter(&mut self) -> bool {
+        // FIXME: If a TMA write is executed on the same M-cycle as the content of TMA
+        // is transferred to TIMA due to a timer overflow, the old value is transferred
+        // to TIMA.
+        let (counter, overflow) = self.counter.overflowing_add(1);
+        self.counter = if overflow { self.modulo } else { counter };
+        overflow
+    }
+}
+
+/// DIV, TIMA, TMA and TAC. Writing DIV or TAC can raise an interrupt, so the bus calls
+/// [`Timer::reset_divider`] and [`Timer::write_control`] itself
+impl IoDevice for Timer {
+    fn read(&self, address: usize) -> u8 {
+        match address {
+            0xFF04 => self.divider,
+            0xFF05 => self.counter,
+            0xFF06 => self.modulo,
+            0xFF07 => self.control,
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u8) {
+        match address {
+            0xFF05 => self.counter = value,
+            0xFF06 => self.modulo = value,
+            0xFF07 => {
+                self.write_control(value);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Snapshot for Timer {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.control);
+        state.u8(self.divider);
+        state.u8(self.counter);
+        state.u8(self.modulo);
+        state.u8(self.divider_counter);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.control = state.u8()?;
+        self.divider = state.u8()?;
+        self.counter = state.u8()?;
+        self.modulo = state.u8()?;
+        self.divider_counter = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_div_write_resets_internal_counter() {
+        let mut timer = Timer::default();
+        timer.step(200);
+        timer.step(100);
+        assert_eq!(timer.divider, 1);
+        assert!(!timer.reset_divider());
+        assert_eq!(timer.divider, 0);
+        // the cycles since the last increment were thrown away too
+        timer.step(255);
+        assert_eq!(timer.divider, 0);
+        timer.step(1);
+        assert_eq!(timer.divider, 1);
+    }
+
+    #[test]
+    fn test_div_write_ticks_timer() {
+        // 16 cycles per tick, clocked by bit 3
+        let mut timer = Timer {
+            control: 0b101,
+            ..Timer::default()
         };
-        (divider, counter)
+        timer.step(4);
+        timer.reset_divider();
+        assert_eq!(timer.counter, 0);
+
+        timer.step(8);
+        timer.reset_divider();
+        assert_eq!(timer.counter, 1);
+        // and the next tick is a full period after the write
+        timer.step(12);
+        assert_eq!(timer.counter, 1);
+        timer.step(4);
+        assert_eq!(timer.counter, 2);
+
+        // the extra tick can overflow TIMA too
+        timer.counter = 0xFF;
+        timer.modulo = 0x80;
+        timer.step(8);
+        assert!(timer.reset_divider());
+        assert_eq!(timer.counter, 0x80);
+    }
+
+    #[test]
+    fn test_tac_write_ticks_timer() {
+        // 16 cycles per tick, clocked by bit 3
+        let mut timer = Timer {
+            control: 0b101,
+            ..Timer::default()
+        };
+        timer.step(8);
+        assert_eq!(timer.counter, 0);
+        // bit 9, which the 1024 cycle rate watches, is still clear, so it looks like bit 3 fell
+        assert!(!timer.write_control(0b100));
+        assert_eq!(timer.counter, 1);
+
+        // and the same the other way round, once bit 9 is set and bit 3 isn't
+        timer.step(250);
+        timer.step(254);
+        assert_eq!(timer.internal_counter(), 512);
+        timer.write_control(0b101);
+        assert_eq!(timer.counter, 2);
+        // going from a clear bit to a set one changes nothing
+        timer.write_control(0b100);
+        assert_eq!(timer.counter, 2);
+
+        // turning the timer off while the bit is set is a falling edge too
+        timer.write_control(0b000);
+        assert_eq!(timer.counter, 3);
+        timer.write_control(0b100);
+        assert_eq!(timer.counter, 3);
     }
 }
@@ -0,0 +1,378 @@
+//! Lockstep comparison against another emulator's log, in the Gameboy Doctor format
+
+use std::{collections::VecDeque, fmt, fmt::Write as _, str::FromStr};
+
+use parse_display::Display;
+
+use crate::{cpu::Cpu, diagnostics::warning};
+
+/// A single line of a Gameboy Doctor log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub pcmem: [u8; 4],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+pub enum ParseStateError {
+    #[display("missing field {0}")]
+    MissingField(&'static str),
+    #[display("invalid value for {0}: {1:?}")]
+    InvalidValue(&'static str, String),
+}
+
+impl std::error::Error for ParseStateError {}
+
+/// The first field which differs between two states
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[display("{field} is {actual:02X}, expected {expected:02X}")]
+pub struct Divergence {
+    pub field: &'static str,
+    pub actual: u16,
+    pub expected: u16,
+}
+
+impl CpuState {
+    pub fn capture(cpu: &Cpu) -> Self {
+        Self {
+            a: cpu.registers.a,
+            f: cpu.registers.f.bits(),
+            b: cpu.registers.b,
+            c: cpu.registers.c,
+            d: cpu.registers.d,
+            e: cpu.registers.e,
+            h: cpu.registers.h,
+            l: cpu.registers.l,
+            sp: cpu.sp,
+            pc: cpu.pc,
+            pcmem: [0, 1, 2, 3].map(|offset| cpu.bus.read_byte(cpu.pc.wrapping_add(offset))),
+        }
+    }
+
+    fn fields(&self) -> [(&'static str, u16); 14] {
+        [
+            ("PC", self.pc),
+            ("A", self.a.into()),
+            ("F", self.f.into()),
+            ("B", self.b.into()),
+            ("C", self.c.into()),
+            ("D", self.d.into()),
+            ("E", self.e.into()),
+            ("H", self.h.into()),
+            ("L", self.l.into()),
+            ("SP", self.sp),
+            ("PCMEM[0]", self.pcmem[0].into()),
+            ("PCMEM[1]", self.pcmem[1].into()),
+            ("PCMEM[2]", self.pcmem[2].into()),
+            ("PCMEM[3]", self.pcmem[3].into()),
+        ]
+    }
+
+    /// Compares against `expected`, checking PC before the registers
+    pub fn first_difference(&self, expected: &Self) -> Option<Divergence> {
+        self.fields()
+            .into_iter()
+            .zip(expected.fields())
+            .find(|((_, actual), (_, expected))| actual != expected)
+            .map(|((field, actual), (_, expected))| Divergence {
+                field,
+                actual,
+                expected,
+            })
+    }
+}
+
+/// The same format as `Cpu::format_state`, without the bank
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [m0, m1, m2, m3] = self.pcmem;
+        write!(
+            f,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} \
+            PC:{:04X} PCMEM:{m0:02X},{m1:02X},{m2:02X},{m3:02X}",
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.pc,
+        )
+    }
+}
+
+impl FromStr for CpuState {
+    type Err = ParseStateError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let field = |name: &'static str| {
+            line.split_whitespace()
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix(':'))
+                .ok_or(ParseStateError::MissingField(name))
+        };
+        let byte = |name: &'static str| {
+            let value = field(name)?;
+            u8::from_str_radix(value, 16)
+                .map_err(|_| ParseStateError::InvalidValue(name, value.to_owned()))
+        };
+        let word = |name: &'static str| {
+            let value = field(name)?;
+            u16::from_str_radix(value, 16)
+                .map_err(|_| ParseStateError::InvalidValue(name, value.to_owned()))
+        };
+
+        let pcmem = field("PCMEM")?;
+        let mut bytes = pcmem.split(',').map(|byte| u8::from_str_radix(byte, 16));
+        let mut next = || {
+            bytes
+                .next()
+                .and_then(Result::ok)
+                .ok_or_else(|| ParseStateError::InvalidValue("PCMEM", pcmem.to_owned()))
+        };
+
+        Ok(Self {
+            a: byte("A")?,
+            f: byte("F")?,
+            b: byte("B")?,
+            c: byte("C")?,
+            d: byte("D")?,
+            e: byte("E")?,
+            h: byte("H")?,
+            l: byte("L")?,
+            sp: word("SP")?,
+            pc: word("PC")?,
+            pcmem: [next()?, next()?, next()?, next()?],
+        })
+    }
+}
+
+/// Why a run stopped matching the reference log
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+pub enum TraceError {
+    #[display("line {line}: couldn't parse reference state: {error}")]
+    Parse { line: usize, error: ParseStateError },
+    #[display("line {line}: diverged at PC {pc:04X}: {divergence}")]
+    Diverged {
+        line: usize,
+        pc: u16,
+        divergence: Divergence,
+    },
+    #[display("reference log ended after {0} lines")]
+    Ended(usize),
+}
+
+impl std::error::Error for TraceError {}
+
+/// Steps through a reference log one line per logged cpu state
+pub struct TraceComparer<I> {
+    lines: I,
+    line: usize,
+}
+
+impl<I: Iterator<Item = String>> TraceComparer<I> {
+    pub const fn new(lines: I) -> Self {
+        Self { lines, line: 0 }
+    }
+
+    /// Checks the cpu against the next line of the reference log
+    pub fn check(&mut self, cpu: &Cpu) -> Result<(), TraceError> {
+        let Some(expected) = self.lines.next() else {
+            return Err(TraceError::Ended(self.line));
+        };
+        self.line += 1;
+        let line = self.line;
+        let expected: CpuState = expected
+            .parse()
+            .map_err(|error| TraceError::Parse { line, error })?;
+
+        let actual = CpuState::capture(cpu);
+        actual
+            .first_difference(&expected)
+            .map_or(Ok(()), |divergence| {
+                Err(TraceError::Diverged {
+                    line,
+                    pc: expected.pc,
+                    divergence,
+                })
+            })
+    }
+}
+
+/// The last `capacity` states, so a crash can be explained without logging every instruction.
+/// They're printed if the emulator thread panics while this is alive
+#[derive(Debug)]
+pub struct TraceRing {
+    states: VecDeque<CpuState>,
+    capacity: usize,
+}
+
+impl TraceRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            states: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, cpu: &Cpu) {
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+        if self.capacity > 0 {
+            self.states.push_back(CpuState::capture(cpu));
+        }
+    }
+
+    /// Oldest first
+    #[cfg(test)]
+    pub fn states(&self) -> impl Iterator<Item = &CpuState> {
+        self.states.iter()
+    }
+
+    /// Reports every recorded state as a single warning, so an installed sink gets all of it
+    pub fn dump(&self) {
+        let mut states = String::new();
+        for state in &self.states {
+            write!(states, "\n{state}").unwrap();
+        }
+        warning!("last {} states:{states}", self.states.len());
+    }
+}
+
+impl Drop for TraceRing {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.dump();
+        }
+    }
+}
+
+/// Narrows `--log` down to the part of a run that's interesting
+#[derive(Debug, Default)]
+pub struct LogWindow {
+    /// Start logging once PC reaches this address
+    pub start_pc: Option<u16>,
+    /// Start logging once this many instructions have run
+    pub start_after: u64,
+    /// Stop after logging this many states
+    pub count: Option<u64>,
+
+    started: bool,
+    logged: u64,
+}
+
+impl LogWindow {
+    pub const fn new(start_pc: Option<u16>, start_after: u64, count: Option<u64>) -> Self {
+        Self {
+            start_pc,
+            start_after,
+            count,
+            started: false,
+            logged: 0,
+        }
+    }
+
+    /// Called with every state that could be logged, returning if it should be
+    pub fn should_log(&mut self, pc: u16, instructions: u64) -> bool {
+        if !self.started {
+            self.started = instructions >= self.start_after
+                && self.start_pc.is_none_or(|start_pc| start_pc == pc);
+            if !self.started {
+                return false;
+            }
+        }
+        if self.count.is_some_and(|count| self.logged >= count) {
+            return false;
+        }
+        self.logged += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_matches_format_state() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let cpu = Cpu::new(None, rom, true);
+        let state: CpuState = cpu.format_state().parse().unwrap();
+        assert_eq!(state, CpuState::capture(&cpu));
+        assert_eq!(state.pc, 0x100);
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let cpu = Cpu::new(None, rom, true);
+        let state = CpuState::capture(&cpu);
+        assert_eq!(state.to_string().parse(), Ok(state));
+        assert_eq!(format!("{state}\n"), cpu.format_state());
+    }
+
+    #[test]
+    fn test_trace_ring_keeps_states_before_error() {
+        let mut rom = vec![0; 0x8000];
+        // NOP, NOP, NOP, NOP, then an illegal opcode
+        rom[0x104] = 0xD3;
+        let mut cpu = Cpu::new(None, &rom, false);
+        let mut ring = TraceRing::new(3);
+        while cpu.step().is_ok() {
+            ring.record(&cpu);
+        }
+        let pcs: Vec<u16> = ring.states().map(|state| state.pc).collect();
+        assert_eq!(pcs, [0x102, 0x103, 0x104]);
+        assert_eq!(ring.states().last().unwrap().pcmem[0], 0xD3);
+    }
+
+    #[test]
+    fn test_log_window() {
+        let mut rom = vec![0; 0x8000];
+        // NOP, NOP, JP $0100
+        rom[0x100..0x105].copy_from_slice(&[0x00, 0x00, 0xC3, 0x00, 0x01]);
+        let run = |mut window: LogWindow| {
+            let mut cpu = Cpu::new(None, &rom, false);
+            let mut logged = Vec::new();
+            for _ in 0..12 {
+                if window.should_log(cpu.pc, cpu.total_instructions) {
+                    logged.push(cpu.pc);
+                }
+                cpu.step_unwrap();
+            }
+            logged
+        };
+
+        assert_eq!(
+            run(LogWindow::new(Some(0x102), 0, Some(3))),
+            [0x102, 0x100, 0x101]
+        );
+        assert_eq!(run(LogWindow::new(None, 4, Some(2))), [0x101, 0x102]);
+    }
+
+    #[test]
+    fn test_reports_first_divergence() {
+        let rom = include_bytes!("../test_roms/cpu_instrs/individual/06-ld r,r.gb");
+        let mut cpu = Cpu::new(None, rom, true);
+        let mut log = vec![cpu.format_state()];
+        cpu.step_unwrap();
+        log.push(cpu.format_state().replace("B:00", "B:01"));
+        let mut comparer = TraceComparer::new(log.into_iter());
+
+        let mut cpu = Cpu::new(None, rom, true);
+        assert_eq!(comparer.check(&cpu), Ok(()));
+        cpu.step_unwrap();
+        let Err(TraceError::Diverged {
+            line, divergence, ..
+        }) = comparer.check(&cpu)
+        else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(line, 2);
+        assert_eq!(divergence.field, "B");
+        assert_eq!(comparer.check(&cpu), Err(TraceError::Ended(2)));
+    }
+}
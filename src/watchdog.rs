@@ -0,0 +1,108 @@
+//! Notices when the CPU is stuck in one place, so a hang from a bad decode doesn't go silently
+
+use parse_display::Display;
+
+use crate::{cpu::Cpu, disassembler::parse_instruction};
+
+/// Where the CPU got stuck
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+#[display("cpu stuck at {pc:04X} ({instruction}) for {steps} steps")]
+pub struct Hang {
+    pub pc: u16,
+    pub instruction: String,
+    pub steps: u64,
+}
+
+/// Tracks PC across steps, reporting once each time it stops moving
+#[derive(Debug)]
+pub struct Watchdog {
+    /// How many steps PC can stay put before it's reported
+    threshold: u64,
+    last_pc: Option<u16>,
+    steps: u64,
+    reported: bool,
+}
+
+impl Watchdog {
+    pub const fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            last_pc: None,
+            steps: 0,
+            reported: false,
+        }
+    }
+
+    /// Called after every step. A jump to itself with interrupts off can never get out, so that's
+    /// reported straight away instead of waiting for the threshold
+    pub fn check(&mut self, cpu: &Cpu) -> Option<Hang> {
+        // a halted cpu is waiting for an interrupt, not stuck
+        if self.last_pc == Some(cpu.pc) && !cpu.halted {
+            self.steps += 1;
+        } else {
+            self.last_pc = Some(cpu.pc);
+            self.steps = 0;
+            self.reported = false;
+        }
+        if self.reported {
+            return None;
+        }
+
+        let bytes = cpu.bus.slice_from(cpu.pc);
+        let jumps_to_self = match bytes {
+            // JR -2
+            [0x18, 0xFE, ..] => true,
+            [0xC3, low, high, _] => u16::from_le_bytes([low, high]) == cpu.pc,
+            _ => false,
+        };
+        let trapped = jumps_to_self && !cpu.interrupts_enabled && !cpu.bus.is_interrupt_pending();
+        if !trapped && self.steps < self.threshold {
+            return None;
+        }
+
+        self.reported = true;
+        let instruction = parse_instruction(&bytes).map_or_else(
+            |_| "undecodable".to_owned(),
+            |(_, instruction)| instruction.to_string(),
+        );
+        Some(Hang {
+            pc: cpu.pc,
+            instruction,
+            steps: self.steps,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_jump_to_self() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        let mut cpu = Cpu::new(None, &rom, false);
+        let mut watchdog = Watchdog::new(1000);
+        cpu.step_unwrap();
+        let hang = watchdog.check(&cpu).unwrap();
+        assert_eq!(hang.pc, 0x100);
+        // only reported once
+        cpu.step_unwrap();
+        assert_eq!(watchdog.check(&cpu), None);
+    }
+
+    #[test]
+    fn test_threshold() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0xFB, 0x00, 0x18, 0xFE]); // EI; NOP; JR -2
+        let mut cpu = Cpu::new(None, &rom, false);
+        let mut watchdog = Watchdog::new(100);
+        // an interrupt could still get out of the loop, so it takes the full threshold
+        let steps = std::iter::repeat_with(|| {
+            cpu.step_unwrap();
+            watchdog.check(&cpu)
+        })
+        .position(|hang| hang.is_some());
+        assert_eq!(steps, Some(101));
+    }
+}